@@ -0,0 +1,25 @@
+use std::process::Command;
+
+fn main() {
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    // A commit moves the branch HEAD points to, or the packed refs after a gc. Only
+    // existing files are watched, cargo reruns the script every build for a missing one.
+    let mut refs = vec![".git/packed-refs".to_string()];
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD")
+        && let Some(reference) = head.trim().strip_prefix("ref: ")
+    {
+        refs.push(format!(".git/{}", reference));
+    }
+    for path in refs.iter().filter(|path| std::path::Path::new(path).exists()) {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+}
@@ -0,0 +1,416 @@
+use bytes::BytesMut;
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{interval, sleep, Duration, Instant};
+
+use crate::agent_socket::{self, AgentTransport};
+use crate::backoff::Backoff;
+use crate::bridge::QosConfig;
+use crate::command::{self, HeldCommands};
+use crate::command_channel::CommandReceiver;
+use crate::correlation::Correlator;
+use crate::info::{self, BridgeInfo};
+use crate::mqtt_client::Message;
+use crate::mux::Mux;
+use crate::publisher::Publisher;
+use crate::queue::PublishQueue;
+use crate::routing::{self, TOPIC_COMMAND_ACK_RAW};
+use crate::state::StateCache;
+use crate::stats;
+use crate::thread::ThreadStatus;
+use crate::{availability, base64, deadletter, discovery, health, inventory, ota, pairing, topics, trace};
+
+pub const TOPIC_RAW_AGENT: &str = "aqara2mqtt/raw/agent";
+pub const DEFAULT_AGENT_SOCKET: &str = "/tmp/miio_agent.socket";
+pub const AGENT_REGISTER_KEYS: [&str; 8] = [
+    "auto.report",
+    "auto.forward",
+    "lanbox.event",
+    "auto.ifttt",
+    "auto.cross.ifttt",
+    "matter.control",
+    "matter.event",
+    "mtbr.control",
+];
+// An agent closing the socket this soon after connecting rejected the bind id
+const BIND_CHECK_WINDOW: Duration = Duration::from_secs(2);
+const BIND_FALLBACK_ATTEMPTS: u32 = 10;
+const AGENT_RECONNECT_MAX: Duration = Duration::from_secs(30);
+// Commands held while the agent socket reconnects, older ones are not replayed
+const HELD_COMMANDS_SIZE: usize = 32;
+const HELD_COMMANDS_MAX_AGE: Duration = Duration::from_secs(30);
+const RECV_BUFFER_SIZE: usize = 4096;
+// The network map changes with every heartbeat, it is published at most this often
+const NETWORK_MAP_INTERVAL: Duration = Duration::from_secs(60);
+
+// The agent connection of a bridge, the defaults match the command line
+#[derive(Clone)]
+pub struct AgentOptions {
+    // Socket path, or tcp://host:port for an agent forwarded over TCP
+    pub socket_path: String,
+    pub bind_id: u32,
+    pub mirror_raw: bool,
+    pub command_timeout: Duration,
+    pub command_retries: u32,
+    // Methods that are safe to send more than once
+    pub retry_methods: Vec<String>,
+    pub register_keys: Vec<String>,
+    // Reconnect when nothing arrives for this long
+    pub watchdog: Option<Duration>,
+    pub inventory_interval: Option<Duration>,
+    // Seqpacket socket where other local processes can share the agent connection
+    pub mux_socket: Option<String>,
+}
+
+impl Default for AgentOptions {
+    fn default() -> Self {
+        AgentOptions {
+            socket_path: DEFAULT_AGENT_SOCKET.to_string(),
+            bind_id: 0,
+            mirror_raw: false,
+            command_timeout: Duration::from_secs(5),
+            command_retries: 0,
+            retry_methods: vec!["get_properties".to_string(), "set_properties".to_string()],
+            register_keys: AGENT_REGISTER_KEYS.iter().map(|key| key.to_string()).collect(),
+            watchdog: Some(Duration::from_secs(90)),
+            inventory_interval: Some(Duration::from_secs(600)),
+            mux_socket: None,
+        }
+    }
+}
+
+pub struct AgentConfig {
+    pub options: AgentOptions,
+    pub qos: QosConfig,
+    pub info: BridgeInfo,
+    pub mux: Option<Mux>,
+    pub correlator: Correlator,
+    // What the ha_driven reader publishes, queued and retried like the agent's own messages
+    pub ha_driven_rx: Option<mpsc::Receiver<Message>>,
+}
+
+pub async fn agent_manager<T: AgentTransport>(
+    config: AgentConfig,
+    publisher: Publisher,
+    mut command_rx: CommandReceiver,
+    mut publish_queue: PublishQueue,
+    state_cache: StateCache,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let AgentConfig {
+        options:
+            AgentOptions {
+                socket_path: agent_socket_path,
+                bind_id: configured_bind_id,
+                mirror_raw,
+                command_timeout,
+                command_retries,
+                retry_methods,
+                register_keys,
+                watchdog,
+                inventory_interval,
+                mux_socket: _,
+            },
+        qos,
+        info,
+        mux,
+        correlator,
+        mut ha_driven_rx,
+    } = config;
+    let mut inventory_id = None;
+    let mut network_map_published = Instant::now();
+    let mut thread_status = ThreadStatus::default();
+    // Only the hub itself runs ha_agent, a remote agent is left alone
+    if agent_socket::is_local(&agent_socket_path) {
+        let _ = Command::new("rm").arg("-rf").arg("/tmp/miio_agent.socket").status().await;
+        sleep(Duration::from_millis(500)).await;
+        let _ = Command::new("killall").arg("-9").arg("ha_agent").status().await;
+    }
+    let mut bind_id = configured_bind_id;
+    let mut bind_attempt = 0;
+    let mut buf = BytesMut::zeroed(RECV_BUFFER_SIZE);
+    let mut flush_timer = interval(Duration::from_secs(1));
+    let mut held_commands = HeldCommands::new(HELD_COMMANDS_SIZE, HELD_COMMANDS_MAX_AGE);
+    let mut backoff = Backoff::new(Duration::from_millis(500), AGENT_RECONNECT_MAX);
+    let mut agent_error = false;
+    let mut connected_before = false;
+
+    loop {
+        info!("Connecting to the miio agent socket at '{}'...", agent_socket_path);
+        publish_queue.publish(&publisher, availability::agent_status(availability::AGENT_CONNECTING)).await;
+
+        let mut agent_socket = loop {
+            match T::connect(&agent_socket_path).await {
+                Ok(mut socket) => {
+                    info!("Successfully connected to miio agent socket with {}", bind_id);
+                    if connected_before {
+                        stats::inc(|stats| &stats.agent_reconnects);
+                    }
+                    connected_before = true;
+                    backoff.reset();
+                    health::agent_alive();
+                    publish_queue.publish(&publisher, availability::agent_status(availability::AGENT_CONNECTED)).await;
+                    // Send initialization messages
+                    let _ = socket.send(agent_socket::bind_message(bind_id).as_bytes()).await;
+                    let mux_keys = mux.as_ref().map(Mux::keys).unwrap_or_default();
+                    for key in register_keys.iter().chain(&mux_keys) {
+                        let msg = format!(r#"{{"key":"{}","method":"register"}}"#, key);
+                        let _ = socket.send(msg.as_bytes()).await;
+                    }
+                    break socket;
+                }
+                Err(e) => {
+                    // Only the first failure in a row is reported
+                    if !agent_error {
+                        error!("Error connecting to the agent socket: {:?}", e);
+                        publish_queue.publish(&publisher, availability::agent_status(availability::AGENT_ERROR)).await;
+                        agent_error = true;
+                    }
+                }
+            }
+            if let Some(rx) = &mut ha_driven_rx {
+                while let Ok(msg) = rx.try_recv() {
+                    publish_queue.publish(&publisher, msg).await;
+                }
+            }
+            // Hold commands until the socket is back, the oldest ones are given up when full
+            while let Some(payload) = command_rx.try_recv() {
+                if let Some(evicted) = held_commands.hold(payload) {
+                    warn!("Agent unavailable, dropping command '{}'", String::from_utf8_lossy(&evicted));
+                    routing::publish_agent_unavailable(&mut publish_queue, &publisher, &correlator, &evicted, qos.ack).await;
+                }
+            }
+            tokio::select! {
+                _ = sleep(backoff.next_delay()) => {}
+                _ = shutdown.recv() => {
+                    // Nothing to unregister, what was queued meanwhile is still written out
+                    publish_queue.flush(&publisher).await;
+                    return;
+                }
+            }
+        };
+        agent_error = false;
+        let connected_at = Instant::now();
+        let mut bind_rejected = false;
+
+        let (fresh, stale) = held_commands.drain();
+        for payload in stale {
+            warn!("Agent unavailable for too long, dropping command '{}'", String::from_utf8_lossy(&payload));
+            routing::publish_agent_unavailable(&mut publish_queue, &publisher, &correlator, &payload, qos.ack).await;
+        }
+        for payload in fresh {
+            debug!("Replaying held command '{}'", String::from_utf8_lossy(&payload));
+            let id = command::command_id(&payload).and_then(|id| id.as_u64());
+            if agent_socket.send(&payload).await.is_err() {
+                routing::publish_agent_unavailable(&mut publish_queue, &publisher, &correlator, &payload, qos.ack).await;
+            } else if let Some(id) = id {
+                correlator.restart(id).await;
+                trace::sent(id);
+            }
+        }
+
+        // The agent is pinged with a bind every third of the watchdog interval
+        let mut last_received = Instant::now();
+        let mut ping_timer = interval(watchdog.map_or(Duration::from_secs(3600), |watchdog| watchdog / 3));
+        ping_timer.tick().await;
+        // The first tick is immediate, so the inventory is queried right after connecting
+        let mut inventory_timer = interval(inventory_interval.unwrap_or(Duration::from_secs(3600)));
+
+        loop {
+            tokio::select! {
+                // Receive commands from MQTT task
+                cmd = command_rx.recv() => {
+                    match cmd {
+                        Some(payload) => {
+                            if let Err(e) = agent_socket.send(&payload).await {
+                                error!("Error sending to agent socket: {:?}. Reconnecting...", e);
+                                if let Some(evicted) = held_commands.hold(payload) {
+                                    warn!("Agent unavailable, dropping command '{}'", String::from_utf8_lossy(&evicted));
+                                    routing::publish_agent_unavailable(&mut publish_queue, &publisher, &correlator, &evicted, qos.ack).await;
+                                }
+                                break;
+                            }
+                            if let Some(id) = command::command_id(&payload).and_then(|id| id.as_u64()) {
+                                trace::sent(id);
+                            }
+                        },
+                        None => return, // Channel closed, exit application
+                    }
+                }
+                msg = async { ha_driven_rx.as_mut()?.recv().await }, if ha_driven_rx.is_some() => {
+                    match msg {
+                        Some(msg) => publish_queue.publish(&publisher, msg).await,
+                        None => ha_driven_rx = None,
+                    }
+                }
+                _ = shutdown.recv() => {
+                    // Leave no stale routing entries behind for the next start with this bind id
+                    let mux_keys = mux.as_ref().map(Mux::keys).unwrap_or_default();
+                    for key in register_keys.iter().chain(&mux_keys) {
+                        let _ = agent_socket.send(format!(r#"{{"key":"{}","method":"unregister"}}"#, key).as_bytes()).await;
+                    }
+                    let _ = agent_socket.send(agent_socket::unbind_message(bind_id).as_bytes()).await;
+                    info!("Unregistered from the miio agent");
+                    publish_queue.flush(&publisher).await;
+                    return;
+                }
+                // Replay reports queued while the broker was down, time out unanswered commands
+                _ = flush_timer.tick() => {
+                    health::agent_alive();
+                    publish_queue.flush(&publisher).await;
+                    for msg in publisher.take_coalesced() {
+                        publish_queue.publish(&publisher, msg).await;
+                    }
+                    for msg in state_cache.expire_devices() {
+                        publish_queue.publish(&publisher, msg).await;
+                    }
+                    if network_map_published.elapsed() >= NETWORK_MAP_INTERVAL
+                        && let Some(msg) = state_cache.take_network_map()
+                    {
+                        publish_queue.publish(&publisher, msg).await;
+                        network_map_published = Instant::now();
+                    }
+                    if let Some(mux) = &mux {
+                        mux.expire(command_timeout);
+                    }
+                    let expired = correlator.take_expired(command_timeout).await;
+                    for command in expired {
+                        // Only methods known to be idempotent are sent again
+                        if command.attempts <= command_retries && retry_methods.contains(&command.method) {
+                            info!("Retrying command {} (attempt {})", command.id, command.attempts + 1);
+                            if agent_socket.send(&command.payload).await.is_ok() {
+                                correlator.retry(command).await;
+                                continue;
+                            }
+                        }
+                        warn!("No response to command {} within {:?}", command.id, command_timeout);
+                        let topic = routing::ack_topic(command.reply_topic);
+                        let msg = routing::command_error(topic, Some(Value::from(command.id)), command::ERROR_TIMEOUT, "command timed out", qos.ack);
+                        publish_queue.publish(&publisher, msg).await;
+                    }
+                }
+                _ = ping_timer.tick(), if watchdog.is_some() => {
+                    if watchdog.is_some_and(|watchdog| last_received.elapsed() > watchdog) {
+                        warn!("Nothing received from the agent for {:?}. Reconnecting...", last_received.elapsed());
+                        break;
+                    }
+                    let _ = agent_socket.send(agent_socket::bind_message(bind_id).as_bytes()).await;
+                }
+                _ = inventory_timer.tick(), if inventory_interval.is_some() => {
+                    let id = command::next_id();
+                    inventory_id = Some(id);
+                    let _ = agent_socket.send(&inventory::query(id)).await;
+                }
+                // Receive data from Agent Socket
+                res = agent_socket.recv(&mut buf) => {
+                    last_received = Instant::now();
+                    health::message_received();
+                    match res {
+                        Ok((n, len)) if len > n => {
+                            warn!("Agent frame of {} bytes truncated to {}", len, n);
+                            let reason = format!("frame of {} bytes truncated to {}", len, n);
+                            publish_queue.publish(&publisher, deadletter::message("agent", &buf[..n], &reason)).await;
+                        }
+                        Ok((n, _)) if n > 0 => {
+                            // The frames are published as slices of the received data. Growing the
+                            // buffer again reuses its memory once the last of them was published.
+                            let data = buf.split_to(n).freeze();
+                            buf.resize(RECV_BUFFER_SIZE, 0);
+                            if mirror_raw {
+                                let raw = Message::new(topics::prefixed(TOPIC_RAW_AGENT), data.clone(), 0);
+                                let _ = publisher.publish_raw(raw).await;
+                            }
+                            let (documents, rest) = agent_socket::split_documents(&data);
+                            let documents_empty = documents.is_empty();
+                            for (frame, report) in documents {
+                                debug!("reading length: '{}' msg: '{:?}'", frame.len(), report);
+                                if inventory_id.is_some() && report.get("id").and_then(|v| v.as_u64()) == inventory_id {
+                                    inventory_id = None;
+                                    match inventory::parse(&report) {
+                                        Some(devices) => {
+                                            info!("Device inventory: {} devices", devices.len());
+                                            state_cache.set_models(&devices);
+                                            publish_queue.publish(&publisher, inventory::message(&devices)).await;
+                                            // Discovery configs are plain HA payloads, so they skip the gateway tag
+                                            for msg in discovery::messages(&devices, state_cache.names()) {
+                                                if let Err(e) = publisher.publish_raw(msg).await {
+                                                    error!("Error publishing discovery config: {:?}", e);
+                                                }
+                                            }
+                                        }
+                                        None => warn!("Unexpected device list response: {}", report),
+                                    }
+                                    continue;
+                                }
+                                if let Some(device) = pairing::joined_device(&report) {
+                                    info!("Device joined: {}", device);
+                                    publish_queue.publish(&publisher, pairing::event("device_joined", device)).await;
+                                    // Refresh the inventory so the new device gets its discovery config
+                                    inventory_timer.reset_immediately();
+                                }
+                                if let Some(progress) = ota::progress(&report) {
+                                    publish_queue.publish(&publisher, progress).await;
+                                }
+                                let (thread_changed, thread_event) = thread_status.update(&report);
+                                if let Some(event) = thread_event {
+                                    publish_queue.publish(&publisher, event).await;
+                                }
+                                if thread_changed {
+                                    publish_queue.publish(&publisher, thread_status.message()).await;
+                                }
+                                let addressed = command::addressed_to(&report, bind_id);
+                                if mux.as_ref().is_some_and(|mux| mux.deliver(frame, &report, addressed)) {
+                                    continue;
+                                }
+                                if agent_socket::is_bind_rejection(&report) {
+                                    warn!("Agent rejected bind id {}: {}", bind_id, report);
+                                    bind_rejected = true;
+                                    continue;
+                                }
+                                let reply = correlator.reply(&report, addressed).await;
+                                routing::handle_agent_document(data.slice_ref(frame), report, reply, &publisher, &mut publish_queue, &state_cache, qos).await;
+                            }
+                            if documents_empty && let Some((rest, _)) = &rest {
+                                // Not JSON at all, likely the answer to a raw command
+                                debug!("Non-JSON agent frame of {} bytes", rest.len());
+                                let msg = Message::new(topics::prefixed(TOPIC_COMMAND_ACK_RAW), base64::encode(rest), qos.ack);
+                                publish_queue.publish(&publisher, msg).await;
+                            } else if let Some((rest, e)) = rest {
+                                error!("Failed to parse JSON from agent: {:?}", e);
+                                let msg = deadletter::message("agent", rest, &e.to_string());
+                                publish_queue.publish(&publisher, msg).await;
+                            }
+                            if bind_rejected {
+                                break;
+                            }
+                        }
+                        Ok(_) => {
+                            warn!("Agent socket closed (EOF). Reconnecting...");
+                            // Closing right after the bind is how a taken bind id shows
+                            bind_rejected |= connected_at.elapsed() < BIND_CHECK_WINDOW;
+                            break;
+                        }
+                        Err(e) => {
+                            error!("Error reading from agent socket: {:?}. Reconnecting...", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        health::agent_disconnected();
+        if bind_rejected {
+            // Try the next ids after the configured one, then start over
+            bind_attempt = (bind_attempt + 1) % (BIND_FALLBACK_ATTEMPTS + 1);
+            bind_id = configured_bind_id.wrapping_add(bind_attempt);
+            warn!("Falling back to bind id {}", bind_id);
+            info.set_bind_id(bind_id);
+            if let Some(msg) = info::message(&info) {
+                let _ = publisher.publish(msg).await;
+            }
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+}
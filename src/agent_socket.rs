@@ -0,0 +1,200 @@
+use std::future::Future;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use bytes::BytesMut;
+use serde_json::Value;
+use tokio_seqpacket::UnixSeqpacket;
+
+#[cfg(any(test, feature = "testing"))]
+mod memory;
+mod tcp;
+
+#[cfg(any(test, feature = "testing"))]
+pub use memory::{MemoryAgent, MemoryAgentListener};
+pub use tcp::TcpAgent;
+
+// Agent addresses with this scheme go over TCP, anything else is a seqpacket socket path
+pub const TCP_SCHEME: &str = "tcp://";
+// An agent in the same process, see MemoryAgentListener. Only built with the testing feature.
+pub const MEMORY_SCHEME: &str = "memory://";
+
+// Frames above this are cut off instead of growing the buffer further
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+// How frames get to and from miio_agent, one send or recv is one whole frame
+pub trait AgentTransport: Send + Sized {
+    // `address` as given with --agent-socket-path, the transport's scheme included
+    fn connect(address: &str) -> impl Future<Output = io::Result<Self>> + Send;
+    fn send(&mut self, frame: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+    // Reads the next frame into `buf`, growing it to fit. Returns the bytes read
+    // and the full frame size, which is larger only when the frame exceeded
+    // MAX_FRAME_SIZE and was truncated. (0, 0) means the peer closed.
+    fn recv(&mut self, buf: &mut BytesMut) -> impl Future<Output = io::Result<(usize, usize)>> + Send;
+}
+
+// The transport picked by the scheme of the address
+pub enum AgentSocket {
+    Unix(UnixSeqpacket),
+    Tcp(TcpAgent),
+    #[cfg(any(test, feature = "testing"))]
+    Memory(MemoryAgent),
+}
+
+// Whether the address is the hub's own agent socket
+pub fn is_local(address: &str) -> bool {
+    !address.starts_with(TCP_SCHEME) && !address.starts_with(MEMORY_SCHEME)
+}
+
+impl AgentTransport for AgentSocket {
+    async fn connect(address: &str) -> io::Result<Self> {
+        if address.starts_with(TCP_SCHEME) {
+            return Ok(AgentSocket::Tcp(TcpAgent::connect(address).await?));
+        }
+        if address.starts_with(MEMORY_SCHEME) {
+            #[cfg(any(test, feature = "testing"))]
+            return Ok(AgentSocket::Memory(MemoryAgent::connect(address).await?));
+            #[cfg(not(any(test, feature = "testing")))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} agents need a build with the testing feature", MEMORY_SCHEME),
+            ));
+        }
+        Ok(AgentSocket::Unix(UnixSeqpacket::connect(address).await?))
+    }
+
+    async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self {
+            AgentSocket::Unix(socket) => socket.send(frame).await,
+            AgentSocket::Tcp(socket) => socket.send(frame).await,
+            #[cfg(any(test, feature = "testing"))]
+            AgentSocket::Memory(socket) => socket.send(frame).await,
+        }
+    }
+
+    async fn recv(&mut self, buf: &mut BytesMut) -> io::Result<(usize, usize)> {
+        match self {
+            AgentSocket::Unix(socket) => socket.recv(buf).await,
+            AgentSocket::Tcp(socket) => socket.recv(buf).await,
+            #[cfg(any(test, feature = "testing"))]
+            AgentSocket::Memory(socket) => socket.recv(buf).await,
+        }
+    }
+}
+
+// Size of the next datagram without consuming it, MSG_TRUNC makes Linux
+// report the real length even though nothing is copied
+fn peek_len(fd: RawFd) -> io::Result<usize> {
+    let len = unsafe { libc::recv(fd, std::ptr::null_mut(), 0, libc::MSG_PEEK | libc::MSG_TRUNC | libc::MSG_DONTWAIT) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+impl AgentTransport for UnixSeqpacket {
+    async fn connect(address: &str) -> io::Result<Self> {
+        UnixSeqpacket::connect(address).await
+    }
+
+    async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        UnixSeqpacket::send(self, frame).await.map(|_| ())
+    }
+
+    // A seqpacket socket silently drops whatever doesn't fit the buffer, so the
+    // size of the datagram is peeked first
+    async fn recv(&mut self, buf: &mut BytesMut) -> io::Result<(usize, usize)> {
+        let len = loop {
+            let mut guard = self.as_async_fd().readable().await?;
+            match guard.try_io(|_| peek_len(self.as_raw_fd())) {
+                Ok(result) => break result?,
+                Err(_would_block) => continue,
+            }
+        };
+        if len > buf.len() {
+            buf.resize(len.min(MAX_FRAME_SIZE), 0);
+        }
+        let n = UnixSeqpacket::recv(self, &mut buf[..]).await?;
+        Ok((n, len))
+    }
+}
+
+pub fn bind_message(bind_id: u32) -> String {
+    format!(r#"{{"address":{},"method":"bind"}}"#, bind_id)
+}
+
+pub fn unbind_message(bind_id: u32) -> String {
+    format!(r#"{{"address":{},"method":"unbind"}}"#, bind_id)
+}
+
+// The agent answers a bind for an address that is already taken with an error
+pub fn is_bind_rejection(document: &Value) -> bool {
+    document.get("method").and_then(Value::as_str) == Some("bind")
+        && (document.get("error").is_some()
+            || document.get("result").and_then(Value::as_str).is_some_and(|result| result != "ok"))
+}
+
+// A document's own bytes and its parsed value
+pub type Document<'a> = (&'a [u8], Value);
+
+// Some firmware packs several JSON documents into one datagram. Returns each
+// document, plus the unparseable rest and its error if any.
+pub fn split_documents(data: &[u8]) -> (Vec<Document<'_>>, Option<(&[u8], serde_json::Error)>) {
+    let mut documents = Vec::new();
+    let mut stream = serde_json::Deserializer::from_slice(data).into_iter::<Value>();
+    let mut start = 0;
+    loop {
+        match stream.next() {
+            Some(Ok(value)) => {
+                let end = stream.byte_offset();
+                documents.push((data[start..end].trim_ascii(), value));
+                start = end;
+            }
+            Some(Err(e)) => return (documents, Some((&data[start..], e))),
+            None => return (documents, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn one_document() {
+        let (documents, rest) = split_documents(br#"{"id":1}"#);
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0].0, br#"{"id":1}"#);
+        assert_eq!(documents[0].1, json!({"id": 1}));
+        assert!(rest.is_none());
+    }
+
+    #[test]
+    fn packed_documents_keep_their_own_bytes() {
+        let (documents, rest) = split_documents(b"{\"id\":1} {\"id\":2}\n[3]");
+        let bytes: Vec<&[u8]> = documents.iter().map(|(bytes, _)| *bytes).collect();
+        assert_eq!(bytes, [&b"{\"id\":1}"[..], b"{\"id\":2}", b"[3]"]);
+        assert_eq!(documents[2].1, json!([3]));
+        assert!(rest.is_none());
+    }
+
+    #[test]
+    fn garbage_after_documents_is_the_rest() {
+        let (documents, rest) = split_documents(b"{\"id\":1}{\"id\":");
+        assert_eq!(documents.len(), 1);
+        let (rest, _) = rest.unwrap();
+        assert_eq!(rest, b"{\"id\":");
+
+        let (documents, rest) = split_documents(b"\x01\x02");
+        assert!(documents.is_empty());
+        assert_eq!(rest.unwrap().0, b"\x01\x02");
+    }
+
+    #[test]
+    fn empty_datagram() {
+        let (documents, rest) = split_documents(b"");
+        assert!(documents.is_empty() && rest.is_none());
+    }
+}
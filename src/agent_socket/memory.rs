@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+use tokio::sync::mpsc;
+
+use super::{AgentTransport, MAX_FRAME_SIZE, MEMORY_SCHEME};
+
+// An agent inside the process, for tests and tools embedding the bridge. The bridge
+// connects to memory://<name>, the other end comes from the listener bound to the name.
+pub struct MemoryAgent {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+static LISTENERS: Mutex<Option<HashMap<String, mpsc::UnboundedSender<MemoryAgent>>>> = Mutex::new(None);
+
+impl MemoryAgent {
+    // Two connected ends, what one sends the other receives
+    pub fn pair() -> (MemoryAgent, MemoryAgent) {
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+        (MemoryAgent { tx: a_tx, rx: b_rx }, MemoryAgent { tx: b_tx, rx: a_rx })
+    }
+}
+
+impl AgentTransport for MemoryAgent {
+    // memory://<name>, or just the name
+    async fn connect(address: &str) -> io::Result<Self> {
+        let name = address.strip_prefix(MEMORY_SCHEME).unwrap_or(address);
+        let mut listeners = LISTENERS.lock().unwrap();
+        let listener = listeners.as_mut().and_then(|listeners| listeners.get(name));
+        let Some(listener) = listener else {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("no memory agent '{}'", name)));
+        };
+        let (local, remote) = MemoryAgent::pair();
+        listener
+            .send(remote)
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, format!("memory agent '{}' is gone", name)))?;
+        Ok(local)
+    }
+
+    async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.tx.send(frame.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    // Same as a seqpacket socket: one frame per call, cut off at MAX_FRAME_SIZE
+    async fn recv(&mut self, buf: &mut BytesMut) -> io::Result<(usize, usize)> {
+        let Some(frame) = self.rx.recv().await else {
+            return Ok((0, 0));
+        };
+        if frame.len() > buf.len() {
+            buf.resize(frame.len().min(MAX_FRAME_SIZE), 0);
+        }
+        let n = frame.len().min(buf.len());
+        buf[..n].copy_from_slice(&frame[..n]);
+        Ok((n, frame.len()))
+    }
+}
+
+// Accepts the connections to memory://<name> until dropped
+pub struct MemoryAgentListener {
+    name: String,
+    rx: mpsc::UnboundedReceiver<MemoryAgent>,
+}
+
+impl MemoryAgentListener {
+    pub fn bind(name: &str) -> io::Result<Self> {
+        let mut listeners = LISTENERS.lock().unwrap();
+        let listeners = listeners.get_or_insert_with(HashMap::new);
+        if listeners.contains_key(name) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("memory agent '{}' already exists", name)));
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        listeners.insert(name.to_string(), tx);
+        Ok(MemoryAgentListener { name: name.to_string(), rx })
+    }
+
+    pub async fn accept(&mut self) -> io::Result<MemoryAgent> {
+        self.rx.recv().await.ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionAborted))
+    }
+}
+
+impl Drop for MemoryAgentListener {
+    fn drop(&mut self) {
+        if let Some(listeners) = LISTENERS.lock().unwrap().as_mut() {
+            listeners.remove(&self.name);
+        }
+    }
+}
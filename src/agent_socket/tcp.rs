@@ -0,0 +1,71 @@
+use std::io;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{AgentTransport, MAX_FRAME_SIZE, TCP_SCHEME};
+
+// The agent socket forwarded over TCP for development, e.g. by socat on the
+// hub. TCP has no message boundaries, so each frame is prefixed with its
+// length as a 4 byte big endian integer.
+pub struct TcpAgent {
+    stream: TcpStream,
+    // Bytes read but not yet returned as a frame, kept here so that a
+    // cancelled recv doesn't lose part of a frame
+    pending: Vec<u8>,
+}
+
+impl TcpAgent {
+    fn take_frame(&mut self, buf: &mut BytesMut) -> io::Result<Option<(usize, usize)>> {
+        let Some(header) = self.pending.first_chunk::<4>() else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(*header) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too large", len)));
+        }
+        if self.pending.len() < 4 + len {
+            return Ok(None);
+        }
+        if len > buf.len() {
+            buf.resize(len, 0);
+        }
+        buf[..len].copy_from_slice(&self.pending[4..4 + len]);
+        self.pending.drain(..4 + len);
+        Ok(Some((len, len)))
+    }
+}
+
+impl AgentTransport for TcpAgent {
+    // tcp://host:port, or just host:port
+    async fn connect(address: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(address.strip_prefix(TCP_SCHEME).unwrap_or(address)).await?;
+        stream.set_nodelay(true)?;
+        Ok(TcpAgent { stream, pending: Vec::new() })
+    }
+
+    async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let mut data = Vec::with_capacity(4 + frame.len());
+        data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        data.extend_from_slice(frame);
+        self.stream.write_all(&data).await
+    }
+
+    async fn recv(&mut self, buf: &mut BytesMut) -> io::Result<(usize, usize)> {
+        let mut chunk = [0; 4096];
+        loop {
+            match self.take_frame(buf)? {
+                // Empty frames carry nothing and would look like a closed peer
+                Some((0, _)) => continue,
+                Some(frame) => return Ok(frame),
+                None => {}
+            }
+            let n = self.stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok((0, 0));
+            }
+            self.pending.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
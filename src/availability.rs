@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use log::error;
+use serde_json::Value;
+
+use crate::context;
+use crate::mqtt_client::{Client, Message, MqttClient};
+use crate::state;
+use crate::topics;
+
+pub const TOPIC_BRIDGE_STATE: &str = "aqara2mqtt/bridge/state";
+pub const STATE_ONLINE: &str = "online";
+pub const STATE_OFFLINE: &str = "offline";
+
+pub const TOPIC_AGENT_STATUS: &str = "aqara2mqtt/bridge/agent_status";
+pub const AGENT_CONNECTING: &str = "connecting";
+pub const AGENT_CONNECTED: &str = "connected";
+pub const AGENT_ERROR: &str = "error";
+
+pub const TOPIC_HA_DRIVEN_STATUS: &str = "aqara2mqtt/bridge/ha_driven_status";
+pub const HA_DRIVEN_RUNNING: &str = "running";
+pub const HA_DRIVEN_RESTARTING: &str = "restarting";
+
+// Registered with the broker as LWT, so the state flips to offline when we drop
+pub fn last_will() -> Message {
+    Message::new_retained(topics::prefixed(TOPIC_BRIDGE_STATE), STATE_OFFLINE, 1)
+}
+
+pub async fn publish_online(client: &Client) {
+    publish_state(client, STATE_ONLINE).await;
+}
+
+// A clean disconnect doesn't trigger the LWT, so offline is published explicitly
+pub async fn publish_offline(client: &Client) {
+    publish_state(client, STATE_OFFLINE).await;
+}
+
+// State of the miio agent socket, retained so outages can be alerted on
+pub fn agent_status(status: &str) -> Message {
+    Message::new_retained(topics::prefixed(TOPIC_AGENT_STATUS), status.to_string(), 1)
+}
+
+// State of the ha_driven the bridge started, retained next to the agent status.
+// `details` is an object with the restart count and why it stopped.
+pub fn ha_driven_status(status: &str, mut details: Value) -> Message {
+    details["status"] = Value::from(status);
+    Message::new_retained(topics::prefixed(TOPIC_HA_DRIVEN_STATUS), details.to_string(), 1)
+}
+
+async fn publish_state(client: &Client, state: &str) {
+    let msg = Message::new_retained(topics::prefixed(TOPIC_BRIDGE_STATE), state.to_string(), 1);
+    if let Err(e) = client.publish(msg).await {
+        error!("Error publishing bridge availability: {:?}", e);
+    }
+}
+
+// aqara2mqtt/<did>/availability, only published with --device-availability
+pub const TOPIC_DEVICE_AVAILABILITY: &str = "availability";
+
+// Battery powered end devices only report every hour or so, the prefixes of their models
+const BATTERY_MODELS: [&str; 8] = [
+    "lumi.sensor_",
+    "lumi.weather",
+    "lumi.magnet",
+    "lumi.motion",
+    "lumi.vibration",
+    "lumi.remote",
+    "lumi.flood",
+    "lumi.airmonitor",
+];
+
+#[derive(Clone, Copy)]
+pub struct DeviceTimeouts {
+    pub battery: Duration,
+    pub mains: Duration,
+}
+
+// Device availability is off without them
+pub fn device_timeouts() -> Option<DeviceTimeouts> {
+    context::current().config.device_timeouts
+}
+
+impl DeviceTimeouts {
+    // Devices without a known model get the longer battery timeout, so they aren't flagged early
+    pub fn for_model(&self, model: Option<&str>) -> Duration {
+        match model {
+            Some(model) if !BATTERY_MODELS.iter().any(|prefix| model.starts_with(prefix)) => self.mains,
+            _ => self.battery,
+        }
+    }
+}
+
+pub fn device_topic(did: &str) -> String {
+    topics::prefixed(&format!("{}/{}/{}", state::TOPIC_DEVICE_PREFIX, did, TOPIC_DEVICE_AVAILABILITY))
+}
+
+pub fn device_status(did: &str, online: bool) -> Message {
+    let status = if online { STATE_ONLINE } else { STATE_OFFLINE };
+    Message::new_retained(device_topic(did), status, 1)
+}
@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+use crate::context;
+use crate::mqtt_client::Message;
+use crate::state::TOPIC_DEVICE_PREFIX;
+use crate::topics;
+
+pub const TOPIC_LOW_BATTERY: &str = "aqara2mqtt/bridge/low_battery";
+
+// Zigbee heartbeat resources of lumi devices
+const RES_BATTERY_PERCENT: &str = "8.0.2001";
+const RES_BATTERY_VOLTAGE: &str = "8.0.2008";
+// MIoT battery property name in the spec database
+pub const MIOT_BATTERY_LEVEL: &str = "battery_level";
+
+// CR2032/CR2450 cells are flat above 3.2 V and dead below 2.85 V
+const VOLTAGE_EMPTY: f64 = 2850.0;
+const VOLTAGE_FULL: f64 = 3200.0;
+
+// Low battery threshold in percent, None when the battery topics are off
+pub fn threshold() -> Option<u8> {
+    context::current().config.battery_threshold
+}
+
+fn from_voltage(millivolts: f64) -> u8 {
+    let level = (millivolts - VOLTAGE_EMPTY) / (VOLTAGE_FULL - VOLTAGE_EMPTY) * 100.0;
+    level.clamp(0.0, 100.0).round() as u8
+}
+
+// Battery percentage from a device state, the reported percentage wins over the voltage
+pub fn level(state: &Value, miot_key: Option<&str>) -> Option<u8> {
+    let percent = [Some(RES_BATTERY_PERCENT), miot_key]
+        .into_iter()
+        .flatten()
+        .find_map(|key| state.get(key).and_then(Value::as_f64));
+    if let Some(percent) = percent {
+        return Some(percent.clamp(0.0, 100.0).round() as u8);
+    }
+    state.get(RES_BATTERY_VOLTAGE).and_then(Value::as_f64).map(from_voltage)
+}
+
+pub fn device_topic(did: &str) -> String {
+    topics::prefixed(&format!("{}/{}/battery", TOPIC_DEVICE_PREFIX, did))
+}
+
+pub fn device_message(did: &str, level: u8) -> Message {
+    Message::new_retained(device_topic(did), level.to_string(), 1)
+}
+
+// All devices under the threshold, e.g. [{"did":"lumi.158d0001","battery":12}]
+pub fn low_battery_message(levels: &BTreeMap<String, u8>, threshold: u8) -> Message {
+    let low: Vec<Value> = levels
+        .iter()
+        .filter(|(_, level)| **level < threshold)
+        .map(|(did, level)| json!({ "did": did, "battery": level }))
+        .collect();
+    Message::new_retained(topics::prefixed(TOPIC_LOW_BATTERY), Value::Array(low).to_string(), 1)
+}
@@ -0,0 +1,530 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+use crate::agent::{self, AgentConfig, AgentOptions};
+use crate::agent_socket::AgentSocket;
+use crate::command_channel::{self, CommandOverflow};
+use crate::context::{self, BridgeConfig, BridgeContext};
+use crate::correlation::Correlator;
+use crate::filter::{CommandFilter, FilterRule};
+use crate::hadriven::HaDrivenOptions;
+use crate::info::BridgeInfo;
+use crate::logger::{self, Levels};
+use crate::mqtt::{self, CommandInput};
+use crate::mqtt_client::{self, Message, MqttConfig};
+use crate::mux::{self, Mux};
+use crate::publisher::Publisher;
+use crate::queue::{OverflowPolicy, PublishQueue};
+use crate::rate_limit::{RateLimitPolicy, RateLimiter};
+use crate::state::StateCache;
+use crate::zigbee2mqtt::FriendlyNames;
+use crate::{dedup, discovery, errors, hadriven, metrics, stats, supervisor, systemd, telemetry, trace};
+
+// Caps of the low memory profile
+const LOW_MEMORY_QUEUE_SIZE: usize = 100;
+const LOW_MEMORY_COMMAND_QUEUE_SIZE: usize = 8;
+const LOW_MEMORY_ERROR_HISTORY: usize = 10;
+
+#[derive(Clone, Copy, Default)]
+pub struct QosConfig {
+    pub report: i32,
+    pub ack: i32,
+    pub command_sub: i32,
+}
+
+// The options that can change without a restart
+pub struct ReloadOptions {
+    pub levels: Levels,
+    pub command_allow: Vec<FilterRule>,
+    pub command_deny: Vec<FilterRule>,
+    pub friendly_names: Option<String>,
+    pub ha_discovery: Option<String>,
+}
+
+// Reads the options again, the binary re-parses its command line and config file
+pub type Reloader = Arc<dyn Fn() -> Result<ReloadOptions, String> + Send + Sync>;
+
+// Applies the options that need no restart: log level, command filters, friendly names
+// and discovery. Returns the discovery configs to publish.
+pub fn reload(reloader: Option<&Reloader>, filter: &Mutex<CommandFilter>, state_cache: &StateCache) -> Result<Vec<Message>, String> {
+    let reloader = reloader.ok_or("this bridge has no configuration to reload")?;
+    let options = reloader()?;
+    let names = match &options.friendly_names {
+        None => FriendlyNames::default(),
+        Some(path) => FriendlyNames::load(&PathBuf::from(path))?,
+    };
+    logger::set_levels(options.levels);
+    *filter.lock().unwrap() = CommandFilter::new(options.command_allow, options.command_deny);
+    state_cache.names().replace(names);
+
+    let inventory = state_cache.inventory();
+    let mut messages = Vec::new();
+    let prefix = options.ha_discovery.as_deref().map(|prefix| prefix.trim_end_matches('/').to_string());
+    if prefix != discovery::prefix() {
+        // Configs under the old prefix would stay behind in Home Assistant
+        messages.extend(inventory.iter().flat_map(|device| discovery::clear_messages(&device.did, &device.model)));
+        match prefix {
+            Some(prefix) => discovery::enable(&prefix),
+            None => discovery::disable(),
+        }
+    }
+    messages.extend(discovery::messages(&inventory, state_cache.names()));
+    info!("Reloaded configuration");
+    Ok(messages)
+}
+
+// Collects the options of a bridge, see Bridge::builder
+pub struct BridgeBuilder {
+    config: BridgeConfig,
+    mqtt: Option<(String, MqttConfig)>,
+    secondary: Option<(String, MqttConfig)>,
+    client_id: Option<String>,
+    agent: AgentOptions,
+    qos: QosConfig,
+    queue_size: usize,
+    queue_overflow: OverflowPolicy,
+    queue_file: Option<PathBuf>,
+    command_queue_size: usize,
+    command_overflow: CommandOverflow,
+    rate_limiter: Option<RateLimiter>,
+    state_cache: StateCache,
+    command_filter: CommandFilter,
+    reloader: Option<Reloader>,
+    telemetry_interval: Option<Duration>,
+    stats_interval: Option<Duration>,
+    metrics: Option<(SocketAddr, Option<Duration>)>,
+    otlp_endpoint: Option<trace::Endpoint>,
+    error_history: usize,
+    low_memory: bool,
+    ha_driven: bool,
+    ha_driven_options: HaDrivenOptions,
+    dedup_window: Duration,
+}
+
+impl Default for BridgeBuilder {
+    fn default() -> Self {
+        BridgeBuilder {
+            config: BridgeConfig::default(),
+            mqtt: None,
+            secondary: None,
+            client_id: None,
+            agent: AgentOptions::default(),
+            qos: QosConfig::default(),
+            queue_size: 1000,
+            queue_overflow: OverflowPolicy::DropOldest,
+            queue_file: None,
+            command_queue_size: command_channel::DEFAULT_CAPACITY,
+            command_overflow: CommandOverflow::Block,
+            rate_limiter: None,
+            state_cache: StateCache::default(),
+            command_filter: CommandFilter::default(),
+            reloader: None,
+            telemetry_interval: Some(Duration::from_secs(60)),
+            stats_interval: Some(Duration::from_secs(60)),
+            metrics: None,
+            otlp_endpoint: None,
+            error_history: 50,
+            low_memory: false,
+            ha_driven: true,
+            ha_driven_options: HaDrivenOptions::default(),
+            dedup_window: dedup::DEFAULT_WINDOW,
+        }
+    }
+}
+
+impl BridgeBuilder {
+    // Topic prefix, gateway id, compat mode and the optional report contents, the modules
+    // look them up on the bridge whose messages they handle
+    pub fn config(mut self, config: BridgeConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    // The primary broker, which gets the commands
+    pub fn mqtt(mut self, uri: impl Into<String>, config: MqttConfig) -> Self {
+        self.mqtt = Some((uri.into(), config));
+        self
+    }
+
+    // An additional broker that receives a copy of every publish
+    pub fn secondary_mqtt(mut self, uri: impl Into<String>, config: MqttConfig) -> Self {
+        self.secondary = Some((uri.into(), config));
+        self
+    }
+
+    // Defaults to agent2mqtt-<bind_id>
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    pub fn agent(mut self, options: AgentOptions) -> Self {
+        self.agent = options;
+        self
+    }
+
+    pub fn qos(mut self, qos: QosConfig) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    // Reports kept while the broker is unreachable, persisted to `file` if given
+    pub fn queue(mut self, size: usize, overflow: OverflowPolicy, file: Option<PathBuf>) -> Self {
+        self.queue_size = size;
+        self.queue_overflow = overflow;
+        self.queue_file = file;
+        self
+    }
+
+    // Commands waiting for the agent task, and what happens to a command beyond them
+    pub fn command_queue(mut self, size: usize, overflow: CommandOverflow) -> Self {
+        self.command_queue_size = size;
+        self.command_overflow = overflow;
+        self
+    }
+
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    // Friendly names, zigbee2mqtt topics and the MIoT spec live in the state cache
+    pub fn state_cache(mut self, state_cache: StateCache) -> Self {
+        self.state_cache = state_cache;
+        self
+    }
+
+    pub fn command_filter(mut self, command_filter: CommandFilter) -> Self {
+        self.command_filter = command_filter;
+        self
+    }
+
+    // Called on SIGHUP and the reload request, without one reloading fails
+    pub fn reloader(mut self, reloader: impl Fn() -> Result<ReloadOptions, String> + Send + Sync + 'static) -> Self {
+        self.reloader = Some(Arc::new(reloader));
+        self
+    }
+
+    pub fn telemetry_interval(mut self, period: Option<Duration>) -> Self {
+        self.telemetry_interval = period;
+        self
+    }
+
+    pub fn stats_interval(mut self, period: Option<Duration>) -> Self {
+        self.stats_interval = period;
+        self
+    }
+
+    // Serves /metrics and /healthz, which fails after `health_max_idle` without a frame from the agent
+    pub fn metrics(mut self, addr: SocketAddr, health_max_idle: Option<Duration>) -> Self {
+        self.metrics = Some((addr, health_max_idle));
+        self
+    }
+
+    pub fn otlp_endpoint(mut self, endpoint: trace::Endpoint) -> Self {
+        self.otlp_endpoint = Some(endpoint);
+        self
+    }
+
+    // Warnings and errors kept on aqara2mqtt/bridge/errors, 0 disables
+    pub fn error_history(mut self, size: usize) -> Self {
+        self.error_history = size;
+        self
+    }
+
+    // For hubs with 128 MB: caps the queues and the error history, and the state cache
+    // keeps neither device states nor the inventory
+    pub fn low_memory(mut self, low_memory: bool) -> Self {
+        self.low_memory = low_memory;
+        self
+    }
+
+    // Restart ha_driven and forward the reports it logs. Off where another tool manages ha_driven,
+    // the bridge then only talks to the agent socket.
+    pub fn ha_driven(mut self, enabled: bool) -> Self {
+        self.ha_driven = enabled;
+        self
+    }
+
+    // Where the ha_driven lines come from and which of them are published besides res/report
+    pub fn ha_driven_options(mut self, options: HaDrivenOptions) -> Self {
+        self.ha_driven_options = options;
+        self
+    }
+
+    // Reports coming in on both the agent socket and the ha_driven log within this
+    // window are published once, zero publishes both
+    pub fn dedup_window(mut self, window: Duration) -> Self {
+        self.dedup_window = window;
+        self
+    }
+
+    // Starts the tasks of the bridge, fails when a broker client or the metrics listener can't be set up
+    pub async fn spawn(mut self) -> Result<Bridge, String> {
+        if self.low_memory {
+            self.queue_size = self.queue_size.min(LOW_MEMORY_QUEUE_SIZE);
+            self.command_queue_size = self.command_queue_size.min(LOW_MEMORY_COMMAND_QUEUE_SIZE);
+            self.error_history = self.error_history.min(LOW_MEMORY_ERROR_HISTORY);
+            self.state_cache = self.state_cache.with_low_memory();
+            info!("Low memory profile: queue of {} reports, {} commands", self.queue_size, self.command_queue_size);
+        }
+        let dedup_window = if self.ha_driven { self.dedup_window } else { Duration::ZERO };
+        let config = std::mem::take(&mut self.config);
+        let context = Arc::new(BridgeContext::new(config, dedup_window, self.error_history));
+        // The tasks spawned from here on inherit the context
+        context::scope(context.clone(), self.start(context)).await
+    }
+
+    async fn start(self, context: Arc<BridgeContext>) -> Result<Bridge, String> {
+        let (mqtt_uri, mqtt_config) = self.mqtt.ok_or("no MQTT broker given")?;
+        mqtt_client::check_options(&mqtt_uri, &mqtt_config).map_err(|e| format!("Broker '{}': {}", mqtt_uri, e))?;
+        if let Some((uri, config)) = &self.secondary {
+            mqtt_client::check_options(uri, config).map_err(|e| format!("Broker '{}': {}", uri, e))?;
+        }
+        let bind_id = self.agent.bind_id;
+        let client_id = self.client_id.unwrap_or_else(|| format!("agent2mqtt-{}", bind_id));
+        let mqtt_client = mqtt::create_client(&mqtt_uri, &client_id).await?;
+
+        let mut qos = self.qos;
+        // The broker only keeps messages for sessions subscribed with QoS >= 1
+        if mqtt_config.persistent_session && qos.command_sub == 0 {
+            warn!("Persistent session requires a command subscription QoS >= 1, using 1");
+            qos.command_sub = 1;
+        }
+
+        let start_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let bridge_info = BridgeInfo::new(bind_id, self.agent.register_keys.clone(), start_time);
+
+        let (tx, rx) = command_channel::channel(self.command_queue_size, self.command_overflow);
+        let mux = self.agent.mux_socket.clone().map(|path| {
+            let mux = Mux::new(bridge_info.bind_id.clone(), self.agent.register_keys.clone());
+            context::spawn(mux::serve(path, mux.clone(), tx.clone()));
+            mux
+        });
+        // Producers stop first so their last reports are flushed before the brokers disconnect
+        let (shutdown_tx, _) = broadcast::channel::<()>(1);
+        let (mqtt_shutdown_tx, _) = broadcast::channel::<()>(1);
+        let mut mqtt_tasks = Vec::new();
+        let mut tasks = Vec::new();
+
+        let rate_limiter = self.rate_limiter.unwrap_or_else(|| RateLimiter::new(None, None, RateLimitPolicy::Drop));
+        let mut publisher = Publisher::new(mqtt_client.clone(), rate_limiter);
+        let state_cache = self.state_cache;
+        let command_filter = Arc::new(Mutex::new(self.command_filter));
+
+        // Supervised tasks are restarted when they fail, repeated failures end up here
+        let (failed_tx, failed_rx) = mpsc::channel::<String>(1);
+
+        if let Some((uri, secondary_config)) = self.secondary {
+            let secondary_client = mqtt::create_client(&uri, &client_id).await?;
+            publisher.add_broker(secondary_client.clone());
+            let info = bridge_info.clone();
+            let task_shutdown_tx = mqtt_shutdown_tx.clone();
+            mqtt_tasks.push(context::spawn(supervisor::supervise(
+                "mqtt_manager (secondary)",
+                move || {
+                    mqtt::mqtt_manager(
+                        secondary_client.clone(),
+                        None,
+                        secondary_config.clone(),
+                        qos,
+                        info.clone(),
+                        task_shutdown_tx.subscribe(),
+                    )
+                },
+                mqtt_shutdown_tx.subscribe(),
+                failed_tx.clone(),
+            )));
+        }
+
+        let correlator = Correlator::spawn();
+        let commands = CommandInput {
+            tx,
+            filter: command_filter.clone(),
+            state_cache: state_cache.clone(),
+            reloader: self.reloader.clone(),
+            correlator: correlator.clone(),
+        };
+        let primary_info = bridge_info.clone();
+        let task_shutdown_tx = mqtt_shutdown_tx.clone();
+        mqtt_tasks.push(context::spawn(supervisor::supervise(
+            "mqtt_manager",
+            move || {
+                mqtt::mqtt_manager(
+                    mqtt_client.clone(),
+                    Some(commands.clone()),
+                    mqtt_config.clone(),
+                    qos,
+                    primary_info.clone(),
+                    task_shutdown_tx.subscribe(),
+                )
+            },
+            mqtt_shutdown_tx.subscribe(),
+            failed_tx.clone(),
+        )));
+
+        let mut ha_driven_rx = None;
+        if self.ha_driven {
+            let (ha_driven_tx, rx) = mpsc::channel(hadriven::QUEUE_SIZE);
+            ha_driven_rx = Some(rx);
+            let ha_publisher = publisher.clone();
+            let ha_state_cache = state_cache.clone();
+            let task_shutdown_tx = shutdown_tx.clone();
+            let options = self.ha_driven_options.clone();
+            tasks.push(context::spawn(supervisor::supervise(
+                "ha_driven_reader",
+                move || {
+                    hadriven::ha_driven_reader(
+                        options.clone(),
+                        ha_publisher.clone(),
+                        ha_driven_tx.clone(),
+                        qos,
+                        ha_state_cache.clone(),
+                        task_shutdown_tx.subscribe(),
+                    )
+                },
+                shutdown_tx.subscribe(),
+                failed_tx,
+            )));
+        }
+
+        if let Some(period) = self.telemetry_interval {
+            tasks.push(context::spawn(telemetry::reporter(publisher.clone(), period, shutdown_tx.subscribe())));
+        }
+
+        if let Some((addr, health_max_idle)) = self.metrics {
+            let listener = metrics::bind(addr).await.map_err(|e| format!("Failed to listen for metrics on {}: {}", addr, e))?;
+            let metrics_context = metrics::Context { publisher: publisher.clone(), health_max_idle };
+            context::spawn(metrics::serve(listener, metrics_context, shutdown_tx.subscribe()));
+        }
+        if let Some(period) = self.stats_interval {
+            tasks.push(context::spawn(stats::reporter(publisher.clone(), period, shutdown_tx.subscribe())));
+        }
+
+        if let Some(endpoint) = self.otlp_endpoint {
+            tasks.push(context::spawn(trace::exporter(endpoint, shutdown_tx.subscribe())));
+        }
+
+        if self.error_history > 0 {
+            tasks.push(context::spawn(errors::reporter(publisher.clone(), shutdown_tx.subscribe())));
+        }
+
+        if systemd::is_enabled() {
+            tasks.push(context::spawn(systemd::notifier(publisher.clone(), shutdown_tx.subscribe())));
+        }
+
+        let publish_queue = PublishQueue::new(self.queue_size, self.queue_overflow, self.queue_file);
+
+        let agent_config = AgentConfig { options: self.agent, qos, info: bridge_info, mux, correlator, ha_driven_rx };
+        let agent_task = context::spawn(logger::tagged("agent_manager", agent::agent_manager::<AgentSocket>(
+            agent_config,
+            publisher.clone(),
+            rx,
+            publish_queue,
+            state_cache.clone(),
+            shutdown_tx.subscribe(),
+        )));
+
+        Ok(Bridge {
+            context,
+            publisher,
+            state_cache,
+            command_filter,
+            reloader: self.reloader,
+            shutdown_tx,
+            mqtt_shutdown_tx,
+            agent_task: Some(agent_task),
+            tasks,
+            mqtt_tasks,
+            failed_rx,
+        })
+    }
+}
+
+// A running bridge between the agent socket and the MQTT brokers
+pub struct Bridge {
+    context: Arc<BridgeContext>,
+    publisher: Publisher,
+    state_cache: StateCache,
+    command_filter: Arc<Mutex<CommandFilter>>,
+    reloader: Option<Reloader>,
+    shutdown_tx: broadcast::Sender<()>,
+    mqtt_shutdown_tx: broadcast::Sender<()>,
+    // None once it returned on its own
+    agent_task: Option<JoinHandle<()>>,
+    // Producers besides the agent task, stopped before the brokers
+    tasks: Vec<JoinHandle<()>>,
+    mqtt_tasks: Vec<JoinHandle<()>>,
+    failed_rx: mpsc::Receiver<String>,
+}
+
+impl Bridge {
+    // e.g. Bridge::builder().mqtt(uri, config).agent(options).spawn().await
+    pub fn builder() -> BridgeBuilder {
+        BridgeBuilder::default()
+    }
+
+    // Publishes through the brokers of the bridge, with its rate limits and gateway tag
+    pub fn publisher(&self) -> &Publisher {
+        &self.publisher
+    }
+
+    // Same as the reload request on aqara2mqtt/bridge/request/reload
+    pub async fn reload(&self) -> Result<(), String> {
+        context::scope(self.context.clone(), async {
+            let messages = reload(self.reloader.as_ref(), &self.command_filter, &self.state_cache)?;
+            for msg in messages {
+                if let Err(e) = self.publisher.publish_raw(msg).await {
+                    error!("Error publishing discovery config: {:?}", e);
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    // Resolves with the reason once the bridge can't go on: the agent task ended, which it
+    // only does when it failed, or a supervised task kept failing
+    pub async fn failed(&mut self) -> String {
+        let Bridge { agent_task, failed_rx, .. } = self;
+        let result = tokio::select! {
+            result = async {
+                match agent_task.as_mut() {
+                    Some(task) => task.await,
+                    None => std::future::pending().await,
+                }
+            } => result,
+            Some(reason) = failed_rx.recv() => return reason,
+        };
+        *agent_task = None;
+        match result {
+            Ok(()) => "task agent_manager stopped unexpectedly".to_string(),
+            Err(e) => format!("task agent_manager failed: {}", e),
+        }
+    }
+
+    // Stops the producers, which flush what they queued, then disconnects from the brokers
+    pub async fn shutdown(self) {
+        let _ = self.shutdown_tx.send(());
+        if let Some(agent_task) = self.agent_task {
+            let _ = agent_task.await;
+        }
+        for task in self.tasks {
+            let _ = task.await;
+        }
+        let _ = self.mqtt_shutdown_tx.send(());
+        for task in self.mqtt_tasks {
+            let _ = task.await;
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use tokio::time::{timeout, Duration};
+
+use crate::agent_socket::{self, AgentSocket, AgentTransport};
+use crate::mqtt_client::{Client, MqttClient, MqttConfig};
+use crate::uds_proxy;
+
+const AGENT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const BROKER_SCHEMES: [&str; 7] = ["tcp://", "mqtt://", "mqtts://", "ssl://", "ws://", "wss://", uds_proxy::UNIX_SCHEME];
+
+// Problems found by check-config, all of them are listed instead of
+// stopping at the first one like startup does
+#[derive(Default)]
+pub struct Report {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn check(&mut self, result: Result<(), String>) {
+        if let Err(e) = result {
+            self.errors.push(e);
+        }
+    }
+
+    pub fn warning(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    // Prints the problems, true when nothing stops the bridge from starting
+    pub fn print(&self) -> bool {
+        for warning in &self.warnings {
+            eprintln!("warning: {}", warning);
+        }
+        for error in &self.errors {
+            eprintln!("error: {}", error);
+        }
+        if self.errors.is_empty() {
+            println!("Config OK");
+        } else {
+            eprintln!("{} error(s) found", self.errors.len());
+        }
+        self.errors.is_empty()
+    }
+}
+
+// Values the bridge builds topics from must not contain wildcards
+pub fn topic_part(option: &str, value: &str) -> Result<(), String> {
+    if value.contains(['+', '#', '\0']) {
+        return Err(format!("--{} '{}' can't contain '+', '#' or NUL, they aren't allowed in topics", option, value));
+    }
+    Ok(())
+}
+
+pub fn topic_prefix(prefix: &str, report: &mut Report) {
+    report.check(topic_part("topic-prefix", prefix));
+    if !prefix.is_empty() && !prefix.ends_with('/') {
+        report.warning(format!(
+            "--topic-prefix '{}' doesn't end with '/', topics will look like '{}aqara2mqtt/...'",
+            prefix, prefix
+        ));
+    }
+}
+
+// The gateway id becomes one topic level, aqara2mqtt/<gwid>/...
+pub fn gateway_id(gateway_id: &str) -> Result<(), String> {
+    topic_part("gateway-id", gateway_id)?;
+    if gateway_id.is_empty() || gateway_id.contains('/') {
+        return Err(format!("--gateway-id '{}' must be a single non-empty topic level without '/'", gateway_id));
+    }
+    Ok(())
+}
+
+pub fn broker_uri(option: &str, uri: &str) -> Result<(), String> {
+    let Some(scheme) = BROKER_SCHEMES.iter().find(|scheme| uri.starts_with(**scheme)) else {
+        return Err(format!("--{} '{}' needs one of the schemes {}", option, uri, BROKER_SCHEMES.join(", ")));
+    };
+    let rest = &uri[scheme.len()..];
+    if rest.is_empty() || rest.starts_with(':') {
+        return Err(format!("--{} '{}' has no host", option, uri));
+    }
+    Ok(())
+}
+
+// PEM file the TLS setup reads, `kind` is the block it must contain, e.g. CERTIFICATE
+pub fn pem_file(option: &str, path: &str, kind: &str) -> Result<(), String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("--{} '{}': {}", option, path, e))?;
+    if !content.contains("-----BEGIN") || !content.contains(kind) {
+        return Err(format!("--{} '{}' is not a PEM file with a {}", option, path, kind));
+    }
+    Ok(())
+}
+
+// Files the bridge creates need an existing directory
+pub fn parent_dir(option: &str, path: &str) -> Result<(), String> {
+    let parent = Path::new(path).parent().filter(|parent| !parent.as_os_str().is_empty());
+    match parent {
+        Some(parent) if !parent.is_dir() => Err(format!("--{} '{}': directory '{}' doesn't exist", option, path, parent.display())),
+        _ => Ok(()),
+    }
+}
+
+// Without a connect test only a missing local socket is noticed, the hub may not be running the agent yet
+pub fn agent_socket(address: &str, report: &mut Report) {
+    if !agent_socket::is_local(address) {
+        return;
+    }
+    match fs::metadata(address) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        Ok(_) => report.check(Err(format!("agent socket '{}' is not a socket", address))),
+        Err(e) => report.warning(format!("agent socket '{}': {}, is miio_agent running?", address, e)),
+    }
+}
+
+pub async fn connect_agent(address: &str) -> Result<(), String> {
+    match timeout(AGENT_CONNECT_TIMEOUT, AgentSocket::connect(address)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("can't connect to the agent at '{}': {}", address, e)),
+        Err(_) => Err(format!("no answer from the agent at '{}' within {:?}", address, AGENT_CONNECT_TIMEOUT)),
+    }
+}
+
+// Connects and disconnects again, returns the negotiated MQTT version.
+// The client id must differ from the running bridge or the broker drops it.
+pub async fn connect_broker(uri: &str, client_id: &str, config: &MqttConfig) -> Result<u32, String> {
+    let (client, version) = connect_client(uri, client_id, config).await?;
+    let _ = client.disconnect().await;
+    Ok(version)
+}
+
+// A connected client and its MQTT version
+pub async fn connect_client(uri: &str, client_id: &str, config: &MqttConfig) -> Result<(Client, u32), String> {
+    let server_uri = if uds_proxy::needed(uri) {
+        uds_proxy::start(uri).await.map_err(|e| format!("'{}': {}", uri, e))?
+    } else {
+        uri.to_string()
+    };
+    let client = Client::new(&server_uri, client_id).map_err(|e| format!("'{}': {}", uri, e))?;
+    let mut last_error = String::new();
+    // Brokers without v5 support get a second try with 3.1.1, like the reconnect loop does
+    for v5 in [true, false] {
+        match timeout(config.connect_timeout, client.connect(config, v5)).await {
+            Ok(Ok(version)) => return Ok((client, version)),
+            Ok(Err(e)) => last_error = e.to_string(),
+            Err(_) => last_error = format!("no answer within {:?}", config.connect_timeout),
+        }
+    }
+    Err(format!("can't connect to the MQTT broker at '{}': {}", uri, last_error))
+}
@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::base64;
+
+// JSON-RPC error codes of the acks the bridge publishes itself
+pub const ERROR_REJECTED: i32 = -32600;
+pub const ERROR_AGENT_UNAVAILABLE: i32 = -32000;
+pub const ERROR_TIMEOUT: i32 = -32001;
+pub const ERROR_QUEUE_FULL: i32 = -32002;
+
+const MATTER_CONTROL_KEY: &str = "matter.control";
+
+// Ids for commands whose envelope is built by the bridge
+static NEXT_ID: AtomicU64 = AtomicU64::new(100_000);
+
+// How a command is turned into what the agent gets, picked by the
+// miio/command/<route> topic suffix
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Route {
+    // miio/command, a JSON command stamped with our address
+    Json,
+    // miio/command/raw, payload bytes go to the agent untouched
+    Raw,
+    // miio/command/raw/base64, like raw after base64 decoding
+    RawBase64,
+    // miio/command/rpc, {"method":..,"params":..} wrapped with an id
+    Rpc,
+    // miio/command/matter, like rpc but addressed to the matter.control key
+    Matter,
+}
+
+pub fn parse_route(suffix: &str) -> Option<Route> {
+    match suffix {
+        "" => Some(Route::Json),
+        "raw" => Some(Route::Raw),
+        "raw/base64" => Some(Route::RawBase64),
+        "rpc" => Some(Route::Rpc),
+        "matter" => Some(Route::Matter),
+        _ => None,
+    }
+}
+
+pub fn command_id(payload: &[u8]) -> Option<Value> {
+    serde_json::from_slice::<Value>(payload).ok().and_then(|v| v.get("id").cloned())
+}
+
+// Stamps a JSON command with the bridge's bind address as `_from` so that the
+// agent's reply comes back addressed to it. Commands that already carry an
+// address or aren't JSON objects are sent as they are.
+pub fn stamp_address(payload: &[u8], address: u32) -> Vec<u8> {
+    match serde_json::from_slice::<Value>(payload) {
+        Ok(Value::Object(mut map)) if !map.contains_key("_from") => {
+            map.insert("_from".to_string(), Value::from(address));
+            Value::Object(map).to_string().into_bytes()
+        }
+        _ => payload.to_vec(),
+    }
+}
+
+// Whether a frame is addressed to us, None when it has no `_to` address
+pub fn addressed_to(document: &Value, address: u32) -> Option<bool> {
+    document.get("_to").and_then(Value::as_u64).map(|to| to == address as u64)
+}
+
+// Payload of a synthetic error ack for a command the agent never answered
+pub fn error_ack(id: Option<Value>, code: i32, message: &str) -> String {
+    json!({
+        "id": id.unwrap_or(Value::Null),
+        "error": { "code": code, "message": message },
+    })
+    .to_string()
+}
+
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn is_raw(route: Route) -> bool {
+    matches!(route, Route::Raw | Route::RawBase64)
+}
+
+// Builds the bytes sent to the agent, `address` is our bind address
+pub fn build(route: Route, payload: &[u8], address: u32) -> Result<Vec<u8>, String> {
+    match route {
+        Route::Json => return Ok(stamp_address(payload, address)),
+        Route::Raw => return Ok(payload.to_vec()),
+        Route::RawBase64 => return base64::decode(payload),
+        Route::Rpc | Route::Matter => {}
+    }
+    let mut map = match serde_json::from_slice::<Value>(payload) {
+        Ok(Value::Object(map)) => map,
+        Ok(_) => return Err("command must be a JSON object".to_string()),
+        Err(e) => return Err(format!("command is not valid JSON: {}", e)),
+    };
+    if !map.get("method").is_some_and(Value::is_string) {
+        return Err("command has no method".to_string());
+    }
+    if !map.contains_key("id") {
+        map.insert("id".to_string(), Value::from(next_id()));
+    }
+    if route == Route::Matter {
+        map.insert("key".to_string(), Value::from(MATTER_CONTROL_KEY));
+    }
+    if !map.contains_key("_from") {
+        map.insert("_from".to_string(), Value::from(address));
+    }
+    Ok(Value::Object(map).to_string().into_bytes())
+}
+
+// Commands received while the agent socket is down, replayed once it is back
+pub struct HeldCommands {
+    commands: VecDeque<(Instant, Vec<u8>)>,
+    capacity: usize,
+    max_age: Duration,
+}
+
+impl HeldCommands {
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        HeldCommands { commands: VecDeque::new(), capacity, max_age }
+    }
+
+    // Returns the oldest command when it had to make room
+    pub fn hold(&mut self, payload: Vec<u8>) -> Option<Vec<u8>> {
+        let evicted = if self.commands.len() >= self.capacity {
+            self.commands.pop_front().map(|(_, payload)| payload)
+        } else {
+            None
+        };
+        self.commands.push_back((Instant::now(), payload));
+        evicted
+    }
+
+    // Empties the buffer into (commands to replay, commands too old to replay)
+    pub fn drain(&mut self) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let max_age = self.max_age;
+        let (fresh, stale): (Vec<_>, Vec<_>) = self.commands.drain(..).partition(|(received, _)| received.elapsed() < max_age);
+        (
+            fresh.into_iter().map(|(_, payload)| payload).collect(),
+            stale.into_iter().map(|(_, payload)| payload).collect(),
+        )
+    }
+}
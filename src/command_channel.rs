@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+
+use clap::ValueEnum;
+use tokio::sync::Notify;
+
+use crate::stats;
+
+// Commands from MQTT and mux clients waiting for the agent task
+pub const DEFAULT_CAPACITY: usize = 32;
+
+// What happens to a command while the agent task is a full queue behind
+#[derive(Clone, Copy, ValueEnum)]
+pub enum CommandOverflow {
+    // Wait for room, which holds up the MQTT connection meanwhile
+    Block,
+    // Make room by dropping the command that waited longest
+    DropOldest,
+    // Refuse the incoming command
+    DropNewest,
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    // Refused by the drop-newest policy
+    Full,
+    // The agent task is gone
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full => f.write_str("command queue full"),
+            SendError::Closed => f.write_str("agent task gone"),
+        }
+    }
+}
+
+struct State {
+    commands: VecDeque<Vec<u8>>,
+    senders: usize,
+    receiver: bool,
+}
+
+struct Shared {
+    // A std Mutex is fine here: it is only held for a few queue operations and
+    // never across an .await, the Notify pair does the waiting
+    state: Mutex<State>,
+    capacity: usize,
+    overflow: CommandOverflow,
+    readable: Notify,
+    writable: Notify,
+}
+
+pub fn channel(capacity: usize, overflow: CommandOverflow) -> (CommandSender, CommandReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State { commands: VecDeque::new(), senders: 1, receiver: true }),
+        capacity: capacity.max(1),
+        overflow,
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    (CommandSender { shared: shared.clone() }, CommandReceiver { shared })
+}
+
+pub struct CommandSender {
+    shared: Arc<Shared>,
+}
+
+impl CommandSender {
+    // Queues a command for the agent task. With drop-oldest the command that
+    // made room is returned, so its sender can be told.
+    pub async fn send(&self, command: Vec<u8>) -> Result<Option<Vec<u8>>, SendError> {
+        loop {
+            let mut writable = pin!(self.shared.writable.notified());
+            writable.as_mut().enable();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if !state.receiver {
+                    return Err(SendError::Closed);
+                }
+                let mut dropped = None;
+                if state.commands.len() >= self.shared.capacity {
+                    match self.shared.overflow {
+                        // Waits below until the agent task takes a command
+                        CommandOverflow::Block => {}
+                        CommandOverflow::DropOldest => dropped = state.commands.pop_front(),
+                        CommandOverflow::DropNewest => {
+                            stats::inc(|stats| &stats.commands_dropped);
+                            return Err(SendError::Full);
+                        }
+                    }
+                }
+                if state.commands.len() < self.shared.capacity {
+                    if dropped.is_some() {
+                        stats::inc(|stats| &stats.commands_dropped);
+                    }
+                    state.commands.push_back(command);
+                    self.shared.readable.notify_one();
+                    return Ok(dropped);
+                }
+            }
+            writable.await;
+        }
+    }
+}
+
+impl Clone for CommandSender {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        CommandSender { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for CommandSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+pub struct CommandReceiver {
+    shared: Arc<Shared>,
+}
+
+impl CommandReceiver {
+    // The next command, None once every sender is gone. Cancel safe.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let mut readable = pin!(self.shared.readable.notified());
+            readable.as_mut().enable();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(command) = state.commands.pop_front() {
+                    self.shared.writable.notify_one();
+                    return Some(command);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            readable.await;
+        }
+    }
+
+    // The next command if one is waiting
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        let command = self.shared.state.lock().unwrap().commands.pop_front();
+        if command.is_some() {
+            self.shared.writable.notify_one();
+        }
+        command
+    }
+}
+
+impl Drop for CommandReceiver {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver = false;
+        self.shared.writable.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{timeout, Duration};
+
+    use super::*;
+
+    async fn fill(sender: &CommandSender, commands: &[&[u8]]) {
+        for command in commands {
+            assert!(sender.send(command.to_vec()).await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (sender, mut receiver) = channel(2, CommandOverflow::Block);
+        fill(&sender, &[b"1", b"2"]).await;
+        assert!(timeout(Duration::from_millis(50), sender.send(b"3".to_vec())).await.is_err());
+
+        let blocked = tokio::spawn(async move { sender.send(b"3".to_vec()).await.map(|dropped| dropped.is_none()) });
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        assert!(blocked.await.unwrap().unwrap());
+        assert_eq!(receiver.recv().await.unwrap(), b"2");
+        assert_eq!(receiver.recv().await.unwrap(), b"3");
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_returns_the_dropped_command() {
+        let (sender, mut receiver) = channel(2, CommandOverflow::DropOldest);
+        fill(&sender, &[b"1", b"2"]).await;
+        assert_eq!(sender.send(b"3".to_vec()).await.unwrap().unwrap(), b"1");
+        assert_eq!(receiver.try_recv().unwrap(), b"2");
+        assert_eq!(receiver.try_recv().unwrap(), b"3");
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn drop_newest_refuses_the_command() {
+        let (sender, mut receiver) = channel(2, CommandOverflow::DropNewest);
+        fill(&sender, &[b"1", b"2"]).await;
+        assert!(matches!(sender.send(b"3".to_vec()).await, Err(SendError::Full)));
+        assert_eq!(receiver.try_recv().unwrap(), b"1");
+        assert_eq!(receiver.try_recv().unwrap(), b"2");
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn either_side_going_away_ends_the_channel() {
+        let (sender, receiver) = channel(2, CommandOverflow::Block);
+        drop(receiver);
+        assert!(matches!(sender.send(b"1".to_vec()).await, Err(SendError::Closed)));
+
+        let (sender, mut receiver) = channel(2, CommandOverflow::Block);
+        let second = sender.clone();
+        fill(&second, &[b"1"]).await;
+        drop(sender);
+        drop(second);
+        // What was queued is still handed out
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        assert!(receiver.recv().await.is_none());
+    }
+}
@@ -0,0 +1,27 @@
+use clap::ValueEnum;
+use serde_json::Value;
+
+use crate::context;
+
+// openmiio_agent publishes agent traffic on these instead of openmiio/report
+pub const TOPIC_MIIO_REPORT: &str = "miio/report";
+pub const TOPIC_MIIO_REPORT_ACK: &str = "miio/report_ack";
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Compat {
+    // Topic layout and payloads of openmiio_agent
+    Openmiio,
+}
+
+pub fn is_openmiio() -> bool {
+    context::current().config.compat == Some(Compat::Openmiio)
+}
+
+// Replies go to report_ack like openmiio_agent does, everything else to report
+pub fn openmiio_topic(frame: &Value) -> &'static str {
+    if frame.get("result").is_some() || frame.get("error").is_some() {
+        TOPIC_MIIO_REPORT_ACK
+    } else {
+        TOPIC_MIIO_REPORT
+    }
+}
@@ -0,0 +1,273 @@
+use std::ffi::OsString;
+use std::fs;
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command};
+use serde_json::{Map, Value};
+
+// Precedence, highest first: command line, environment, config file, defaults.
+// The file sets the same options as the command line, keyed by the long flag
+// name with - or _, e.g.
+//
+//   mqtt_uri = "mqtts://broker:8883"
+//   register = ["auto.report", "matter.event"]
+//   zigbee2mqtt_topics = true
+//
+// JSON when the file ends in .json. Otherwise a TOML subset, no TOML parser is
+// pulled in for a handful of flat options: one `key = value` per line, strings in
+// "" or '', integers, floats, booleans, one-line arrays of those and # comments.
+// Tables, inline tables, multi-line strings or arrays, dates and \u escapes are
+// errors. YAML isn't read.
+
+fn parse_string(value: &str, quote: char) -> Result<(String, &str), String> {
+    let mut out = String::new();
+    let mut chars = value.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok((out, &value[i + 1..])),
+            // Literal 'strings' have no escapes
+            '\\' if quote == '"' => match chars.next().map(|(_, c)| c) {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c @ ('"' | '\\')) => out.push(c),
+                other => return Err(format!("unsupported escape '\\{}'", other.unwrap_or(' '))),
+            },
+            c => out.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+// One value from the start of `input`, returns it with the rest of the input
+fn parse_value(input: &str) -> Result<(Value, &str), String> {
+    let input = input.trim_start();
+    if let Some(rest) = input.strip_prefix('"') {
+        let (s, rest) = parse_string(rest, '"')?;
+        return Ok((Value::String(s), rest));
+    }
+    if let Some(rest) = input.strip_prefix('\'') {
+        let (s, rest) = parse_string(rest, '\'')?;
+        return Ok((Value::String(s), rest));
+    }
+    if let Some(mut rest) = input.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest);
+            if rest.is_empty() {
+                return Err("unterminated array, arrays must be on one line".to_string());
+            }
+        }
+    }
+    let end = input.find([',', ']', '#']).unwrap_or(input.len());
+    let (token, rest) = input.split_at(end);
+    let token = token.trim();
+    let value = match token {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => {
+            let number = token.replace('_', "");
+            if let Ok(int) = number.parse::<i64>() {
+                Value::from(int)
+            } else if let Ok(float) = number.parse::<f64>() {
+                Value::from(float)
+            } else {
+                return Err(format!("invalid value '{}'", token));
+            }
+        }
+    };
+    Ok((value, rest))
+}
+
+fn parse_toml(content: &str) -> Result<Map<String, Value>, String> {
+    let mut options = Map::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |e: String| format!("line {}: {}", number + 1, e);
+        if line.starts_with('[') {
+            return Err(error("tables are not supported, put all options at the top level".to_string()));
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| error("expected key = value".to_string()))?;
+        let (value, rest) = parse_value(value).map_err(error)?;
+        let rest = rest.trim();
+        if !(rest.is_empty() || rest.starts_with('#')) {
+            return Err(error(format!("unexpected '{}'", rest)));
+        }
+        options.insert(key.trim().trim_matches('"').to_string(), value);
+    }
+    Ok(options)
+}
+
+pub fn load(path: &str) -> Result<Map<String, Value>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let options = if path.ends_with(".json") {
+        match serde_json::from_str(&content) {
+            Ok(Value::Object(options)) => Ok(options),
+            Ok(_) => Err("expected a JSON object".to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    } else {
+        parse_toml(&content)
+    };
+    options.map_err(|e| format!("{}: {}", path, e))
+}
+
+fn scalar(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(format!("unsupported value {}", value)),
+    }
+}
+
+// Command line arguments for the file options that weren't given on the
+// command line or in the environment
+pub fn to_args(options: &Map<String, Value>, command: &Command, matches: &ArgMatches) -> Result<Vec<OsString>, String> {
+    let mut args = Vec::new();
+    for (key, value) in options {
+        let long = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()) || arg.get_id().as_str() == key.replace('-', "_"))
+            .ok_or_else(|| format!("unknown option '{}'", key))?;
+        if arg.get_id() == "config" {
+            return Err("'config' can't be set in the config file".to_string());
+        }
+        if matches!(matches.value_source(arg.get_id().as_str()), Some(ValueSource::CommandLine | ValueSource::EnvVariable)) {
+            continue;
+        }
+        let flag = format!("--{}", arg.get_long().unwrap_or(long.as_str()));
+        let takes_values = arg.get_action().takes_values();
+        let optional_value = arg.get_num_args().is_some_and(|n| n.min_values() == 0);
+        match value {
+            // Flags, and options with an optional value, are given bare or left out
+            Value::Bool(true) if !takes_values || optional_value => args.push(flag.into()),
+            Value::Bool(false) if !takes_values || optional_value => {}
+            Value::Array(items) => {
+                for item in items {
+                    args.push(flag.clone().into());
+                    args.push(scalar(item).map_err(|e| format!("'{}': {}", key, e))?.into());
+                }
+            }
+            value => {
+                args.push(flag.into());
+                args.push(scalar(value).map_err(|e| format!("'{}': {}", key, e))?.into());
+            }
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, ArgAction};
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse(content: &str) -> Result<Value, String> {
+        parse_toml(content).map(Value::Object)
+    }
+
+    #[test]
+    fn strings_and_escapes() {
+        let options = parse(concat!(
+            "basic = \"tab\\there\\nquote \\\" backslash \\\\\"\n",
+            "literal = 'C:\\path\\n'\n",
+            "\"quoted_key\" = \"x\"\n",
+        ))
+        .unwrap();
+        assert_eq!(options["basic"], "tab\there\nquote \" backslash \\");
+        assert_eq!(options["literal"], "C:\\path\\n");
+        assert_eq!(options["quoted_key"], "x");
+    }
+
+    #[test]
+    fn hash_inside_strings_and_comments() {
+        let options = parse("# comment\n\nprefix = \"a#b\" # trailing\nname = 'c#d'\ncount = 3 # trailing\n").unwrap();
+        assert_eq!(options, json!({"prefix": "a#b", "name": "c#d", "count": 3}));
+    }
+
+    #[test]
+    fn one_line_arrays() {
+        let options = parse("register = [\"auto.report\", 'matter.event', ]\nempty = []\nmixed = [1, true, \"x,y\"]\n").unwrap();
+        assert_eq!(options["register"], json!(["auto.report", "matter.event"]));
+        assert_eq!(options["empty"], json!([]));
+        assert_eq!(options["mixed"], json!([1, true, "x,y"]));
+    }
+
+    #[test]
+    fn numbers_and_booleans() {
+        let options = parse("size = 1_000\nrate = 2.5\nneg = -3\nflag = true\noff = false\n").unwrap();
+        assert_eq!(options, json!({"size": 1000, "rate": 2.5, "neg": -3, "flag": true, "off": false}));
+    }
+
+    #[test]
+    fn rejected_forms() {
+        let rejected = [
+            "[mqtt]\nuri = \"x\"",
+            "mqtt.uri = { host = \"x\" }",
+            "text = \"\"\"multi\"\"\"",
+            "list = [1,\n2]",
+            "date = 1979-05-27",
+            "text = \"\\u00e9\"",
+            "text = \"unterminated",
+            "just a line",
+            "count = 1 2",
+            "text = \"a\" b",
+        ];
+        for content in rejected {
+            assert!(parse(content).is_err(), "accepted {:?}", content);
+        }
+        assert_eq!(parse("ok = 1\n[table]").unwrap_err(), "line 2: tables are not supported, put all options at the top level");
+    }
+
+    fn command() -> Command {
+        Command::new("test")
+            .arg(Arg::new("config").long("config"))
+            .arg(Arg::new("zigbee2mqtt_topics").long("zigbee2mqtt-topics").action(ArgAction::SetTrue))
+            .arg(Arg::new("ha_discovery").long("ha-discovery").num_args(0..=1))
+            .arg(Arg::new("register").long("register").action(ArgAction::Append))
+            .arg(Arg::new("mqtt_uri").long("mqtt-uri"))
+    }
+
+    fn args(options: Value, command_line: &[&str]) -> Result<Vec<String>, String> {
+        let Value::Object(options) = options else { unreachable!() };
+        let matches = command().get_matches_from(std::iter::once("test").chain(command_line.iter().copied()));
+        let args = to_args(&options, &command(), &matches)?;
+        Ok(args.into_iter().map(|arg| arg.into_string().unwrap()).collect())
+    }
+
+    #[test]
+    fn file_options_become_arguments() {
+        let options = json!({"zigbee2mqtt-topics": true, "register": ["auto.report", "matter.event"], "mqtt_uri": "tcp://x"});
+        let mut args = args(options, &[]).unwrap();
+        args.sort();
+        assert_eq!(args, ["--mqtt-uri", "--register", "--register", "--zigbee2mqtt-topics", "auto.report", "matter.event", "tcp://x"]);
+    }
+
+    #[test]
+    fn optional_values() {
+        assert_eq!(args(json!({"ha_discovery": true}), &[]).unwrap(), ["--ha-discovery"]);
+        assert!(args(json!({"ha_discovery": false}), &[]).unwrap().is_empty());
+        assert_eq!(args(json!({"ha_discovery": "ha"}), &[]).unwrap(), ["--ha-discovery", "ha"]);
+        assert!(args(json!({"zigbee2mqtt_topics": false}), &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn command_line_wins_and_unknown_keys_fail() {
+        assert!(args(json!({"mqtt_uri": "tcp://file"}), &["--mqtt-uri", "tcp://cli"]).unwrap().is_empty());
+        assert!(args(json!({"no_such_option": 1}), &[]).is_err());
+        assert!(args(json!({"config": "other.toml"}), &[]).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use std::future::Future;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex, RwLock};
+
+use once_cell::sync::Lazy;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+use crate::availability::DeviceTimeouts;
+use crate::compat::Compat;
+use crate::stats::Stats;
+use crate::{dedup, errors, health};
+
+// Settings of one bridge that the modules look up while handling its messages
+#[derive(Clone, Default)]
+pub struct BridgeConfig {
+    // e.g. "gw1/" so that two bridges can share a broker
+    pub topic_prefix: String,
+    // Inserted into the aqara2mqtt/ topics and added as `_gw` to JSON payloads
+    pub gateway_id: Option<String>,
+    pub compat: Option<Compat>,
+    // `_ts` and `_seq` on reports
+    pub enrich_reports: bool,
+    // `_latency_ms` on acks
+    pub ack_latency: bool,
+    // Low battery threshold in percent, None publishes no battery topics
+    pub battery_threshold: Option<u8>,
+    pub network_quality: bool,
+    // None leaves device availability off
+    pub device_timeouts: Option<DeviceTimeouts>,
+    // Home Assistant discovery prefix at startup, a reload can change it
+    pub ha_discovery: Option<String>,
+}
+
+// The settings of a bridge and the state its tasks share, each bridge in a process has its own
+pub struct BridgeContext {
+    pub config: BridgeConfig,
+    // Discovery prefix in use
+    pub discovery: RwLock<Option<String>>,
+    pub dedup: Mutex<dedup::Seen>,
+    pub errors: Mutex<errors::Recent>,
+    pub stats: Stats,
+    pub health: health::Activity,
+    // Counts every report, so consumers can spot the ones that went missing
+    pub report_seq: AtomicU64,
+}
+
+impl BridgeContext {
+    // A zero dedup window and error history keep nothing
+    pub fn new(config: BridgeConfig, dedup_window: Duration, error_history: usize) -> Self {
+        BridgeContext {
+            discovery: RwLock::new(config.ha_discovery.as_deref().map(|prefix| prefix.trim_end_matches('/').to_string())),
+            config,
+            dedup: Mutex::new(dedup::Seen::new(dedup_window)),
+            errors: Mutex::new(errors::Recent::new(error_history)),
+            stats: Stats::new(),
+            health: health::Activity::default(),
+            report_seq: AtomicU64::new(0),
+        }
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: Arc<BridgeContext>;
+}
+
+// Outside of a bridge, for the subcommands and the code running before spawn
+static DEFAULT: Lazy<Arc<BridgeContext>> =
+    Lazy::new(|| Arc::new(BridgeContext::new(BridgeConfig::default(), Duration::ZERO, 0)));
+
+// The context of the bridge the calling task belongs to
+pub fn current() -> Arc<BridgeContext> {
+    CURRENT.try_with(Arc::clone).unwrap_or_else(|_| DEFAULT.clone())
+}
+
+// Runs `future` as part of the bridge with `context`
+pub fn scope<F: Future>(context: Arc<BridgeContext>, future: F) -> impl Future<Output = F::Output> {
+    CURRENT.scope(context, future)
+}
+
+// tokio::spawn, the task belongs to the same bridge as the caller
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(scope(current(), future))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats;
+
+    fn bridge(topic_prefix: &str) -> Arc<BridgeContext> {
+        let config = BridgeConfig { topic_prefix: topic_prefix.to_string(), ..BridgeConfig::default() };
+        Arc::new(BridgeContext::new(config, Duration::ZERO, 0))
+    }
+
+    #[tokio::test]
+    async fn spawned_tasks_stay_with_their_bridge() {
+        let (gw1, gw2) = (bridge("gw1/"), bridge("gw2/"));
+        let prefix = scope(gw1.clone(), async {
+            let task = spawn(async {
+                stats::inc(|stats| &stats.parse_errors);
+                current().config.topic_prefix.clone()
+            });
+            task.await.unwrap()
+        })
+        .await;
+        assert_eq!(prefix, "gw1/");
+        scope(gw2.clone(), async { stats::inc(|stats| &stats.commands_dropped) }).await;
+
+        assert_eq!(scope(gw1, async { (stats::get(|s| &s.parse_errors), stats::get(|s| &s.commands_dropped)) }).await, (1, 0));
+        assert_eq!(scope(gw2, async { (stats::get(|s| &s.parse_errors), stats::get(|s| &s.commands_dropped)) }).await, (0, 1));
+    }
+}
@@ -0,0 +1,178 @@
+use log::debug;
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Duration;
+
+use crate::context;
+use crate::pending::{PendingCommand, PendingCommands};
+
+// Requests waiting for the correlation task, it is fast so a short queue is enough
+const REQUEST_QUEUE_SIZE: usize = 64;
+
+// What a frame from the agent is to the commands the bridge sent
+pub enum Reply {
+    // Not an answer to one of our commands
+    Report,
+    // Answers a pending command, which is no longer pending
+    Answer(PendingCommand),
+    // Addressed to us, but the command was already given up
+    Late(u64),
+}
+
+enum Request {
+    Track(PendingCommand),
+    Reply(Option<u64>, Option<bool>, oneshot::Sender<Reply>),
+    Take(u64, oneshot::Sender<Option<PendingCommand>>),
+    Restart(u64),
+    Retry(PendingCommand),
+    TakeExpired(Duration, oneshot::Sender<Vec<PendingCommand>>),
+}
+
+// Matches answers from the agent to the commands sent to it. Replies addressed
+// to us are acks even when the command was already given up, frames for another
+// address are reports whatever their id.
+pub fn reply(pending: &mut PendingCommands, id: Option<u64>, addressed: Option<bool>) -> Reply {
+    let Some(id) = id.filter(|_| addressed != Some(false)) else {
+        return Reply::Report;
+    };
+    match pending.take(id) {
+        Some(command) => Reply::Answer(command),
+        None if addressed == Some(true) => Reply::Late(id),
+        None => Reply::Report,
+    }
+}
+
+// Owns the pending commands, the MQTT and agent tasks only talk to it through a Correlator
+async fn run(mut pending: PendingCommands, mut rx: mpsc::Receiver<Request>) {
+    while let Some(request) = rx.recv().await {
+        match request {
+            Request::Track(command) => pending.track(command),
+            Request::Reply(id, addressed, tx) => {
+                let _ = tx.send(reply(&mut pending, id, addressed));
+            }
+            Request::Take(id, tx) => {
+                let _ = tx.send(pending.take(id));
+            }
+            Request::Restart(id) => pending.restart(id),
+            Request::Retry(command) => pending.retry(command),
+            Request::TakeExpired(timeout, tx) => {
+                let _ = tx.send(pending.take_expired(timeout));
+            }
+        }
+    }
+}
+
+// Handle of the correlation task, it stops once every handle is dropped
+#[derive(Clone)]
+pub struct Correlator {
+    tx: mpsc::Sender<Request>,
+}
+
+impl Correlator {
+    pub fn spawn() -> Correlator {
+        let (tx, rx) = mpsc::channel(REQUEST_QUEUE_SIZE);
+        context::spawn(run(PendingCommands::default(), rx));
+        Correlator { tx }
+    }
+
+    async fn request(&self, request: Request) {
+        let _ = self.tx.send(request).await;
+    }
+
+    // Remembers a command sent to the agent, so its answer is published as the ack
+    pub async fn track(&self, json_msg: &Value, payload: Vec<u8>, reply_topic: Option<String>, refresh_state: bool) {
+        let Some(id) = json_msg.get("id").and_then(|v| v.as_u64()) else { return };
+        let to = json_msg.get("_to").and_then(|v| v.as_u64()).unwrap_or(0);
+        let from = json_msg.get("_from").and_then(|v| v.as_u64()).unwrap_or(0);
+        let method = json_msg.get("method").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        debug!("pending command id: {}, to: {}, from: {}", id, to, from);
+        let mut command = PendingCommand::new(id, to, from, method, payload);
+        command.reply_topic = reply_topic;
+        command.refresh_state = refresh_state;
+        self.request(Request::Track(command)).await;
+    }
+
+    pub async fn reply(&self, report: &Value, addressed: Option<bool>) -> Reply {
+        let (tx, rx) = oneshot::channel();
+        let id = report.get("id").and_then(|v| v.as_u64());
+        self.request(Request::Reply(id, addressed, tx)).await;
+        rx.await.unwrap_or(Reply::Report)
+    }
+
+    pub async fn take(&self, id: u64) -> Option<PendingCommand> {
+        let (tx, rx) = oneshot::channel();
+        self.request(Request::Take(id, tx)).await;
+        rx.await.ok().flatten()
+    }
+
+    pub async fn restart(&self, id: u64) {
+        self.request(Request::Restart(id)).await;
+    }
+
+    pub async fn retry(&self, command: PendingCommand) {
+        self.request(Request::Retry(command)).await;
+    }
+
+    pub async fn take_expired(&self, timeout: Duration) -> Vec<PendingCommand> {
+        let (tx, rx) = oneshot::channel();
+        self.request(Request::TakeExpired(timeout, tx)).await;
+        rx.await.unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn pending(ids: &[u64]) -> PendingCommands {
+        let mut pending = PendingCommands::default();
+        for &id in ids {
+            pending.track(PendingCommand::new(id, 0, 7, "get_properties".to_string(), Vec::new()));
+        }
+        pending
+    }
+
+    #[test]
+    fn answer_takes_the_pending_command() {
+        let mut pending = pending(&[1]);
+        assert!(matches!(reply(&mut pending, Some(1), None), Reply::Answer(command) if command.id == 1));
+        // Answered once, the same id again is a report
+        assert!(matches!(reply(&mut pending, Some(1), None), Reply::Report));
+    }
+
+    #[test]
+    fn addressed_reply_without_command_is_late() {
+        let mut pending = pending(&[]);
+        assert!(matches!(reply(&mut pending, Some(2), Some(true)), Reply::Late(2)));
+        assert!(matches!(reply(&mut pending, Some(2), None), Reply::Report));
+    }
+
+    #[test]
+    fn frames_for_others_or_without_id_are_reports() {
+        let mut pending = pending(&[3]);
+        assert!(matches!(reply(&mut pending, Some(3), Some(false)), Reply::Report));
+        assert!(matches!(reply(&mut pending, None, Some(true)), Reply::Report));
+        // Still pending for the frame that answers it
+        assert!(matches!(reply(&mut pending, Some(3), Some(true)), Reply::Answer(_)));
+    }
+
+    #[tokio::test]
+    async fn correlator_tracks_and_expires_commands() {
+        let correlator = Correlator::spawn();
+        let command = json!({"id": 5, "_from": 7, "method": "set_properties"});
+        correlator.track(&command, Vec::new(), Some("reply/topic".to_string()), false).await;
+        correlator.track(&json!({"id": 6}), Vec::new(), None, false).await;
+
+        let Reply::Answer(answered) = correlator.reply(&json!({"id": 5}), Some(true)).await else {
+            panic!("no answer to command 5");
+        };
+        assert_eq!((answered.from, answered.method.as_str()), (7, "set_properties"));
+        assert_eq!(answered.reply_topic.as_deref(), Some("reply/topic"));
+
+        let expired = correlator.take_expired(Duration::ZERO).await;
+        assert_eq!(expired.iter().map(|command| command.id).collect::<Vec<_>>(), vec![6]);
+        assert!(correlator.take(6).await.is_none());
+    }
+}
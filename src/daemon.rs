@@ -0,0 +1,76 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+fn fork() -> Result<libc::pid_t, String> {
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(format!("fork: {}", io::Error::last_os_error()));
+    }
+    Ok(pid)
+}
+
+fn dup2(file: &File, fd: i32) -> Result<(), String> {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        return Err(format!("dup2: {}", io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// Whether the process still exists, also when it belongs to another user
+fn is_running(pid: libc::pid_t) -> bool {
+    pid > 0
+        && (unsafe { libc::kill(pid, 0) } == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+// A pid file naming a running process means another bridge, stale ones are overwritten
+fn check_pidfile(path: &str) -> Result<(), String> {
+    let Ok(content) = fs::read_to_string(path) else {
+        return Ok(());
+    };
+    match content.trim().parse::<libc::pid_t>() {
+        Ok(pid) if is_running(pid) => Err(format!("pid file '{}': already running as pid {}", path, pid)),
+        _ => Ok(()),
+    }
+}
+
+// Runs before the tokio runtime exists, forking a process with threads would lose them.
+// Everything that can fail is checked before detaching, while errors still reach the terminal.
+pub fn start(daemon: bool, log_file: Option<&str>, pidfile: Option<&str>) -> Result<(), String> {
+    if let Some(path) = pidfile {
+        check_pidfile(path)?;
+    }
+    let output = match log_file {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path).map_err(|e| format!("'{}': {}", path, e))?),
+        None if daemon => Some(OpenOptions::new().write(true).open("/dev/null").map_err(|e| format!("/dev/null: {}", e))?),
+        None => None,
+    };
+    if daemon {
+        let null = File::open("/dev/null").map_err(|e| format!("/dev/null: {}", e))?;
+        // The first parent returns to the init script, the second fork leaves a process
+        // that isn't a session leader and can't get a controlling terminal again
+        if fork()? > 0 {
+            unsafe { libc::_exit(0) };
+        }
+        if unsafe { libc::setsid() } < 0 {
+            return Err(format!("setsid: {}", io::Error::last_os_error()));
+        }
+        if fork()? > 0 {
+            unsafe { libc::_exit(0) };
+        }
+        // The working directory is kept, relative paths of the options are read again on reload
+        dup2(&null, libc::STDIN_FILENO)?;
+    }
+    if let Some(output) = output {
+        dup2(&output, libc::STDOUT_FILENO)?;
+        dup2(&output, libc::STDERR_FILENO)?;
+    }
+    if let Some(path) = pidfile {
+        fs::write(path, format!("{}\n", std::process::id())).map_err(|e| format!("pid file '{}': {}", path, e))?;
+    }
+    Ok(())
+}
+
+pub fn remove_pidfile(path: &str) {
+    let _ = fs::remove_file(path);
+}
@@ -0,0 +1,24 @@
+use serde_json::json;
+
+use crate::base64;
+use crate::mqtt_client::Message;
+use crate::stats;
+use crate::topics;
+
+pub const TOPIC_DEADLETTER: &str = "aqara2mqtt/deadletter";
+
+// Data that could not be parsed, `source` is "agent", "mqtt" or "ha_driven"
+pub fn message(source: &str, data: &[u8], reason: &str) -> Message {
+    stats::inc(|stats| &stats.parse_errors);
+    let (encoding, data) = match std::str::from_utf8(data) {
+        Ok(text) => ("utf8", text.to_string()),
+        Err(_) => ("base64", base64::encode(data)),
+    };
+    let payload = json!({
+        "source": source,
+        "reason": reason,
+        "encoding": encoding,
+        "data": data,
+    });
+    Message::new(topics::prefixed(TOPIC_DEADLETTER), payload.to_string(), 0)
+}
@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use serde_json::Value;
+use tokio::time::{Duration, Instant};
+
+use crate::stats;
+use crate::{context, state};
+
+// A report seen on one path is dropped when it shows up on the other this soon after
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(2);
+// Reports remembered at most, a burst beyond this just isn't deduplicated
+const MAX_SEEN: usize = 1024;
+
+// Where a report came in
+#[derive(Clone, Copy, PartialEq)]
+pub enum Origin {
+    Agent,
+    HaDriven,
+}
+
+// Hashes of the latest reports from both paths, oldest first. A zero window keeps none.
+pub struct Seen {
+    reports: VecDeque<(Instant, u64, Origin)>,
+    window: Duration,
+}
+
+impl Seen {
+    pub fn new(window: Duration) -> Self {
+        Seen { reports: VecDeque::new(), window }
+    }
+}
+
+// What identifies a report on both paths: the device, its values and their time.
+// Other fields and the key order may differ between the socket and the log, the
+// parsed objects compare with sorted keys.
+fn hash(report: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let did = state::find_did(report);
+    let params = report.get("params").or_else(|| report.get("value"));
+    if did.is_none() && params.is_none() {
+        report.to_string().hash(&mut hasher);
+        return hasher.finish();
+    }
+    did.hash(&mut hasher);
+    params.map(Value::to_string).hash(&mut hasher);
+    report.get("time").map(Value::to_string).hash(&mut hasher);
+    hasher.finish()
+}
+
+// Whether the same report already came in on the other path within the window.
+// Repeats on the same path are real reports and are kept.
+pub fn is_duplicate(origin: Origin, report: &Value) -> bool {
+    let context = context::current();
+    let mut seen = context.dedup.lock().unwrap();
+    if seen.window.is_zero() {
+        return false;
+    }
+    let now = Instant::now();
+    let window = seen.window;
+    while seen.reports.front().is_some_and(|(at, _, _)| now.duration_since(*at) > window) {
+        seen.reports.pop_front();
+    }
+    let hash = hash(report);
+    if let Some(i) = seen.reports.iter().position(|(_, seen_hash, seen_origin)| *seen_hash == hash && *seen_origin != origin) {
+        // Each copy suppresses only one from the other path
+        seen.reports.remove(i);
+        stats::inc(|stats| &stats.duplicates_suppressed);
+        return true;
+    }
+    if seen.reports.len() >= MAX_SEEN {
+        seen.reports.pop_front();
+    }
+    seen.reports.push_back((now, hash, origin));
+    false
+}
@@ -0,0 +1,105 @@
+use serde_json::{json, Value};
+
+use crate::command;
+use crate::state::{self, TOPIC_DEVICE_PREFIX};
+use crate::topics;
+
+// aqara2mqtt/<did>/set takes {"<property>": value}, the ack goes to aqara2mqtt/<did>/set_result
+pub const TOPIC_DEVICE_SET: &str = "set";
+pub const TOPIC_DEVICE_SET_RESULT: &str = "set_result";
+// aqara2mqtt/<did>/get takes ["<property>", ..], empty for all known ones
+pub const TOPIC_DEVICE_GET: &str = "get";
+
+pub fn subscription(action: &str) -> String {
+    topics::prefixed(&format!("{}/+/{}", TOPIC_DEVICE_PREFIX, action))
+}
+
+// The did of an aqara2mqtt/<did>/<action> topic
+pub fn topic_did<'a>(topic: &'a str, action: &str) -> Option<&'a str> {
+    let prefix = topics::prefixed(&format!("{}/", TOPIC_DEVICE_PREFIX));
+    let did = topic.strip_prefix(&prefix)?.strip_suffix(&format!("/{}", action))?;
+    (!did.is_empty() && !did.contains('/')).then_some(did)
+}
+
+pub fn set_result_topic(did: &str) -> String {
+    topics::prefixed(&format!("{}/{}/{}", TOPIC_DEVICE_PREFIX, did, TOPIC_DEVICE_SET_RESULT))
+}
+
+// Switch style strings become the booleans MIoT expects
+fn property_value(value: &Value) -> Value {
+    match value.as_str().map(str::to_ascii_lowercase).as_deref() {
+        Some("on") | Some("true") => Value::Bool(true),
+        Some("off") | Some("false") => Value::Bool(false),
+        _ => value.clone(),
+    }
+}
+
+fn siid_piid(key: &str) -> Option<(u64, u64)> {
+    let (siid, piid) = key.split_once('.')?;
+    Some((siid.parse().ok()?, piid.parse().ok()?))
+}
+
+// Property key of a name, siid.piid keys are taken as they are
+fn property_key(did: &str, name: &str, resolve: &impl Fn(&str) -> Option<String>) -> Result<(u64, u64), String> {
+    let key = if state::is_resource_key(name) { Some(name.to_string()) } else { resolve(name) };
+    let key = key.ok_or_else(|| format!("unknown property '{}' of '{}'", name, did))?;
+    siid_piid(&key).ok_or_else(|| format!("property '{}' is not a siid.piid property", name))
+}
+
+// Wraps {"power":"on"} into a set_properties rpc, `resolve` maps a property
+// name to its siid.piid, which can also be given directly
+pub fn set_command(did: &str, payload: &[u8], resolve: impl Fn(&str) -> Option<String>, address: u32) -> Result<Vec<u8>, String> {
+    let request = match serde_json::from_slice::<Value>(payload) {
+        Ok(Value::Object(map)) if !map.is_empty() => map,
+        Ok(_) => return Err("request must be a non-empty JSON object".to_string()),
+        Err(e) => return Err(format!("request is not valid JSON: {}", e)),
+    };
+    let mut params = Vec::new();
+    for (name, value) in &request {
+        let (siid, piid) = property_key(did, name, &resolve)?;
+        params.push(json!({ "did": did, "siid": siid, "piid": piid, "value": property_value(value) }));
+    }
+    let rpc = json!({
+        "id": command::next_id(),
+        "method": "set_properties",
+        "params": params,
+        "_from": address,
+    });
+    Ok(rpc.to_string().into_bytes())
+}
+
+// get_properties rpc for the requested properties, a list of names or an object
+// whose keys are names, or `known` when the payload is empty
+pub fn get_command(
+    did: &str,
+    payload: &[u8],
+    resolve: impl Fn(&str) -> Option<String>,
+    known: Vec<String>,
+    address: u32,
+) -> Result<Vec<u8>, String> {
+    let names: Vec<String> = if payload.iter().all(u8::is_ascii_whitespace) {
+        known
+    } else {
+        match serde_json::from_slice::<Value>(payload) {
+            Ok(Value::Array(names)) => names.iter().filter_map(Value::as_str).map(String::from).collect(),
+            Ok(Value::Object(map)) => map.keys().cloned().collect(),
+            Ok(Value::String(name)) => vec![name],
+            Ok(_) => return Err("request must be a list of properties".to_string()),
+            Err(e) => return Err(format!("request is not valid JSON: {}", e)),
+        }
+    };
+    if names.is_empty() {
+        return Err(format!("no known properties of '{}'", did));
+    }
+    let params = names
+        .iter()
+        .map(|name| property_key(did, name, &resolve).map(|(siid, piid)| json!({ "did": did, "siid": siid, "piid": piid })))
+        .collect::<Result<Vec<Value>, String>>()?;
+    let rpc = json!({
+        "id": command::next_id(),
+        "method": "get_properties",
+        "params": params,
+        "_from": address,
+    });
+    Ok(rpc.to_string().into_bytes())
+}
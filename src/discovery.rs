@@ -0,0 +1,250 @@
+use serde_json::{json, Map, Value};
+
+use crate::availability::TOPIC_BRIDGE_STATE;
+use crate::context;
+use crate::inventory::Device;
+use crate::mqtt_client::Message;
+use crate::routing::TOPIC_COMMAND;
+use crate::state::state_topic;
+use crate::topics;
+use crate::zigbee2mqtt::FriendlyNames;
+
+struct Config {
+    prefix: String,
+    command_topic: String,
+}
+
+// Set on reload, the bridge starts with the prefix of its config
+pub fn enable(prefix: &str) {
+    *context::current().discovery.write().unwrap() = Some(prefix.trim_end_matches('/').to_string());
+}
+
+pub fn disable() {
+    *context::current().discovery.write().unwrap() = None;
+}
+
+pub fn prefix() -> Option<String> {
+    context::current().discovery.read().unwrap().clone()
+}
+
+fn config() -> Option<Config> {
+    Some(Config { prefix: prefix()?, command_topic: topics::prefixed(TOPIC_COMMAND) })
+}
+
+// One Home Assistant entity backed by a resource of the device state
+struct Entity {
+    component: &'static str,
+    object_id: &'static str,
+    // Resource key in aqara2mqtt/<did>/state, siid.piid for writable entities
+    key: &'static str,
+    // Brightness of lights, position of covers and target temperature of thermostats
+    level: Option<&'static str>,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    // Raw values are divided by this
+    scale: u32,
+}
+
+const fn sensor(object_id: &'static str, key: &'static str, device_class: &'static str, unit: &'static str, scale: u32) -> Entity {
+    Entity {
+        component: "sensor",
+        object_id,
+        key,
+        level: None,
+        device_class: Some(device_class),
+        unit: Some(unit),
+        scale,
+    }
+}
+
+const fn binary_sensor(object_id: &'static str, key: &'static str, device_class: &'static str) -> Entity {
+    Entity {
+        component: "binary_sensor",
+        object_id,
+        key,
+        level: None,
+        device_class: Some(device_class),
+        unit: None,
+        scale: 1,
+    }
+}
+
+const fn writable(component: &'static str, object_id: &'static str, key: &'static str, level: Option<&'static str>) -> Entity {
+    Entity {
+        component,
+        object_id,
+        key,
+        level,
+        device_class: None,
+        unit: None,
+        scale: 1,
+    }
+}
+
+const BATTERY: Entity = sensor("battery", "8.0.2008", "voltage", "mV", 1);
+
+// Models are matched by prefix, the first match wins
+const MODELS: &[(&str, &[Entity])] = &[
+    ("lumi.sensor_magnet", &[binary_sensor("contact", "3.1.85", "door"), BATTERY]),
+    ("lumi.sensor_motion", &[binary_sensor("motion", "3.1.85", "motion"), sensor("illuminance", "0.4.85", "illuminance", "lx", 1), BATTERY]),
+    ("lumi.sensor_wleak", &[binary_sensor("leak", "3.1.85", "moisture"), BATTERY]),
+    ("lumi.sensor_smoke", &[binary_sensor("smoke", "13.1.85", "smoke"), BATTERY]),
+    ("lumi.weather", &[
+        sensor("temperature", "0.1.85", "temperature", "°C", 100),
+        sensor("humidity", "0.2.85", "humidity", "%", 100),
+        sensor("pressure", "0.3.85", "pressure", "hPa", 100),
+        BATTERY,
+    ]),
+    ("lumi.sensor_ht", &[sensor("temperature", "0.1.85", "temperature", "°C", 100), sensor("humidity", "0.2.85", "humidity", "%", 100), BATTERY]),
+    ("lumi.plug", &[writable("switch", "switch", "2.1", None), sensor("power", "0.12.85", "power", "W", 1)]),
+    ("lumi.switch", &[writable("switch", "switch", "2.1", None)]),
+    ("lumi.light", &[writable("light", "light", "2.1", Some("2.2"))]),
+    ("lumi.curtain", &[writable("cover", "curtain", "2.2", Some("2.4"))]),
+    ("lumi.airrtc", &[writable("climate", "thermostat", "2.1", Some("2.4"))]),
+];
+
+fn entities(model: &str) -> &'static [Entity] {
+    MODELS
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, entities)| *entities)
+        .unwrap_or_default()
+}
+
+// HA ids only allow [a-zA-Z0-9_-], dids look like lumi.158d0001234567
+fn node_id(did: &str) -> String {
+    did.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect()
+}
+
+fn value_template(key: &str, scale: u32) -> String {
+    match scale {
+        1 => format!("{{{{ value_json['{}'] }}}}", key),
+        scale => format!("{{{{ value_json['{}'] / {} }}}}", key, scale),
+    }
+}
+
+// set_properties command for a siid.piid key, `value` is inserted verbatim so it can be a template
+fn set_property(did: &str, key: &str, value: &str) -> String {
+    let (siid, piid) = key.split_once('.').unwrap_or((key, "0"));
+    format!(
+        r#"{{"method":"set_properties","params":[{{"did":"{}","siid":{},"piid":{},"value":{}}}]}}"#,
+        did, siid, piid, value
+    )
+}
+
+fn entity_config(config: &Config, device: &Device, name: &str, entity: &Entity) -> Value {
+    let did = device.did.as_str();
+    let state = state_topic(did);
+    let mut payload = Map::new();
+    let mut set = |key: &str, value: Value| {
+        payload.insert(key.to_string(), value);
+    };
+    set("unique_id", json!(format!("{}_{}", node_id(did), entity.object_id)));
+    set("object_id", json!(format!("{}_{}", node_id(did), entity.object_id)));
+    set("name", json!(entity.object_id));
+    set("availability_topic", json!(topics::prefixed(TOPIC_BRIDGE_STATE)));
+    set("device", json!({
+        "identifiers": [did],
+        "name": name,
+        "model": device.model,
+        "manufacturer": "Aqara",
+    }));
+    if let Some(device_class) = entity.device_class {
+        set("device_class", json!(device_class));
+    }
+    if let Some(unit) = entity.unit {
+        set("unit_of_measurement", json!(unit));
+    }
+    let is_on = format!("value_json['{}'] in [1, true]", entity.key);
+    match entity.component {
+        "sensor" => {
+            set("state_topic", json!(state));
+            set("value_template", json!(value_template(entity.key, entity.scale)));
+        }
+        "binary_sensor" => {
+            set("state_topic", json!(state));
+            set("value_template", json!(format!("{{{{ 'ON' if {} else 'OFF' }}}}", is_on)));
+        }
+        "switch" | "light" => {
+            let template_key = if entity.component == "switch" { "value_template" } else { "state_value_template" };
+            set("state_topic", json!(state));
+            set(template_key, json!(format!("{{{{ 'ON' if {} else 'OFF' }}}}", is_on)));
+            set("command_topic", json!(config.command_topic));
+            set("payload_on", json!(set_property(did, entity.key, "true")));
+            set("payload_off", json!(set_property(did, entity.key, "false")));
+            if let Some(level) = entity.level {
+                set("brightness_state_topic", json!(state));
+                set("brightness_value_template", json!(value_template(level, 1)));
+                set("brightness_command_topic", json!(config.command_topic));
+                set("brightness_command_template", json!(set_property(did, level, "{{ value }}")));
+                set("brightness_scale", json!(100));
+            }
+        }
+        "cover" => {
+            set("command_topic", json!(config.command_topic));
+            set("payload_stop", json!(set_property(did, entity.key, "0")));
+            set("payload_open", json!(set_property(did, entity.key, "1")));
+            set("payload_close", json!(set_property(did, entity.key, "2")));
+            if let Some(level) = entity.level {
+                set("position_topic", json!(state));
+                set("position_template", json!(value_template(level, 1)));
+                set("set_position_topic", json!(config.command_topic));
+                set("set_position_template", json!(set_property(did, level, "{{ position }}")));
+            }
+        }
+        "climate" => {
+            set("modes", json!(["off", "heat"]));
+            set("mode_state_topic", json!(state));
+            set("mode_state_template", json!(format!("{{{{ 'heat' if {} else 'off' }}}}", is_on)));
+            set("mode_command_topic", json!(config.command_topic));
+            set(
+                "mode_command_template",
+                json!(set_property(did, entity.key, "{{ 'true' if value == 'heat' else 'false' }}")),
+            );
+            if let Some(level) = entity.level {
+                set("temperature_state_topic", json!(state));
+                set("temperature_state_template", json!(value_template(level, 1)));
+                set("temperature_command_topic", json!(config.command_topic));
+                set("temperature_command_template", json!(set_property(did, level, "{{ value }}")));
+            }
+        }
+        _ => {}
+    }
+    Value::Object(payload)
+}
+
+// Retained discovery configs for every known entity of the inventory, empty when discovery is off
+fn config_topic(config: &Config, did: &str, entity: &Entity) -> String {
+    format!("{}/{}/{}/{}/config", config.prefix, entity.component, node_id(did), entity.object_id)
+}
+
+pub fn messages(devices: &[Device], names: &FriendlyNames) -> Vec<Message> {
+    let config = config();
+    let Some(config) = config.as_ref() else {
+        return Vec::new();
+    };
+    devices
+        .iter()
+        .flat_map(|device| {
+            // The friendly name wins over the name set in the Aqara app
+            let name = names.name(&device.did).unwrap_or_else(|| {
+                if device.name.is_empty() { device.did.clone() } else { device.name.clone() }
+            });
+            entities(&device.model).iter().map(move |entity| {
+                let topic = config_topic(config, &device.did, entity);
+                Message::new_retained(topic, entity_config(config, device, &name, entity).to_string(), 1)
+            })
+        })
+        .collect()
+}
+
+// Empty retained configs, HA deletes the entities of a removed device
+pub fn clear_messages(did: &str, model: &str) -> Vec<Message> {
+    let Some(config) = config() else {
+        return Vec::new();
+    };
+    entities(model)
+        .iter()
+        .map(|entity| Message::new_retained(config_topic(&config, did, entity), "", 1))
+        .collect()
+}
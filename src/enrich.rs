@@ -0,0 +1,37 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use serde_json::Value;
+
+use crate::context;
+
+// Adds `_latency_ms`, the time since the command came in, to JSON object acks when enabled
+pub fn ack(payload: Bytes, latency: Option<Duration>) -> Bytes {
+    let Some(latency) = latency.filter(|_| context::current().config.ack_latency) else {
+        return payload;
+    };
+    let Ok(Value::Object(mut map)) = serde_json::from_slice::<Value>(&payload) else {
+        return payload;
+    };
+    map.insert("_latency_ms".to_string(), Value::from(latency.as_millis() as u64));
+    Bytes::from(Value::Object(map).to_string())
+}
+
+// Adds `_ts` (epoch millis) and `_seq` to JSON object reports when enabled
+pub fn report(payload: Bytes) -> Bytes {
+    let context = context::current();
+    if !context.config.enrich_reports {
+        return payload;
+    }
+    let Ok(Value::Object(mut map)) = serde_json::from_slice::<Value>(&payload) else {
+        return payload;
+    };
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    map.insert("_ts".to_string(), Value::from(ts));
+    map.insert("_seq".to_string(), Value::from(context.report_seq.fetch_add(1, Ordering::Relaxed)));
+    Bytes::from(Value::Object(map).to_string())
+}
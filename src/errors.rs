@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, Level};
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+use tokio::time::{interval, Duration};
+
+use crate::context;
+use crate::mqtt_client::Message;
+use crate::publisher::Publisher;
+use crate::topics;
+
+pub const TOPIC_ERRORS: &str = "aqara2mqtt/bridge/errors";
+// Changes are published at most this often, a burst of errors is one publish
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(5);
+
+// The latest warnings and errors of the log, capacity 0 keeps none
+pub struct Recent {
+    events: VecDeque<Value>,
+    capacity: usize,
+    changed: bool,
+}
+
+impl Recent {
+    pub fn new(capacity: usize) -> Self {
+        Recent { events: VecDeque::new(), capacity, changed: false }
+    }
+}
+
+// Called by the logger for every warning and error it writes, kept by the bridge it came from
+pub fn record(level: Level, target: &str, task: Option<&str>, message: String) {
+    let context = context::current();
+    let mut recent = context.errors.lock().unwrap();
+    if recent.capacity == 0 || level > Level::Warn {
+        return;
+    }
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    let mut event = json!({ "ts": ts, "level": level.as_str(), "target": target, "message": message });
+    if let Some(task) = task {
+        event["task"] = json!(task);
+    }
+    if recent.events.len() >= recent.capacity {
+        recent.events.pop_front();
+    }
+    recent.events.push_back(event);
+    recent.changed = true;
+}
+
+// The events oldest first, None when nothing changed since the last call
+fn take_changed() -> Option<Value> {
+    let context = context::current();
+    let mut recent = context.errors.lock().unwrap();
+    if !recent.changed {
+        return None;
+    }
+    recent.changed = false;
+    Some(Value::Array(recent.events.iter().cloned().collect()))
+}
+
+pub async fn reporter(publisher: Publisher, mut shutdown: broadcast::Receiver<()>) {
+    let mut timer = interval(PUBLISH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {}
+            _ = shutdown.recv() => return,
+        }
+        // Kept for the next tick while the broker is away
+        if !publisher.is_connected() {
+            continue;
+        }
+        let Some(events) = take_changed() else { continue };
+        let msg = Message::new_retained(topics::prefixed(TOPIC_ERRORS), events.to_string(), 0);
+        // Logged below warn, an error here would be recorded and published again
+        if let Err(e) = publisher.publish(msg).await {
+            debug!("Error publishing recent errors: {:?}", e);
+            context::current().errors.lock().unwrap().changed = true;
+        }
+    }
+}
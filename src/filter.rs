@@ -0,0 +1,181 @@
+use serde_json::Value;
+
+use crate::state::is_resource_key;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Method,
+    Did,
+    Key,
+}
+
+// One `field=glob` rule, e.g. `method=auto.*`, `did=lumi1.54ef*` or `key=4.3.85`
+#[derive(Clone)]
+pub struct FilterRule {
+    field: Field,
+    pattern: String,
+}
+
+pub fn parse_rule(rule: &str) -> Result<FilterRule, String> {
+    let (field, pattern) = rule
+        .split_once('=')
+        .ok_or_else(|| format!("expected field=pattern, got '{}'", rule))?;
+    let field = match field.trim() {
+        "method" => Field::Method,
+        "did" => Field::Did,
+        "key" => Field::Key,
+        other => return Err(format!("unknown filter field '{}', use method, did or key", other)),
+    };
+    Ok(FilterRule { field, pattern: pattern.trim().to_string() })
+}
+
+// Glob matching with `*` for any run of characters and `?` for a single one
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// The values a command exposes for each filter field
+struct CommandFields {
+    methods: Vec<String>,
+    dids: Vec<String>,
+    keys: Vec<String>,
+}
+
+fn collect_fields(json: &Value, fields: &mut CommandFields) {
+    match json {
+        Value::Object(map) => {
+            for (key, value) in map {
+                match (key.as_str(), value) {
+                    ("method", Value::String(method)) => fields.methods.push(method.clone()),
+                    // auto.control carries the resource path in params.name
+                    ("name", Value::String(name)) => fields.methods.push(name.clone()),
+                    ("did" | "sdid", Value::String(did)) => fields.dids.push(did.clone()),
+                    _ => {}
+                }
+                if is_resource_key(key) {
+                    fields.keys.push(key.clone());
+                }
+                if let Value::String(inner) = value {
+                    // Resource writes are often JSON documents embedded in strings
+                    if let Ok(inner @ Value::Object(_)) = serde_json::from_str::<Value>(inner) {
+                        collect_fields(&inner, fields);
+                    }
+                } else {
+                    collect_fields(value, fields);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                if let Value::String(key) = item
+                    && is_resource_key(key)
+                {
+                    fields.keys.push(key.clone());
+                }
+                collect_fields(item, fields);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl FilterRule {
+    fn matches(&self, fields: &CommandFields) -> bool {
+        let values = match self.field {
+            Field::Method => &fields.methods,
+            Field::Did => &fields.dids,
+            Field::Key => &fields.keys,
+        };
+        values.iter().any(|value| glob_match(&self.pattern, value))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CommandFilter {
+    allow: Vec<FilterRule>,
+    deny: Vec<FilterRule>,
+}
+
+impl CommandFilter {
+    pub fn new(allow: Vec<FilterRule>, deny: Vec<FilterRule>) -> Self {
+        CommandFilter { allow, deny }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    // Deny rules win, and with any allow rule present a command must match one of them
+    pub fn check(&self, command: &Value) -> Result<(), String> {
+        let mut fields = CommandFields { methods: Vec::new(), dids: Vec::new(), keys: Vec::new() };
+        collect_fields(command, &mut fields);
+        if let Some(rule) = self.deny.iter().find(|rule| rule.matches(&fields)) {
+            return Err(format!("command denied by rule '{}'", rule.pattern));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.matches(&fields)) {
+            return Err("command not allowed by any rule".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn filter(allow: &[&str], deny: &[&str]) -> CommandFilter {
+        let rules = |rules: &[&str]| rules.iter().map(|rule| parse_rule(rule).unwrap()).collect();
+        CommandFilter::new(rules(allow), rules(deny))
+    }
+
+    #[test]
+    fn glob_stars_and_question_marks() {
+        assert!(glob_match("auto.*", "auto.report"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("lumi?.54ef*", "lumi1.54ef44001"));
+        assert!(glob_match("*.report", "auto.report"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("auto.*", "matter.event"));
+        assert!(!glob_match("lumi?", "lumi"));
+        assert!(!glob_match("a*b", "abc"));
+        assert!(glob_match("4.3.85", "4.3.85"));
+    }
+
+    #[test]
+    fn rules_need_a_known_field() {
+        assert!(parse_rule("method = set_*").is_ok());
+        assert!(parse_rule("model=lumi.*").is_err());
+        assert!(parse_rule("set_properties").is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = filter(&["method=set_*"], &["did=lumi.bad"]);
+        let set = |did: &str| json!({"method": "set_properties", "params": [{"did": did, "siid": 2, "piid": 1, "value": true}]});
+        assert!(filter.check(&set("lumi.good")).is_ok());
+        assert!(filter.check(&set("lumi.bad")).is_err());
+        assert!(filter.check(&json!({"method": "get_properties", "params": []})).is_err());
+        assert!(CommandFilter::default().check(&set("lumi.bad")).is_ok());
+    }
+}
@@ -0,0 +1,94 @@
+use serde_json::{json, Value};
+
+use crate::command;
+use crate::topics;
+
+// aqara2mqtt/gateway/<control>/set for the hub's own hardware, acks on .../set_result
+const TOPIC_GATEWAY_PREFIX: &str = "aqara2mqtt/gateway";
+
+#[derive(Clone, Copy)]
+pub enum Control {
+    // {"state":"ON","brightness":50,"color":{"r":255,"g":0,"b":0}} or "OFF"
+    Led,
+    // 0-100
+    Volume,
+    // "ON"/"OFF", arms or disarms the alarm
+    Alarm,
+}
+
+const CONTROLS: [(&str, Control); 3] = [("led", Control::Led), ("volume", Control::Volume), ("alarm", Control::Alarm)];
+
+pub fn subscription() -> String {
+    topics::prefixed(&format!("{}/+/set", TOPIC_GATEWAY_PREFIX))
+}
+
+pub fn parse_topic(topic: &str) -> Option<(&'static str, Control)> {
+    let name = topic
+        .strip_prefix(&topics::prefixed(&format!("{}/", TOPIC_GATEWAY_PREFIX)))?
+        .strip_suffix("/set")?;
+    CONTROLS.iter().find(|(control, _)| *control == name).copied()
+}
+
+pub fn result_topic(name: &str) -> String {
+    topics::prefixed(&format!("{}/{}/set_result", TOPIC_GATEWAY_PREFIX, name))
+}
+
+fn switch_state(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(on) => Some(*on),
+        Value::String(state) => match state.to_ascii_lowercase().as_str() {
+            "on" | "true" => Some(true),
+            "off" | "false" => Some(false),
+            _ => None,
+        },
+        Value::Object(map) => map.get("state").and_then(switch_state),
+        _ => None,
+    }
+}
+
+fn percent(value: Option<&Value>, field: &str) -> Result<u64, String> {
+    match value.and_then(Value::as_u64) {
+        Some(value) if value <= 100 => Ok(value),
+        _ => Err(format!("'{}' must be 0-100", field)),
+    }
+}
+
+// set_rgb takes brightness << 24 | rgb, 0 turns the LED off
+fn led_value(request: &Value) -> Result<u64, String> {
+    let on = switch_state(request).unwrap_or(request.is_object());
+    if !on {
+        return Ok(0);
+    }
+    let brightness = match request.get("brightness") {
+        Some(brightness) => percent(Some(brightness), "brightness")?,
+        None => 100,
+    };
+    let channel = |name: &str| -> Result<u64, String> {
+        match request.get("color").map(|color| color.get(name).and_then(Value::as_u64)) {
+            None => Ok(255),
+            Some(Some(value)) if value <= 255 => Ok(value),
+            Some(_) => Err(format!("color '{}' must be 0-255", name)),
+        }
+    };
+    Ok(brightness << 24 | channel("r")? << 16 | channel("g")? << 8 | channel("b")?)
+}
+
+// The local rpc for a control request, addressed to us like other bridge commands
+pub fn command(control: Control, payload: &[u8], address: u32) -> Result<Vec<u8>, String> {
+    // Plain ON/OFF as sent by HA switches isn't JSON
+    let request = serde_json::from_slice(payload)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(payload).trim().to_string()));
+    let (method, params) = match control {
+        Control::Led => ("set_rgb", json!([led_value(&request)?])),
+        Control::Volume => {
+            let volume = request.get("volume").unwrap_or(&request);
+            ("set_gateway_volume", json!([percent(Some(volume), "volume")?]))
+        }
+        Control::Alarm => {
+            let armed = switch_state(&request).ok_or("alarm state must be ON or OFF")?;
+            ("set_arming", json!([if armed { "on" } else { "off" }]))
+        }
+    };
+    let rpc = json!({ "id": command::next_id(), "method": method, "params": params, "_from": address });
+    Ok(rpc.to_string().into_bytes())
+}
@@ -0,0 +1,408 @@
+use std::collections::VecDeque;
+use std::io::{self, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+use crate::backoff::Backoff;
+use crate::bridge::QosConfig;
+use crate::dedup::{self, Origin};
+use crate::filter::glob_match;
+use crate::mqtt_client::Message;
+use crate::publisher::Publisher;
+use crate::routing::TOPIC_RESPONSE;
+use crate::state::StateCache;
+use crate::stats;
+use crate::{availability, compat, deadletter, enrich, topics};
+
+// ha_driven is started again after these delays, doubling while it keeps stopping
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const RESTART_DELAY_MAX: Duration = Duration::from_secs(300);
+// A run of this length was not a crash loop, the delay starts over
+const STABLE_RUN: Duration = Duration::from_secs(60);
+// How often a tailed log is checked for new lines
+const TAIL_POLL: Duration = Duration::from_millis(500);
+
+pub const TOPIC_HA_DRIVEN_PREFIX: &str = "aqara2mqtt/ha_driven";
+pub const TOPIC_HA_DRIVEN_ERRORS: &str = "aqara2mqtt/bridge/ha_driven/errors";
+// Messages waiting for the agent task to publish them
+pub const QUEUE_SIZE: usize = 64;
+// The stderr lines kept for the crash report
+const CRASH_EXCERPT_LINES: usize = 20;
+// How long the stderr of a stopped ha_driven is still read
+const STDERR_DRAIN: Duration = Duration::from_millis(200);
+
+// How the bridge gets at what ha_driven logs
+#[derive(Clone, Copy, ValueEnum)]
+pub enum HaDrivenMode {
+    // Kill the running ha_driven and start it again with its output piped
+    Spawn,
+    // Follow the log file ha_driven writes to, leaving the process alone
+    Tail,
+}
+
+#[derive(Clone)]
+pub enum Source {
+    Spawn,
+    Tail(PathBuf),
+}
+
+// One `class=glob` rule on whole ha_driven lines, e.g. `event=*"method":"event.*`.
+// Matching lines go to aqara2mqtt/ha_driven/<class>.
+#[derive(Clone)]
+pub struct LineRule {
+    class: String,
+    pattern: String,
+}
+
+pub fn parse_rule(rule: &str) -> Result<LineRule, String> {
+    let (class, pattern) = rule
+        .split_once('=')
+        .ok_or_else(|| format!("expected class=pattern, got '{}'", rule))?;
+    let class = class.trim();
+    if class.is_empty() || class.contains(['/', '+', '#']) {
+        return Err(format!("'{}' can't be used as a topic level", class));
+    }
+    Ok(LineRule { class: class.to_string(), pattern: pattern.trim().to_string() })
+}
+
+// Button events and property changes, the first matching rule wins. Broader
+// classes like heartbeats match a lot of log noise and are left to --ha-driven-rule.
+pub const DEFAULT_RULES: &[&str] = &[
+    r#"event=*onReceiveMessage*"method":"event.*"#,
+    r#"props=*onReceiveMessage*"method":"props"*"#,
+];
+
+// The ha_driven reader of a bridge, the defaults match the command line
+#[derive(Clone)]
+pub struct HaDrivenOptions {
+    pub source: Source,
+    // Lines besides res/report to publish, by class
+    pub rules: Vec<LineRule>,
+    // Publish the reason and last stderr lines when a spawned ha_driven stops
+    pub publish_errors: bool,
+}
+
+impl Default for HaDrivenOptions {
+    fn default() -> Self {
+        HaDrivenOptions {
+            source: Source::Spawn,
+            rules: DEFAULT_RULES.iter().filter_map(|rule| parse_rule(rule).ok()).collect(),
+            publish_errors: false,
+        }
+    }
+}
+
+// How one run of the reader ended
+enum Exit {
+    Shutdown,
+    Stopped(String),
+}
+
+// Where the lines read from ha_driven go. Its messages are published by the
+// agent task through the publish queue, in order with the agent's own.
+struct Forwarder {
+    publisher: Publisher,
+    tx: mpsc::Sender<Message>,
+    qos: QosConfig,
+    state_cache: StateCache,
+    rules: Vec<LineRule>,
+    publish_errors: bool,
+}
+
+impl Forwarder {
+    async fn forward(&self, msg: Message) {
+        stats::inc(|stats| &stats.ha_driven_forwarded);
+        if self.tx.send(msg).await.is_err() {
+            debug!("Agent task gone, dropping ha_driven message");
+        }
+    }
+
+    // Publishes the res/report lines like reports from the agent socket, lines
+    // without a valid report go to the dead letter topic
+    async fn line(&self, line: &str) {
+        debug!("Captured line: {}", line);
+        if !(line.contains("onReceiveMessage") && line.contains("method") && line.contains("res/report")) {
+            if let Some(rule) = self.rules.iter().find(|rule| glob_match(&rule.pattern, line)) {
+                self.classified(&rule.class, line).await;
+            }
+            return;
+        }
+        let (report, json) = match extract_json(line) {
+            Ok(extracted) => extracted,
+            Err(reason) => {
+                warn!("Unparseable ha_driven line: {}", reason);
+                self.forward(deadletter::message("ha_driven", line.as_bytes(), &reason)).await;
+                return;
+            }
+        };
+        debug!("res/report line: {}", json);
+        if dedup::is_duplicate(Origin::HaDriven, &report) {
+            debug!("Report already forwarded from the agent socket");
+            return;
+        }
+        for msg in self.state_cache.updates(&report, self.qos.report) {
+            if let Some(msg) = self.publisher.admit(msg) {
+                self.forward(msg).await;
+            }
+        }
+        let topic = if compat::is_openmiio() { compat::TOPIC_MIIO_REPORT } else { TOPIC_RESPONSE };
+        let payload = enrich::report(Bytes::copy_from_slice(json.as_bytes()));
+        if let Some(msg) = self.publisher.admit(Message::new(topics::prefixed(topic), payload, self.qos.report)) {
+            self.forward(msg).await;
+        }
+    }
+
+    // Publishes the reason ha_driven stopped and the last lines of its stderr
+    async fn crashed(&self, reason: &str, stderr: VecDeque<String>) {
+        if !self.publish_errors {
+            return;
+        }
+        let ts = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let payload = json!({ "ts": ts, "reason": reason, "stderr": stderr });
+        self.forward(Message::new(topics::prefixed(TOPIC_HA_DRIVEN_ERRORS), payload.to_string(), 0)).await;
+    }
+
+    // The JSON object a matched line carries, or the line itself when it has none
+    async fn classified(&self, class: &str, line: &str) {
+        debug!("{} line: {}", class, line);
+        let payload = match extract_json(line) {
+            Ok((_, json)) => json.to_string(),
+            Err(_) => json!({ "line": line }).to_string(),
+        };
+        let topic = topics::prefixed(&format!("{}/{}", TOPIC_HA_DRIVEN_PREFIX, class));
+        if let Some(msg) = self.publisher.admit(Message::new(topic, payload, self.qos.report)) {
+            self.forward(msg).await;
+        }
+    }
+}
+
+// The JSON object ha_driven logs after onReceiveMessage, e.g.
+// `onReceiveMessage >> {"method":"res/report",...} (master_bridge ...)`. Only
+// the object itself is taken, whatever comes before or after it on the line.
+// Lines without onReceiveMessage are searched from the start.
+fn extract_json(line: &str) -> Result<(Value, &str), String> {
+    let after = line.find("onReceiveMessage").map_or(0, |i| i + "onReceiveMessage".len());
+    let start = line[after..].find('{').map(|i| after + i).ok_or("no JSON object after onReceiveMessage")?;
+    let mut stream = serde_json::Deserializer::from_str(&line[start..]).into_iter::<Value>();
+    match stream.next() {
+        Some(Ok(report)) if report.is_object() => Ok((report, &line[start..start + stream.byte_offset()])),
+        Some(Ok(_)) | None => Err("no JSON object after onReceiveMessage".to_string()),
+        Some(Err(e)) => Err(e.to_string()),
+    }
+}
+
+fn stderr_line(excerpt: &mut VecDeque<String>, line: String) {
+    warn!("ha_driven: {}", line);
+    if excerpt.len() == CRASH_EXCERPT_LINES {
+        excerpt.pop_front();
+    }
+    excerpt.push_back(line);
+}
+
+// Kills the running ha_driven, starts it with its output piped and forwards
+// the lines it logs until it stops. What it writes to stderr is logged, and
+// the last of it goes with the crash report.
+async fn spawn_once(forwarder: &Forwarder, restarts: u64, shutdown: &mut broadcast::Receiver<()>) -> Exit {
+    let _ = Command::new("killall").arg("-9").arg("ha_driven").status().await;
+    sleep(Duration::from_millis(500)).await;
+
+    let mut command = Command::new("ha_driven");
+    // Also killed when the shutdown times out and the task is dropped
+    command.stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true);
+    info!("Preparing to read logs from ha_driven...");
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => return Exit::Stopped(format!("failed to start: {}", e)),
+    };
+    let (Some(stdout), Some(stderr)) = (child.stdout.take(), child.stderr.take()) else {
+        return Exit::Stopped("has no output to read".to_string());
+    };
+    let running = availability::ha_driven_status(availability::HA_DRIVEN_RUNNING, json!({ "restarts": restarts }));
+    forwarder.forward(running).await;
+
+    let mut reader = BufReader::new(stdout).lines();
+    let mut errors = Some(BufReader::new(stderr).lines());
+    let mut excerpt = VecDeque::with_capacity(CRASH_EXCERPT_LINES);
+
+    let reason = loop {
+        let line = tokio::select! {
+            line = reader.next_line() => match line {
+                Ok(Some(line)) => line,
+                Ok(None) => break None,
+                Err(e) => break Some(format!("output failed: {}", e)),
+            },
+            line = async { errors.as_mut()?.next_line().await.ok().flatten() }, if errors.is_some() => {
+                match line {
+                    Some(line) => stderr_line(&mut excerpt, line),
+                    None => errors = None,
+                }
+                continue;
+            }
+            _ = shutdown.recv() => {
+                let _ = child.kill().await;
+                info!("Stopped ha_driven");
+                return Exit::Shutdown;
+            }
+        };
+        if line.contains("another process exist") {
+            return Exit::Stopped("found another ha_driven running".to_string());
+        }
+        forwarder.line(&line).await;
+    };
+    let reason = match (reason, child.wait().await) {
+        (Some(reason), _) => reason,
+        (None, Ok(status)) => format!("exited ({})", status),
+        (None, Err(e)) => format!("closed its output: {}", e),
+    };
+    // Whatever it wrote last before going down
+    if let Some(mut errors) = errors {
+        while let Ok(Ok(Some(line))) = timeout(STDERR_DRAIN, errors.next_line()).await {
+            stderr_line(&mut excerpt, line);
+        }
+    }
+    forwarder.crashed(&reason, excerpt).await;
+    Exit::Stopped(reason)
+}
+
+// Follows the log file from its end like tail -F, starting over when it is
+// rotated or truncated
+async fn tail_once(path: &Path, forwarder: &Forwarder, shutdown: &mut broadcast::Receiver<()>) -> Exit {
+    let open = |from_start: bool| async move {
+        let mut file = File::open(path).await?;
+        if !from_start {
+            file.seek(SeekFrom::End(0)).await?;
+        }
+        let inode = file.metadata().await?.ino();
+        Ok::<_, io::Error>((BufReader::new(file), inode))
+    };
+    let (mut reader, mut inode) = match open(false).await {
+        Ok(opened) => opened,
+        Err(e) => return Exit::Stopped(format!("log '{}' can't be read: {}", path.display(), e)),
+    };
+    info!("Following the ha_driven log at '{}'", path.display());
+    let mut line = Vec::new();
+    loop {
+        match reader.read_until(b'\n', &mut line).await {
+            // Only whole lines, the rest of a partly written one comes with the next read
+            Ok(_) if line.ends_with(b"\n") => {
+                forwarder.line(String::from_utf8_lossy(&line).trim_end()).await;
+                line.clear();
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => return Exit::Stopped(format!("log '{}' can't be read: {}", path.display(), e)),
+        }
+        tokio::select! {
+            _ = sleep(TAIL_POLL) => {}
+            _ = shutdown.recv() => return Exit::Shutdown,
+        }
+        // A new file under the path or a shorter one means the log was rotated
+        let Ok(metadata) = fs::metadata(path).await else { continue };
+        let position = reader.stream_position().await.unwrap_or(0);
+        if metadata.ino() != inode || metadata.len() < position {
+            debug!("ha_driven log '{}' was rotated", path.display());
+            (reader, inode) = match open(true).await {
+                Ok(opened) => opened,
+                Err(e) => return Exit::Stopped(format!("log '{}' can't be read: {}", path.display(), e)),
+            };
+            line.clear();
+        }
+    }
+}
+
+// Forwards what ha_driven logs until shutdown. Each time the reader stops it is
+// started again after a backoff delay. A spawned ha_driven reports running and
+// restarting on aqara2mqtt/bridge/ha_driven_status.
+pub async fn ha_driven_reader(
+    options: HaDrivenOptions,
+    publisher: Publisher,
+    tx: mpsc::Sender<Message>,
+    qos: QosConfig,
+    state_cache: StateCache,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let HaDrivenOptions { source, rules, publish_errors } = options;
+    let forwarder = Forwarder { publisher, tx, qos, state_cache, rules, publish_errors };
+    let mut backoff = Backoff::new(RESTART_DELAY, RESTART_DELAY_MAX);
+    let mut restarts: u64 = 0;
+    loop {
+        let started = Instant::now();
+        let exit = match &source {
+            Source::Spawn => spawn_once(&forwarder, restarts, &mut shutdown).await,
+            Source::Tail(path) => tail_once(path, &forwarder, &mut shutdown).await,
+        };
+        let reason = match exit {
+            Exit::Shutdown => return,
+            Exit::Stopped(reason) => reason,
+        };
+        if started.elapsed() >= STABLE_RUN {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        match &source {
+            Source::Spawn => {
+                restarts += 1;
+                warn!("ha_driven {}, restarting in {:?}", reason, delay);
+                let status = availability::ha_driven_status(
+                    availability::HA_DRIVEN_RESTARTING,
+                    json!({ "reason": reason, "restarts": restarts, "delay_ms": delay.as_millis() as u64 }),
+                );
+                forwarder.forward(status).await;
+            }
+            // Nothing was restarted, only the log is opened again
+            Source::Tail(_) => debug!("ha_driven {}, reopening in {:?}", reason, delay),
+        }
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn object_after_on_receive_message() {
+        let line = r#"[I] master_bridge onReceiveMessage >> {"method":"res/report","params":[{"did":"lumi.1"}]} (master_bridge 12)"#;
+        let (report, raw) = extract_json(line).unwrap();
+        assert_eq!(report, json!({"method": "res/report", "params": [{"did": "lumi.1"}]}));
+        assert_eq!(raw, r#"{"method":"res/report","params":[{"did":"lumi.1"}]}"#);
+    }
+
+    #[test]
+    fn braces_before_the_marker_are_skipped() {
+        let line = r#"{ctx} onReceiveMessage >> {"id":1}"#;
+        assert_eq!(extract_json(line).unwrap().0, json!({"id": 1}));
+    }
+
+    #[test]
+    fn lines_without_the_marker_are_searched_from_the_start() {
+        let (report, raw) = extract_json(r#"report {"a":{"b":"}"}} tail"#).unwrap();
+        assert_eq!(report, json!({"a": {"b": "}"}}));
+        assert_eq!(raw, r#"{"a":{"b":"}"}}"#);
+    }
+
+    #[test]
+    fn no_object() {
+        assert!(extract_json("onReceiveMessage >> [1, 2]").is_err());
+        assert!(extract_json("ha_driven started").is_err());
+        assert!(extract_json(r#"onReceiveMessage >> {"method":"#).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+use tokio::time::{Duration, Instant};
+
+use crate::context;
+use crate::publisher::Publisher;
+
+// The agent task ticks every second, a few missed ticks mean it is stuck
+const AGENT_STALL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub struct Activity {
+    // When the agent task last went through its loop while connected, None while disconnected
+    agent_alive: Mutex<Option<Instant>>,
+    // When the last frame came from the agent
+    last_message: Mutex<Option<Instant>>,
+}
+
+pub fn agent_alive() {
+    *context::current().health.agent_alive.lock().unwrap() = Some(Instant::now());
+}
+
+pub fn agent_disconnected() {
+    *context::current().health.agent_alive.lock().unwrap() = None;
+}
+
+pub fn message_received() {
+    *context::current().health.last_message.lock().unwrap() = Some(Instant::now());
+}
+
+pub struct Health {
+    pub mqtt: bool,
+    pub agent: bool,
+    last_message: Option<Duration>,
+    // Longer without a frame from the agent is unhealthy, None doesn't check
+    max_idle: Option<Duration>,
+}
+
+pub fn check(publisher: &Publisher, max_idle: Option<Duration>) -> Health {
+    let context = context::current();
+    Health {
+        mqtt: publisher.is_connected(),
+        agent: context.health.agent_alive.lock().unwrap().is_some_and(|alive| alive.elapsed() < AGENT_STALL),
+        last_message: context.health.last_message.lock().unwrap().map(|last| last.elapsed()),
+        max_idle,
+    }
+}
+
+impl Health {
+    // Both links up, the flow of messages is only checked when asked for
+    pub fn links_up(&self) -> bool {
+        self.mqtt && self.agent
+    }
+
+    fn flowing(&self) -> bool {
+        match self.max_idle {
+            Some(max_idle) => self.last_message.is_some_and(|age| age <= max_idle),
+            None => true,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.links_up() && self.flowing()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "status": if self.is_ok() { "ok" } else { "error" },
+            "mqtt": self.mqtt,
+            "agent": self.agent,
+            "last_message_age": self.last_message.map(|age| age.as_secs()),
+        })
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use log::error;
+use serde::{Serialize, Serializer};
+
+use crate::mqtt_client::{Client, Message, MqttClient};
+use crate::topics;
+
+pub const TOPIC_BRIDGE_INFO: &str = "aqara2mqtt/bridge/info";
+
+// Retained birth message describing this bridge instance
+#[derive(Clone, Serialize)]
+pub struct BridgeInfo {
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    // Shared between clones, the agent task changes it when it falls back to another id
+    #[serde(serialize_with = "serialize_bind_id")]
+    pub bind_id: Arc<AtomicU32>,
+    pub agent_keys: Vec<String>,
+    pub start_time: u64,
+}
+
+impl BridgeInfo {
+    pub fn new(bind_id: u32, agent_keys: Vec<String>, start_time: u64) -> Self {
+        BridgeInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: env!("GIT_HASH"),
+            bind_id: Arc::new(AtomicU32::new(bind_id)),
+            agent_keys,
+            start_time,
+        }
+    }
+}
+
+fn serialize_bind_id<S: Serializer>(bind_id: &Arc<AtomicU32>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u32(bind_id.load(Ordering::Relaxed))
+}
+
+impl BridgeInfo {
+    pub fn current_bind_id(&self) -> u32 {
+        self.bind_id.load(Ordering::Relaxed)
+    }
+
+    pub fn set_bind_id(&self, bind_id: u32) {
+        self.bind_id.store(bind_id, Ordering::Relaxed);
+    }
+}
+
+pub fn message(info: &BridgeInfo) -> Option<Message> {
+    match serde_json::to_string(info) {
+        Ok(payload) => Some(Message::new_retained(topics::prefixed(TOPIC_BRIDGE_INFO), payload, 1)),
+        Err(e) => {
+            error!("Error serializing bridge info: {:?}", e);
+            None
+        }
+    }
+}
+
+pub async fn publish(client: &Client, info: &BridgeInfo) {
+    let Some(msg) = message(info) else { return };
+    let msg = topics::tag(msg);
+    if let Err(e) = client.publish(msg).await {
+        error!("Error publishing bridge info: {:?}", e);
+    }
+}
@@ -0,0 +1,59 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::mqtt_client::Message;
+use crate::topics;
+
+pub const TOPIC_BRIDGE_DEVICES: &str = "aqara2mqtt/bridge/devices";
+
+// Agent method listing the sub devices known to the hub
+const QUERY_METHOD: &str = "get_device_list";
+
+#[derive(Clone, Serialize)]
+pub struct Device {
+    pub did: String,
+    pub model: String,
+    pub name: String,
+}
+
+pub fn query(id: u64) -> Vec<u8> {
+    json!({ "id": id, "method": QUERY_METHOD, "params": {} }).to_string().into_bytes()
+}
+
+fn string_field(item: &Value, keys: &[&str]) -> String {
+    keys.iter()
+        .find_map(|key| item.get(*key).and_then(Value::as_str))
+        .unwrap_or_default()
+        .to_string()
+}
+
+// Firmwares answer with the list as `result` itself or nested one level
+// down (`result.devices`, `result.list`), so the first array found is used
+fn find_list(result: &Value) -> Option<&Vec<Value>> {
+    match result {
+        Value::Array(items) => Some(items),
+        Value::Object(map) => map.values().find_map(|value| value.as_array()),
+        _ => None,
+    }
+}
+
+pub fn parse(response: &Value) -> Option<Vec<Device>> {
+    let items = find_list(response.get("result")?)?;
+    let devices = items
+        .iter()
+        .filter_map(|item| {
+            let did = item.get("did").and_then(Value::as_str)?;
+            Some(Device {
+                did: did.to_string(),
+                model: string_field(item, &["model"]),
+                name: string_field(item, &["name", "nickname"]),
+            })
+        })
+        .collect();
+    Some(devices)
+}
+
+pub fn message(devices: &[Device]) -> Message {
+    let payload = serde_json::to_string(devices).unwrap_or_else(|_| "[]".to_string());
+    Message::new_retained(topics::prefixed(TOPIC_BRIDGE_DEVICES), payload, 1)
+}
@@ -0,0 +1,62 @@
+// The bridge as a library, main.rs only parses the options and handles signals
+pub mod agent;
+pub mod agent_socket;
+pub mod availability;
+pub mod backoff;
+pub mod base64;
+pub mod battery;
+pub mod bridge;
+pub mod check;
+pub mod command;
+pub mod command_channel;
+pub mod compat;
+pub mod config;
+pub mod context;
+pub mod correlation;
+pub mod daemon;
+pub mod deadletter;
+pub mod dedup;
+pub mod device;
+pub mod discovery;
+pub mod enrich;
+pub mod errors;
+pub mod filter;
+pub mod gateway;
+pub mod hadriven;
+pub mod health;
+pub mod info;
+pub mod inventory;
+pub mod logger;
+pub mod matter;
+pub mod metrics;
+pub mod monitor;
+pub mod mqtt;
+pub mod mqtt_client;
+pub mod mux;
+pub mod network;
+pub mod ota;
+pub mod pairing;
+pub mod pending;
+pub mod publisher;
+pub mod queue;
+pub mod rate_limit;
+pub mod repl;
+pub mod routing;
+pub mod scene;
+pub mod selftest;
+pub mod send;
+pub mod service;
+pub mod spec;
+pub mod state;
+pub mod stats;
+pub mod supervisor;
+pub mod systemd;
+pub mod telemetry;
+pub mod thread;
+pub mod topics;
+pub mod trace;
+pub mod uds_proxy;
+pub mod zigbee2mqtt;
+
+pub use bridge::{Bridge, BridgeBuilder};
+pub use context::BridgeConfig;
@@ -0,0 +1,321 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::future::Future;
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+use serde_json::json;
+
+use crate::errors;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogColor {
+    // Colored when stderr is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    // One JSON object per line for log shippers
+    Json,
+}
+
+// Size based rotation of --log-file, which stdout and stderr point to
+pub struct Rotation {
+    pub path: String,
+    pub max_size: u64,
+    // Rotated files kept as path.1 (newest) to path.N
+    pub max_files: u32,
+}
+
+struct Logger {
+    format: LogFormat,
+    color: bool,
+    timestamps: bool,
+    rotation: Option<Rotation>,
+    // Bytes in the current log file, the lock also keeps records and rotation apart
+    written: Mutex<u64>,
+}
+
+static LOGGER: OnceCell<Logger> = OnceCell::new();
+
+// A default level and levels for modules or tasks, e.g. warn,agent=trace,mqtt=warn
+#[derive(Clone)]
+pub struct Levels {
+    default: LevelFilter,
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Levels { default: LevelFilter::Info, directives: Vec::new() }
+    }
+}
+
+static LEVELS: RwLock<Option<Levels>> = RwLock::new(None);
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level
+        .trim()
+        .parse()
+        .map_err(|_| format!("unknown log level '{}', use off, error, warn, info, debug or trace", level.trim()))
+}
+
+// A bare level sets the default, name=level applies to modules and tasks whose name starts with `name`
+pub fn parse_levels(spec: &str) -> Result<Levels, String> {
+    let mut levels = Levels::default();
+    for item in spec.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        match item.split_once('=') {
+            Some((name, level)) => levels.directives.push((name.trim().to_string(), parse_level(level)?)),
+            None => levels.default = parse_level(item)?,
+        }
+    }
+    Ok(levels)
+}
+
+impl Levels {
+    fn longest_match(&self, target: &str) -> Option<LevelFilter> {
+        self.directives
+            .iter()
+            .filter(|(name, _)| target.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, level)| *level)
+    }
+
+    // The module decides before the task, e.g. mqtt_client records logged from the agent task.
+    // The longest matching name wins, so agent_socket=info can narrow agent=trace.
+    fn level_for(&self, module: &str, task: Option<&str>) -> LevelFilter {
+        self.longest_match(module)
+            .or_else(|| task.and_then(|task| self.longest_match(task)))
+            .unwrap_or(self.default)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.directives.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+
+    pub fn default_level(&self) -> LevelFilter {
+        self.default
+    }
+
+    pub fn with_default(mut self, level: LevelFilter) -> Self {
+        self.default = level;
+        self
+    }
+}
+
+impl fmt::Display for Levels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.default.as_str().to_lowercase())?;
+        for (name, level) in &self.directives {
+            write!(f, ",{}={}", name, level.as_str().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn levels() -> Levels {
+    LEVELS.read().unwrap().clone().unwrap_or_default()
+}
+
+pub fn set_levels(levels: Levels) {
+    // Records above the most verbose directive are dropped by the log macros already
+    log::set_max_level(levels.max());
+    *LEVELS.write().unwrap() = Some(levels);
+}
+
+tokio::task_local! {
+    // Name of the long running task a record comes from, tasks share modules like routing
+    static TASK: &'static str;
+}
+
+// Runs `future` with its log records tagged with the task name
+pub fn tagged<F: Future>(name: &'static str, future: F) -> impl Future<Output = F::Output> {
+    TASK.scope(name, future)
+}
+
+// Local date and time with milliseconds, or RFC 3339 in UTC
+pub fn timestamp(utc: bool) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        if utc {
+            libc::gmtime_r(&secs, &mut tm);
+        } else {
+            libc::localtime_r(&secs, &mut tm);
+        }
+    };
+    format!(
+        "{}-{:02}-{:02}{}{:02}:{:02}:{:02}.{:03}{}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        if utc { 'T' } else { ' ' },
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        now.subsec_millis(),
+        if utc { "Z" } else { "" }
+    )
+}
+
+fn level_color(level: Level) -> &'static str {
+    match level {
+        Level::Error => "\x1b[31m",
+        Level::Warn => "\x1b[33m",
+        Level::Info => "\x1b[32m",
+        Level::Debug => "\x1b[34m",
+        Level::Trace => "\x1b[35m",
+    }
+}
+
+// The module without the crate name, main.rs is just the crate name
+fn module<'a>(record: &Record<'a>) -> &'a str {
+    let target = record.target();
+    match target.split_once("::") {
+        Some((env!("CARGO_CRATE_NAME"), module)) => module,
+        _ if target == env!("CARGO_CRATE_NAME") => "main",
+        _ => target,
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, _meta: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let task = TASK.try_with(|task| *task).ok();
+        if record.level() > levels_for(record, task) {
+            return;
+        }
+        if record.level() <= Level::Warn {
+            errors::record(record.level(), module(record), task, record.args().to_string());
+        }
+        let line = match self.format {
+            LogFormat::Text => self.text(record),
+            LogFormat::Json => json_line(record),
+        };
+        // One write per record, so lines of concurrent tasks don't mix
+        let mut written = self.written.lock().unwrap();
+        let _ = io::stderr().lock().write_all(line.as_bytes());
+        *written += line.len() as u64;
+        if let Some(rotation) = &self.rotation
+            && *written >= rotation.max_size
+        {
+            match rotation.rotate() {
+                Ok(()) => *written = 0,
+                // Not tried again until the file has grown by another max_size
+                Err(e) => {
+                    *written = 0;
+                    let _ = writeln!(io::stderr(), "ERROR log rotation of '{}' failed: {}", rotation.path, e);
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn levels_for(record: &Record, task: Option<&str>) -> LevelFilter {
+    match LEVELS.read().unwrap().as_ref() {
+        Some(levels) => levels.level_for(module(record), task),
+        None => LevelFilter::Info,
+    }
+}
+
+// {"ts":..,"level":..,"target":..,"task":..,"message":..}, task only inside a tagged task
+fn json_line(record: &Record) -> String {
+    let mut object = json!({
+        "ts": timestamp(true),
+        "level": record.level().as_str(),
+        "target": module(record),
+    });
+    if let Ok(task) = TASK.try_with(|task| *task) {
+        object["task"] = json!(task);
+    }
+    object["message"] = json!(record.args().to_string());
+    let mut line = object.to_string();
+    line.push('\n');
+    line
+}
+
+impl Logger {
+    fn text(&self, record: &Record) -> String {
+        let mut line = String::new();
+        if self.timestamps {
+            line.push_str(&timestamp(false));
+            line.push(' ');
+        }
+        let level = format!("{:<5}", record.level());
+        if self.color {
+            line.push_str(&format!("{}{}\x1b[0m ", level_color(record.level()), level));
+        } else {
+            line.push_str(&level);
+            line.push(' ');
+        }
+        if let Ok(task) = TASK.try_with(|task| *task) {
+            line.push_str(&format!("[{}] ", task));
+        }
+        if self.color {
+            line.push_str(&format!("\x1b[2m{}\x1b[0m: {}\n", module(record), record.args()));
+        } else {
+            line.push_str(&format!("{}: {}\n", module(record), record.args()));
+        }
+        line
+    }
+}
+
+impl Rotation {
+    fn rotated(&self, n: u32) -> String {
+        format!("{}.{}", self.path, n)
+    }
+
+    // Shifts path.N-1 to path.N and so on, moves the log to path.1 and points stdout
+    // and stderr at a fresh file, so panics and child output rotate along
+    fn rotate(&self) -> io::Result<()> {
+        if self.max_files > 0 {
+            for n in (1..self.max_files).rev() {
+                match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn init(levels: Levels, format: LogFormat, color: LogColor, rotation: Option<Rotation>) {
+    let color = match color {
+        LogColor::Auto => io::stderr().is_terminal(),
+        LogColor::Always => true,
+        LogColor::Never => false,
+    };
+    // The journal timestamps every line itself
+    let timestamps = std::env::var_os("JOURNAL_STREAM").is_none();
+    // The file may have been appended to by an earlier run
+    let written = rotation
+        .as_ref()
+        .and_then(|rotation| fs::metadata(&rotation.path).ok())
+        .map_or(0, |metadata| metadata.len());
+    let logger = LOGGER.get_or_init(|| Logger { format, color, timestamps, rotation, written: Mutex::new(written) });
+    set_levels(levels);
+    log::set_logger(logger).unwrap();
+}
@@ -1,363 +1,795 @@
-use log::{info, debug, warn, error, LevelFilter, Metadata, Log, Record};
-use clap::Parser;
-use std::sync::Mutex;
-use std::process::Stdio;
-use once_cell::sync::Lazy;
+use log::{info, warn, error, LevelFilter};
+use clap::{ArgMatches, CommandFactory, Parser, Subcommand};
+use std::ffi::OsString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
 
-struct Logger;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::{timeout, Duration},
+};
+
+use aqara_agent2mqtt::agent::{AgentOptions, AGENT_REGISTER_KEYS, DEFAULT_AGENT_SOCKET};
+use aqara_agent2mqtt::availability::DeviceTimeouts;
+use aqara_agent2mqtt::bridge::{QosConfig, ReloadOptions};
+use aqara_agent2mqtt::command_channel::{self, CommandOverflow};
+use aqara_agent2mqtt::compat::Compat;
+use aqara_agent2mqtt::context::{self, BridgeConfig, BridgeContext};
+use aqara_agent2mqtt::filter::{self, CommandFilter, FilterRule};
+use aqara_agent2mqtt::hadriven::{self, HaDrivenMode, HaDrivenOptions, LineRule};
+use aqara_agent2mqtt::logger::{self, Levels, LogColor, LogFormat, Rotation};
+use aqara_agent2mqtt::mqtt_client::{self, MqttConfig};
+use aqara_agent2mqtt::queue::OverflowPolicy;
+use aqara_agent2mqtt::rate_limit::{self, RateLimitPolicy, RateLimiter};
+use aqara_agent2mqtt::spec::MiotSpec;
+use aqara_agent2mqtt::state::StateCache;
+use aqara_agent2mqtt::zigbee2mqtt::FriendlyNames;
+use aqara_agent2mqtt::{check, config, daemon, metrics, monitor, repl, selftest, send, service, trace, Bridge};
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+
+    /// Config file with the same options as the command line: JSON if it ends in .json, otherwise flat TOML
+    /// (key = value lines of strings, numbers, booleans and one-line arrays, no tables), see README
+    #[arg(long)]
+    config: Option<String>,
+
     #[arg(short, long)]
     mqtt_ip: Option<String>,
 
-    #[arg(short, long)]
+    #[arg(long)]
+    mqtt_port: Option<u16>,
+
+    /// Full broker URI, e.g. tcp://host:8883, ws://host:9001/mqtt, wss://host/mqtt or unix:///var/run/mosquitto.sock,
+    /// overrides --mqtt-ip/--mqtt-port. With paho, unix:// goes through a proxy on a random 127.0.0.1 port
+    #[arg(long)]
+    mqtt_uri: Option<String>,
+
+    /// Agent socket path, or tcp://host:port for an agent forwarded over TCP
+    #[arg(short, long, visible_alias = "agent")]
     agent_socket_path: Option<String>,
 
     #[arg(short, long)]
     bind_id: Option<u32>,
 
-    #[arg(short, long)]
-    log_level: Option<String>,
+    /// Log level, with optional levels per module or task, e.g. info,agent=trace,mqtt=warn
+    #[arg(short, long, value_parser = logger::parse_levels)]
+    log_level: Option<Levels>,
+
+    /// Text for reading, json for one object per line with ts, level, target, task and message
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Color the log levels, by default only on a terminal
+    #[arg(long, value_enum, default_value_t = LogColor::Auto)]
+    log_color: LogColor,
+
+    /// Append the log to this file instead of stderr, with --daemon it else goes to /dev/null
+    #[arg(long)]
+    log_file: Option<String>,
+
+    /// Rotate --log-file once it reaches this many kB
+    #[arg(long, default_value_t = 1024)]
+    log_max_size: u64,
+
+    /// Rotated log files kept next to --log-file, 0 only truncates it
+    #[arg(long, default_value_t = 2)]
+    log_max_files: u32,
+
+    /// Detach from the terminal and run in the background
+    #[arg(long)]
+    daemon: bool,
+
+    /// Write the process id to this file, refuses to start while that process runs
+    #[arg(long)]
+    pidfile: Option<String>,
+
+    /// Run on this many worker threads, so a busy ha_driven reader can't hold up the publisher.
+    /// By default everything runs on one thread.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    workers: Option<u16>,
+
+    /// Keep the broker session across restarts so queued commands are delivered
+    #[arg(long)]
+    persistent_session: bool,
+
+    /// Session expiry in seconds for persistent MQTT v5 sessions
+    #[arg(long, default_value_t = 3600)]
+    session_expiry: u32,
+
+    /// MQTT keep-alive interval in seconds
+    #[arg(long, default_value_t = 20)]
+    keep_alive: u64,
+
+    /// MQTT connect timeout in seconds
+    #[arg(long, default_value_t = 30)]
+    connect_timeout: u64,
+
+    /// Maximum number of unacknowledged QoS 1/2 publishes
+    #[arg(long, default_value_t = 10)]
+    max_inflight: i32,
+
+    /// Upper bound in seconds for the MQTT reconnect backoff
+    #[arg(long, default_value_t = 60)]
+    mqtt_reconnect_max: u64,
+
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(i32).range(0..=2))]
+    qos_report: i32,
+
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(i32).range(0..=2))]
+    qos_ack: i32,
+
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(i32).range(0..=2))]
+    qos_command_sub: i32,
+
+    /// Reports kept while the broker is unreachable
+    #[arg(long, default_value_t = 1000)]
+    queue_size: usize,
+
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::DropOldest)]
+    queue_overflow: OverflowPolicy,
+
+    /// Persist queued reports to this file so they survive a restart, it never holds more than --queue-size of them
+    #[arg(long)]
+    queue_file: Option<String>,
+
+    /// Leave ha_driven alone, where openmiio_agent or another tool manages it. Only the agent socket is bridged.
+    #[arg(long)]
+    no_ha_driven: bool,
+
+    /// spawn restarts ha_driven to read its output, tail follows --ha-driven-log and leaves the running ha_driven alone
+    #[arg(long, value_enum, default_value_t = HaDrivenMode::Spawn)]
+    ha_driven_mode: HaDrivenMode,
+
+    /// The log ha_driven writes to, followed with --ha-driven-mode tail
+    #[arg(long, default_value = "/var/log/messages")]
+    ha_driven_log: PathBuf,
+
+    /// Publish ha_driven lines matching the glob to aqara2mqtt/ha_driven/<class>, e.g. event=*"method":"event.* (repeatable, replaces the default rules)
+    #[arg(long, value_parser = hadriven::parse_rule)]
+    ha_driven_rule: Vec<LineRule>,
+
+    /// Publish why a spawned ha_driven stopped and the last lines of its stderr to aqara2mqtt/bridge/ha_driven/errors
+    #[arg(long)]
+    ha_driven_errors: bool,
+
+    /// Milliseconds within which a report seen on both the agent socket and from ha_driven is published once, 0 disables
+    #[arg(long, default_value_t = 2000)]
+    dedup_window: u64,
+
+    /// Smaller queues and error history, no device state or inventory kept, for hubs with 128 MB
+    #[arg(long)]
+    low_memory: bool,
+
+    /// Commands waiting for the agent task
+    #[arg(long, default_value_t = command_channel::DEFAULT_CAPACITY)]
+    command_queue_size: usize,
+
+    /// What happens to a command once --command-queue-size commands are waiting, dropped commands get an error ack
+    #[arg(long, value_enum, default_value_t = CommandOverflow::Block)]
+    command_queue_overflow: CommandOverflow,
+
+    /// Global limit for published reports, e.g. 50/s or 600/m
+    #[arg(long, value_parser = rate_limit::parse_rate)]
+    max_publish_rate: Option<f64>,
+
+    /// Limit for published reports per topic, e.g. 10/s
+    #[arg(long, value_parser = rate_limit::parse_rate)]
+    max_topic_rate: Option<f64>,
+
+    #[arg(long, value_enum, default_value_t = RateLimitPolicy::Drop)]
+    rate_limit_policy: RateLimitPolicy,
+
+    /// Only forward commands matching one of these rules, e.g. method=auto.* (repeatable)
+    #[arg(long, value_parser = filter::parse_rule)]
+    command_allow: Vec<FilterRule>,
+
+    /// Reject commands matching any of these rules, e.g. did=lumi.0 (repeatable)
+    #[arg(long, value_parser = filter::parse_rule)]
+    command_deny: Vec<FilterRule>,
+
+    /// Prefix for all bridge topics, e.g. gw1/ to run several bridges on one broker
+    #[arg(long, default_value = "")]
+    topic_prefix: String,
+
+    /// Gateway id, namespaces the aqara2mqtt/ topics and is added to payloads as _gw
+    #[arg(long)]
+    gateway_id: Option<String>,
+
+    /// Mirror the topics and payloads of another bridge, e.g. openmiio
+    #[arg(long, value_enum)]
+    compat: Option<Compat>,
+
+    /// Also publish device state on zigbee2mqtt/<friendly_name>
+    #[arg(long)]
+    zigbee2mqtt_topics: bool,
+
+    /// JSON file with friendly names for devices and resources, renames are saved to it
+    #[arg(long)]
+    friendly_names: Option<String>,
+
+    /// JSON file with MIoT property names per model, extends the bundled ones
+    #[arg(long)]
+    spec_file: Option<String>,
+
+    /// Seconds to wait for the agent to answer a command before a timeout ack
+    #[arg(long, default_value_t = 5)]
+    command_timeout: u64,
+
+    /// Times an unanswered command is sent again before the timeout ack
+    #[arg(long, default_value_t = 0)]
+    command_retries: u32,
+
+    /// Methods that are safe to send more than once
+    #[arg(long, value_delimiter = ',', default_value = "get_properties,set_properties")]
+    retry_methods: Vec<String>,
+
+    /// Agent keys to register, defaults to all known keys
+    #[arg(long = "register", value_delimiter = ',', default_values = AGENT_REGISTER_KEYS)]
+    register_keys: Vec<String>,
+
+    /// Reconnect to the agent when nothing arrives for this many seconds, 0 disables
+    #[arg(long, default_value_t = 90)]
+    agent_watchdog: u64,
+
+    /// Seqpacket socket where other local processes can share the agent connection
+    #[arg(long)]
+    mux_socket: Option<String>,
+
+    /// Add _ts (epoch millis) and _seq (counter) to every published report
+    #[arg(long)]
+    enrich_reports: bool,
+
+    /// Add _latency_ms, the time from the command to its ack, to every command ack
+    #[arg(long)]
+    ack_latency: bool,
+
+    /// Publish aqara2mqtt/<did>/availability, offline once a device stays silent too long
+    #[arg(long)]
+    device_availability: bool,
+
+    /// Minutes without a report before a battery powered device is offline
+    #[arg(long, default_value_t = 1500)]
+    availability_battery_timeout: u64,
+
+    /// Minutes without a report before a mains powered device is offline
+    #[arg(long, default_value_t = 10)]
+    availability_mains_timeout: u64,
+
+    /// Publish aqara2mqtt/<did>/battery and list devices below this percentage on aqara2mqtt/bridge/low_battery
+    #[arg(long, num_args = 0..=1, default_missing_value = "20")]
+    battery_topics: Option<u8>,
+
+    /// Publish LQI/RSSI on aqara2mqtt/<did>/link_quality and a mesh graph on aqara2mqtt/bridge/network_map
+    #[arg(long)]
+    network_quality: bool,
+
+    /// Seconds between hub telemetry (uptime, memory, load, flash) reports, 0 disables
+    #[arg(long, default_value_t = 60)]
+    telemetry_interval: u64,
+
+    /// Seconds between bridge statistics (message counts, errors, drops) on aqara2mqtt/bridge/stats, 0 disables
+    #[arg(long, default_value_t = 60)]
+    stats_interval: u64,
+
+    /// Serve the statistics in the Prometheus text format on http://<address>/metrics and the health on /healthz, e.g. 0.0.0.0:9898
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+
+    /// /healthz fails after this many seconds without a frame from the agent, 0 only checks the connections
+    #[arg(long, default_value_t = 0)]
+    health_max_idle: u64,
+
+    /// Export a span per command (channel, agent, publish) to an OTLP/HTTP collector, e.g. http://collector:4318
+    #[arg(long, value_parser = trace::parse_endpoint)]
+    otlp_endpoint: Option<trace::Endpoint>,
+
+    /// Warnings and errors kept on aqara2mqtt/bridge/errors, 0 disables
+    #[arg(long, default_value_t = 50)]
+    error_history: usize,
+
+    /// Minutes between device inventory queries, 0 disables
+    #[arg(long, default_value_t = 10)]
+    inventory_interval: u64,
+
+    /// Publish Home Assistant discovery configs for the inventory, under homeassistant/ or the given prefix
+    #[arg(long, num_args = 0..=1, default_missing_value = "homeassistant")]
+    ha_discovery: Option<String>,
+
+    /// Mirror every agent datagram verbatim to aqara2mqtt/raw/agent
+    #[arg(long)]
+    mirror_raw: bool,
+
+    /// MQTT client id, defaults to agent2mqtt-<bind_id>
+    #[arg(long)]
+    client_id: Option<String>,
+
+    #[arg(long, env = "MQTT_USER")]
+    mqtt_user: Option<String>,
+
+    #[arg(long, env = "MQTT_PASSWORD", hide_env_values = true)]
+    mqtt_password: Option<String>,
+
+    /// Connect with mqtts:// on port 8883
+    #[arg(long)]
+    mqtt_tls: bool,
+
+    #[arg(long)]
+    mqtt_ca_cert: Option<String>,
+
+    #[arg(long)]
+    mqtt_client_cert: Option<String>,
+
+    #[arg(long)]
+    mqtt_client_key: Option<String>,
+
+    /// Skip verification of the broker certificate
+    #[arg(long)]
+    insecure: bool,
+
+    /// Additional broker that receives a copy of every publish
+    #[arg(long)]
+    mqtt_uri_secondary: Option<String>,
+
+    #[arg(long, env = "MQTT_USER_SECONDARY")]
+    mqtt_user_secondary: Option<String>,
+
+    #[arg(long, env = "MQTT_PASSWORD_SECONDARY", hide_env_values = true)]
+    mqtt_password_secondary: Option<String>,
 }
 
-#[allow(dead_code)]
-struct SendingTopicCommand {
-    id: u64,
-    to: u64,
-    from: u64
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Validate the options and files without starting the bridge, exits non-zero on errors
+    CheckConfig {
+        /// Also connect to the MQTT brokers and the agent socket
+        #[arg(long)]
+        connect: bool,
+    },
+    /// Print the agent traffic with timestamps until Ctrl-C, registers the --register keys
+    Monitor {
+        /// Only show frames with a matching top-level field, e.g. key=auto.report (repeatable, all must match)
+        #[arg(long, value_parser = monitor::parse_filter)]
+        filter: Vec<monitor::FrameFilter>,
+    },
+    /// Interactive prompt sending commands to the agent, responses are waited for up to --command-timeout
+    Repl,
+    /// Print an init script or service unit running the bridge with the options given before `install`
+    Install {
+        #[arg(long, value_enum)]
+        init: service::InitSystem,
+        /// Write it to /etc/init.d/aqara2mqtt or /etc/systemd/system/aqara2mqtt.service instead
+        #[arg(long)]
+        write: bool,
+    },
+    /// Ask the bridge running with --metrics-listen whether it is healthy, exits non-zero if not
+    Health,
+    /// Send one JSON command to the agent, print the response and exit, waits up to --command-timeout
+    Send {
+        /// e.g. '{"method":"get_properties","params":[{"did":"lumi.0","siid":2,"piid":1}]}'
+        command: String,
+    },
+    /// Check the agent bind, a broker loopback, ha_driven and the clock, prints PASS or FAIL for each and exits non-zero on a failure
+    Selftest,
 }
 
-impl Log for Logger {
-    fn enabled(&self, _meta: &Metadata) -> bool {
-        true
+async fn wait_for_shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to install SIGINT handler");
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+        _ = sigint.recv() => info!("Received SIGINT"),
     }
+}
 
-    fn log(&self, record: &Record) {
-        eprintln!("{}: {}", record.level(), record.args());
+fn mqtt_uri(cli: &Cli) -> String {
+    let (scheme, default_port) = if cli.mqtt_tls { ("mqtts", 8883) } else { ("mqtt", 1883) };
+    let port = cli.mqtt_port.unwrap_or(default_port);
+    match (&cli.mqtt_uri, &cli.mqtt_ip) {
+        (Some(uri), _) => uri.clone(),
+        (None, Some(ip)) => format!("{}://{}:{}", scheme, ip, port),
+        (None, None) => format!("{}://localhost:{}", scheme, port),
     }
+}
 
-    fn flush(&self) {}
+fn mqtt_config(cli: &Cli) -> MqttConfig {
+    MqttConfig {
+        user: cli.mqtt_user.clone(),
+        password: cli.mqtt_password.clone(),
+        ca_cert: cli.mqtt_ca_cert.clone(),
+        client_cert: cli.mqtt_client_cert.clone(),
+        client_key: cli.mqtt_client_key.clone(),
+        insecure: cli.insecure,
+        reconnect_max: Duration::from_secs(cli.mqtt_reconnect_max),
+        persistent_session: cli.persistent_session,
+        session_expiry: cli.session_expiry,
+        keep_alive: Duration::from_secs(cli.keep_alive),
+        connect_timeout: Duration::from_secs(cli.connect_timeout),
+        max_inflight: cli.max_inflight,
+    }
 }
 
-use paho_mqtt as mqtt;
-use tokio::{
-    sync::mpsc,
-    time::{sleep, Duration},
-    process::Command,
-    io::{AsyncBufReadExt, BufReader}
-};
+// Same as the primary broker unless it has its own credentials
+fn secondary_mqtt_config(cli: &Cli) -> MqttConfig {
+    let mut config = mqtt_config(cli);
+    if cli.mqtt_user_secondary.is_some() {
+        config.user = cli.mqtt_user_secondary.clone();
+        config.password = cli.mqtt_password_secondary.clone();
+    }
+    config
+}
 
-use tokio_seqpacket::UnixSeqpacket;
-use tokio_stream::StreamExt;
-use serde_json::Value;
-
-const TOPIC_COMMAND: &str = "miio/command";
-const TOPIC_COMMAND_ACK: &str = "miio/command_ack";
-const TOPIC_RESPONSE: &str = "openmiio/report";
-static SENDING_TOPIC_COMMAND: Lazy<Mutex<SendingTopicCommand>> = Lazy::new(|| {
-    Mutex::new(SendingTopicCommand {
-        id: 0,
-        to: 0,
-        from: 0
-    })
-});
+fn ha_driven_source(cli: &Cli) -> hadriven::Source {
+    match cli.ha_driven_mode {
+        HaDrivenMode::Spawn => hadriven::Source::Spawn,
+        HaDrivenMode::Tail => hadriven::Source::Tail(cli.ha_driven_log.clone()),
+    }
+}
 
+// A wildcard listen address is reached on the loopback interface
+fn local_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port()),
+        _ => addr,
+    }
+}
 
-async fn mqtt_reconnect(client: &mqtt::AsyncClient) {
-    loop {
-        if client.reconnect().await.is_ok() {
-            if mqtt_subscribe(client).await {
-                warn!("Successfully reconnected");
-                return;
+// The subcommands run instead of the bridge, returns the exit code
+async fn run_subcommand(cli: &Cli, command: &CliCommand) -> i32 {
+    let agent = cli.agent_socket_path.clone().unwrap_or_else(|| DEFAULT_AGENT_SOCKET.to_string());
+    let bind_id = cli.bind_id.unwrap_or(0);
+    let wait = Duration::from_secs(cli.command_timeout);
+    let result = match command {
+        CliCommand::CheckConfig { connect } => return if check_config(cli, *connect).await { 0 } else { 1 },
+        CliCommand::Monitor { filter } => monitor::run(&agent, bind_id, &cli.register_keys, filter).await.map(|()| 0),
+        CliCommand::Repl => repl::run(&agent, bind_id, wait).await.map(|()| 0),
+        CliCommand::Install { init, write } => install_service(*init, *write),
+        CliCommand::Health => match cli.metrics_listen {
+            Some(addr) => metrics::probe(local_addr(addr)).await.map(|healthy| if healthy { 0 } else { 1 }),
+            None => Err("health needs the --metrics-listen address of the bridge".to_string()),
+        },
+        CliCommand::Selftest => {
+            // The loopback topic is under the prefix, where the broker ACL lets the bridge publish
+            let config = BridgeConfig { topic_prefix: cli.topic_prefix.clone(), ..BridgeConfig::default() };
+            let context = Arc::new(BridgeContext::new(config, Duration::ZERO, 0));
+            let mut brokers = vec![(mqtt_uri(cli), mqtt_config(cli))];
+            if let Some(uri) = &cli.mqtt_uri_secondary {
+                brokers.push((uri.clone(), secondary_mqtt_config(cli)));
             }
+            let client_id = cli.client_id.clone().unwrap_or_else(|| format!("agent2mqtt-{}", bind_id));
+            let ha_driven = (!cli.no_ha_driven).then(|| ha_driven_source(cli));
+            let passed = context::scope(context, selftest::run(&agent, bind_id, &brokers, &client_id, wait, ha_driven.as_ref())).await;
+            return if passed { 0 } else { 1 };
+        }
+        CliCommand::Send { command } => send::send(&agent, bind_id, command, wait).await.map(|response| {
+            println!("{}", response);
+            // Error responses of the agent fail too, so scripts can check the exit code
+            if response.get("error").is_some() { 1 } else { 0 }
+        }),
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            1
         }
-        sleep(Duration::from_millis(500)).await;
     }
 }
 
-async fn mqtt_subscribe(client: &mqtt::AsyncClient) -> bool {
-    let subscribe_result = client.subscribe(TOPIC_COMMAND, 0).await.and_then(|rsp| {
-        rsp.subscribe_response()
-            .ok_or(mqtt::Error::General("Bad response"))
-    });
-    if let Err(err) = subscribe_result {
-        let _ = client.disconnect(None).await;
-        error!("Error subscribing to topics: {:?}", err);
-        return false;
+// Options given on the command line before the subcommand are baked into the script,
+// those from the environment aren't, put them in a --config file
+fn install_service(init: service::InitSystem, write: bool) -> Result<i32, String> {
+    let args: Vec<String> = std::env::args().skip(1).take_while(|arg| arg != "install").collect();
+    let binary = std::env::current_exe().map_err(|e| format!("path of the binary: {}", e))?;
+    let script = service::script(init, &binary.display().to_string(), &service::service_args(&args));
+    if !write {
+        print!("{}", script);
+        return Ok(0);
     }
-    true
+    let path = service::install(init, &script)?;
+    println!("Installed {}, next: {}", path, service::next_steps(init));
+    Ok(0)
 }
 
-async fn mqtt_manager(
-    mut mqtt_client: mqtt::AsyncClient,
-    command_tx: mpsc::Sender<String>,
-) {
-    let conn_opts = mqtt::ConnectOptionsBuilder::new()
-        .keep_alive_interval(Duration::from_secs(20))
-        .clean_session(true)
-        .finalize();
-
-    // Make the connection to the broker
-    loop {
-        info!(
-            "Connecting to the MQTT broker at '{}'...",
-            mqtt_client.server_uri()
-        );
-        match mqtt_client.connect(conn_opts.clone()).await {
-            Ok(response) => {
-                if let Some(response) = response.connect_response() {
-                    info!(
-                        "Connected to: '{}' with MQTT version {}",
-                        response.server_uri, response.mqtt_version
-                    );
-
-                    mqtt_subscribe(&mqtt_client).await;
-                    break;
-                }
-            }
-            Err(e) => {
-                error!("Error connecting to the MQTT broker: {:?}", e);
-                sleep(Duration::from_millis(500)).await;
-            }
+// check-config: what startup would only find out by panicking or reconnecting forever
+async fn check_config(cli: &Cli, connect: bool) -> bool {
+    let mut report = check::Report::default();
+    check::topic_prefix(&cli.topic_prefix, &mut report);
+    if let Some(gateway_id) = &cli.gateway_id {
+        report.check(check::gateway_id(gateway_id));
+    }
+    if let Some(prefix) = &cli.ha_discovery {
+        report.check(check::topic_part("ha-discovery", prefix));
+    }
+
+    let uri = mqtt_uri(cli);
+    let mut brokers = vec![(uri.clone(), mqtt_config(cli))];
+    report.check(check::broker_uri("mqtt-uri", &uri));
+    if let Some(uri) = &cli.mqtt_uri_secondary {
+        report.check(check::broker_uri("mqtt-uri-secondary", uri));
+        brokers.push((uri.clone(), secondary_mqtt_config(cli)));
+    }
+    let tls_files = [
+        ("mqtt-ca-cert", &cli.mqtt_ca_cert, "CERTIFICATE"),
+        ("mqtt-client-cert", &cli.mqtt_client_cert, "CERTIFICATE"),
+        ("mqtt-client-key", &cli.mqtt_client_key, "PRIVATE KEY"),
+    ];
+    for (option, path, kind) in tls_files {
+        let Some(path) = path else { continue };
+        report.check(check::pem_file(option, path, kind));
+        if !brokers.iter().any(|(uri, _)| mqtt_client::is_ssl_uri(uri)) {
+            report.warning(format!("--{} is ignored, no broker URI uses mqtts://, ssl:// or wss://", option));
         }
     }
+    if cli.insecure {
+        report.warning("--insecure skips verification of the broker certificate".to_string());
+    }
 
-    // Outer loop to recreate stream if it closes
-    loop {
-        let mut stream = mqtt_client.get_stream(25);
-
-        while let Some(msg) = stream.next().await {
-            match msg {
-                Some(msg) => {
-                    if msg.topic() == TOPIC_COMMAND {
-                        debug!("get command '{}'", msg);
-                        let payload = msg.payload_str().to_string();
-                        if let Err(e) = command_tx.send(payload.clone()).await {
-                            error!("Error sending command to agent task: {:?}", e);
-                        }
-                        match serde_json::from_str::<Value>(&payload) {
-                            Ok(json_msg) => {
-                                let mut sending_command = SENDING_TOPIC_COMMAND.lock().unwrap();
-                                if let Some(id) = json_msg.get("id").and_then(|v| v.as_u64()) {
-                                    sending_command.id = id;
-                                }
-                                if let Some(to) = json_msg.get("_to").and_then(|v| v.as_u64()) {
-                                    sending_command.to = to;
-                                }
-                                if let Some(from) = json_msg.get("_from").and_then(|v| v.as_u64()) {
-                                    sending_command.from = from;
-                                }
-                                debug!("id: {}", sending_command.id);
-                                debug!("to: {}", sending_command.to);
-                                debug!("from: {}", sending_command.from);
-                            }
-                            Err(e) => {
-                                error!("Failed to parse JSON from MQTT: {:?}", e);
-                                continue;
-                            }
-                        }
-                    }
-                }
-                None => {
-                    warn!("MQTT Connection lost. Reconnecting...");
-                    mqtt_reconnect(&mqtt_client).await;
-                }
+    if let Some(path) = &cli.friendly_names {
+        report.check(FriendlyNames::load(&PathBuf::from(path)).map(|_| ()).map_err(|e| format!("--friendly-names {}", e)));
+    }
+    if let Some(path) = &cli.spec_file {
+        report.check(MiotSpec::load(&PathBuf::from(path)).map(|_| ()).map_err(|e| format!("--spec-file {}", e)));
+    }
+    if let Some(path) = &cli.queue_file {
+        report.check(check::parent_dir("queue-file", path));
+    }
+
+    let agent = cli.agent_socket_path.clone().unwrap_or_else(|| DEFAULT_AGENT_SOCKET.to_string());
+    if connect {
+        report.check(check::connect_agent(&agent).await);
+        let client_id = cli.client_id.clone().unwrap_or_else(|| format!("agent2mqtt-{}", cli.bind_id.unwrap_or(0)));
+        for (uri, config) in &brokers {
+            match check::connect_broker(uri, &format!("{}-check", client_id), config).await {
+                Ok(version) => println!("Connected to '{}' with MQTT version {}", uri, version),
+                Err(e) => report.check(Err(e)),
             }
         }
-        info!("MQTT stream ended. Re-acquiring stream...");
-        sleep(Duration::from_millis(1000)).await;
+    } else {
+        check::agent_socket(&agent, &mut report);
     }
+    report.print()
 }
 
-async fn ha_driven_reader(
-    mqtt_client: mqtt::AsyncClient
-) {
-    loop {
-        let _ = Command::new("killall").arg("-9").arg("ha_driven").status().await;
-        sleep(Duration::from_millis(500)).await;
 
-        let mut command = Command::new("ha_driven");
-        command.stdout(Stdio::piped());
-        info!("Preparing to read logs from ha_driven...");
+// SIGUSR1: info, debug, trace and back to info
+// Only the default level cycles, levels for modules and tasks stay as they are
+fn cycle_log_level() -> Levels {
+    let levels = logger::levels();
+    let level = match levels.default_level() {
+        LevelFilter::Info => LevelFilter::Debug,
+        LevelFilter::Debug => LevelFilter::Trace,
+        _ => LevelFilter::Info,
+    };
+    let levels = levels.with_default(level);
+    logger::set_levels(levels.clone());
+    levels
+}
 
-        let mut child = command.spawn().expect("Failed to spawn child process");
+// The command line with the options of --config in front
+fn merged_args(args: &[OsString], matches: &ArgMatches) -> Result<Vec<OsString>, String> {
+    let Some(path) = matches.get_one::<String>("config") else {
+        return Ok(args.to_vec());
+    };
+    let options = config::load(path)?;
+    let file_args = config::to_args(&options, &Cli::command(), matches)?;
+    // File options go first, nothing in them was given on the command line
+    Ok(args.iter().take(1).chain(&file_args).chain(args.iter().skip(1)).cloned().collect())
+}
 
-        let stdout = child.stdout.take().expect("Failed to open stdout");
+// Parses the command line, merged with the options of --config
+fn parse_cli() -> Cli {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = Cli::command().get_matches_from(&args);
+    let merged = merged_args(&args, &matches).unwrap_or_else(|e| {
+        eprintln!("Invalid config file: {}", e);
+        std::process::exit(2);
+    });
+    Cli::parse_from(merged)
+}
 
-        let mut reader = BufReader::new(stdout).lines();
+// Same as parse_cli, but errors are returned instead of ending the process
+fn reload_cli() -> Result<Cli, String> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = Cli::command().try_get_matches_from(&args).map_err(|e| e.to_string())?;
+    let merged = merged_args(&args, &matches).map_err(|e| format!("Invalid config file: {}", e))?;
+    Cli::try_parse_from(merged).map_err(|e| e.to_string())
+}
 
-        while let Ok(Some(line)) = reader.next_line().await {
-            debug!("Captured line: {}", line);
-            if line.contains("another process exist") {
-                break;
-            }
-            if line.contains("onReceiveMessage") && line.contains("method") && line.contains("res/report") {
-                if let Some(s) = line.split(">>").nth(1) {
-                    if let Some(s2) = s.trim().split(" (master_bridge").nth(0) {
-                        debug!("res/report line: {}", s2);
-                        let _ = mqtt_client
-                            .publish(mqtt::Message::new(TOPIC_RESPONSE, s2.as_bytes(), 0)).await;
-                    }
-                }
-                continue;
-            }
-        }
-    }
+// What SIGHUP and the reload request apply, read again from the command line and config file
+fn reload_options() -> Result<ReloadOptions, String> {
+    let cli = reload_cli()?;
+    Ok(ReloadOptions {
+        levels: cli.log_level.unwrap_or_default(),
+        command_allow: cli.command_allow,
+        command_deny: cli.command_deny,
+        friendly_names: cli.friendly_names,
+        ha_discovery: cli.ha_discovery,
+    })
 }
 
-async fn agent_manager(
-    agent_socket_path: &str,
-    mqtt_client: mqtt::AsyncClient,
-    mut command_rx: mpsc::Receiver<String>,
-    bind_id: u32,
-) {
-    let _ = Command::new("rm").arg("-rf").arg("/tmp/miio_agent.socket").status().await;
-    sleep(Duration::from_millis(500)).await;
-    let _ = Command::new("killall").arg("-9").arg("ha_agent").status().await;
-
-    let mut buf = [0; 4096];
-
-    loop {
-        info!("Connecting to the miio agent socket at '{}'...", agent_socket_path);
-
-        let agent_socket = loop {
-            if let Ok(socket) = UnixSeqpacket::connect(agent_socket_path).await {
-                info!("Successfully connected to miio agent socket with {}", bind_id);
-                // Send initialization messages
-                let _ = socket.send(format!(r#"{{"address":{},"method":"bind"}}"#, bind_id).as_bytes()).await;
-                for msg in [
-                    r#"{"key":"auto.report","method":"register"}"#,
-                    r#"{"key":"auto.forward","method":"register"}"#,
-                    r#"{"key":"lanbox.event","method":"register"}"#,
-                    r#"{"key":"auto.ifttt","method":"register"}"#,
-                    r#"{"key":"auto.cross.ifttt","method":"register"}"#,
-                    r#"{"key":"matter.control","method":"register"}"#,
-                    r#"{"key":"matter.event","method":"register"}"#,
-                    r#"{"key":"mtbr.control","method":"register"}"#,
-                ] {
-                    let _ = socket.send(msg.as_bytes()).await;
-                }
-                break socket;
-            }
-            sleep(Duration::from_millis(500)).await;
-        };
-
-        loop {
-            tokio::select! {
-                // Receive commands from MQTT task
-                cmd = command_rx.recv() => {
-                    match cmd {
-                        Some(payload) => {
-                            if let Err(e) = agent_socket.send(payload.as_bytes()).await {
-                                error!("Error sending to agent socket: {:?}. Reconnecting...", e);
-                                break;
-                            }
-                        },
-                        None => return, // Channel closed, exit application
-                    }
-                }
-                // Receive data from Agent Socket
-                res = agent_socket.recv(&mut buf) => {
-                    match res {
-                        Ok(n) if n > 0 => {
-                            let mut topic: &str = TOPIC_RESPONSE;
-                            match serde_json::from_slice::<Value>(&buf[..n]) {
-                                Ok(msg) => {
-                                    debug!("reading length: '{}' msg: '{:?}'", n, msg);
-
-                                    // Check if this message correlates to the last command sent
-                                    if let Some(recv_id) = msg.get("id").and_then(|v| v.as_u64()) {
-                                        let sending_command = SENDING_TOPIC_COMMAND.lock().unwrap();
-                                        if sending_command.id == recv_id {
-                                            topic = TOPIC_COMMAND_ACK;
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    error!("Failed to parse JSON from agent: {:?}", e);
-                                    continue;
-                                }
-                            }
-
-                            let _ = mqtt_client
-                                .publish(mqtt::Message::new(topic, &buf[..n], 0))
-                                .await;
-                        }
-                        Ok(_) => {
-                            warn!("Agent socket closed (EOF). Reconnecting...");
-                            break;
-                        }
-                        Err(e) => {
-                            error!("Error reading from agent socket: {:?}. Reconnecting...", e);
-                            break;
-                        }
-                    }
-                }
-            }
+fn main() {
+    let cli = parse_cli();
+    let pidfile = cli.pidfile.clone();
+    if cli.command.is_none()
+        && let Err(e) = daemon::start(cli.daemon, cli.log_file.as_deref(), pidfile.as_deref())
+    {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+    let mut runtime = match cli.workers {
+        Some(workers) => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(workers.into());
+            builder
         }
-        sleep(Duration::from_millis(500)).await;
+        None => tokio::runtime::Builder::new_current_thread(),
+    };
+    let exit_code = runtime
+        .enable_all()
+        .build()
+        .expect("Failed to start the tokio runtime")
+        .block_on(run(cli));
+    if let Some(path) = pidfile {
+        daemon::remove_pidfile(&path);
+    }
+    if exit_code != 0 {
+        std::process::exit(exit_code);
     }
 }
 
-fn init_log(log_level: LevelFilter) {
-    static LOGGER: Logger = Logger;
-    log::set_max_level(log_level);
-    log::set_logger(&LOGGER).unwrap();
-}
-
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let cli = Cli::parse();
-
-    let level = match cli.log_level {
-        Some(level) => match level.as_str() {
-            "error" => LevelFilter::Error,
-            "warn" => LevelFilter::Warn,
-            "info" => LevelFilter::Info,
-            "debug" => LevelFilter::Debug,
-            "trace" => LevelFilter::Trace,
-            _ => LevelFilter::Info,
-        },
-        None => LevelFilter::Info,
+// Returns the exit code, non-zero when the bridge gave up on a failing task
+async fn run(cli: Cli) -> i32 {
+    // Subcommands log to the terminal, --log-file is only applied by daemon::start
+    let rotation = cli.log_file.clone().filter(|_| cli.command.is_none()).map(|path| Rotation {
+        path,
+        max_size: cli.log_max_size.max(1) * 1024,
+        max_files: cli.log_max_files,
+    });
+    logger::init(cli.log_level.clone().unwrap_or_default(), cli.log_format, cli.log_color, rotation);
+    if let Some(command) = &cli.command {
+        std::process::exit(run_subcommand(&cli, command).await);
+    }
+    let mut ha_driven = HaDrivenOptions {
+        source: ha_driven_source(&cli),
+        publish_errors: cli.ha_driven_errors,
+        ..HaDrivenOptions::default()
     };
-
-    init_log(level);
-
-    let mqtt_host = match cli.mqtt_ip {
-        Some(ip) => format!("mqtt://{}:1883", ip),
-        None => "mqtt://localhost:1883".to_string(),
+    if !cli.ha_driven_rule.is_empty() {
+        ha_driven.rules = cli.ha_driven_rule.clone();
+    }
+    let config = BridgeConfig {
+        topic_prefix: cli.topic_prefix.clone(),
+        gateway_id: cli.gateway_id.clone(),
+        compat: cli.compat,
+        enrich_reports: cli.enrich_reports,
+        ack_latency: cli.ack_latency,
+        battery_threshold: cli.battery_topics,
+        network_quality: cli.network_quality,
+        device_timeouts: cli.device_availability.then(|| DeviceTimeouts {
+            battery: Duration::from_secs(cli.availability_battery_timeout * 60),
+            mains: Duration::from_secs(cli.availability_mains_timeout * 60),
+        }),
+        ha_discovery: cli.ha_discovery.clone(),
     };
 
-    let create_opts = mqtt::CreateOptionsBuilder::new()
-        .server_uri(mqtt_host)
-        .client_id("agent2mqtt")
-        .finalize();
-    let mqtt_client = mqtt::AsyncClient::new(create_opts).unwrap_or_else(|e| {
-        panic!("Error creating the MQTT client: {:?}", e);
-    });
-
-    let bind_id = match cli.bind_id {
-        Some(id) => id,
-        None => 0,
+    let mqtt_host = mqtt_uri(&cli);
+    let mqtt_config = mqtt_config(&cli);
+    let secondary_config = secondary_mqtt_config(&cli);
+
+    let bind_id = cli.bind_id.unwrap_or(0);
+
+    let agent = AgentOptions {
+        socket_path: cli.agent_socket_path.unwrap_or_else(|| DEFAULT_AGENT_SOCKET.to_string()),
+        bind_id,
+        mirror_raw: cli.mirror_raw,
+        command_timeout: Duration::from_secs(cli.command_timeout),
+        command_retries: cli.command_retries,
+        retry_methods: cli.retry_methods,
+        register_keys: cli.register_keys,
+        watchdog: (cli.agent_watchdog > 0).then(|| Duration::from_secs(cli.agent_watchdog)),
+        inventory_interval: (cli.inventory_interval > 0).then(|| Duration::from_secs(cli.inventory_interval * 60)),
+        mux_socket: cli.mux_socket,
     };
-
-    let agent_socket_path = match cli.agent_socket_path {
-        Some(path) => path,
-        None => "/tmp/miio_agent.socket".to_string(),
+    let qos = QosConfig {
+        report: cli.qos_report,
+        ack: cli.qos_ack,
+        command_sub: cli.qos_command_sub,
     };
 
-    let (tx, rx) = mpsc::channel::<String>(32);
+    let friendly_names = match cli.friendly_names.map(|path| FriendlyNames::load(&PathBuf::from(path))) {
+        None => FriendlyNames::default(),
+        Some(Ok(friendly_names)) => friendly_names,
+        Some(Err(e)) => {
+            eprintln!("error: failed to load friendly names: {}", e);
+            return 1;
+        }
+    };
+    let spec = match cli.spec_file.map(|path| MiotSpec::load(&PathBuf::from(path))) {
+        None => MiotSpec::default(),
+        Some(Ok(spec)) => spec,
+        Some(Err(e)) => {
+            eprintln!("error: failed to load spec file: {}", e);
+            return 1;
+        }
+    };
 
-    tokio::spawn(mqtt_manager(
-        mqtt_client.clone(),
-        tx,
-    ));
+    let mut builder = Bridge::builder()
+        .config(config)
+        .mqtt(mqtt_host, mqtt_config)
+        .agent(agent)
+        .qos(qos)
+        .queue(cli.queue_size, cli.queue_overflow, cli.queue_file.map(PathBuf::from))
+        .command_queue(cli.command_queue_size, cli.command_queue_overflow)
+        .rate_limiter(RateLimiter::new(cli.max_publish_rate, cli.max_topic_rate, cli.rate_limit_policy))
+        .state_cache(StateCache::new(friendly_names, cli.zigbee2mqtt_topics, spec))
+        .command_filter(CommandFilter::new(cli.command_allow, cli.command_deny))
+        .reloader(reload_options)
+        .telemetry_interval((cli.telemetry_interval > 0).then(|| Duration::from_secs(cli.telemetry_interval)))
+        .stats_interval((cli.stats_interval > 0).then(|| Duration::from_secs(cli.stats_interval)))
+        .error_history(cli.error_history)
+        .low_memory(cli.low_memory)
+        .ha_driven(!cli.no_ha_driven)
+        .ha_driven_options(ha_driven)
+        .dedup_window(Duration::from_millis(cli.dedup_window));
+    if let Some(client_id) = cli.client_id {
+        builder = builder.client_id(client_id);
+    }
+    if let Some(uri) = cli.mqtt_uri_secondary {
+        builder = builder.secondary_mqtt(uri, secondary_config);
+    }
+    if let Some(addr) = cli.metrics_listen {
+        builder = builder.metrics(addr, (cli.health_max_idle > 0).then(|| Duration::from_secs(cli.health_max_idle)));
+    }
+    if let Some(endpoint) = cli.otlp_endpoint {
+        builder = builder.otlp_endpoint(endpoint);
+    }
+    let mut bridge = match builder.spawn().await {
+        Ok(bridge) => bridge,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return 1;
+        }
+    };
 
-    tokio::spawn(ha_driven_reader(
-        mqtt_client.clone(),
-    ));
+    let mut sighup = signal(SignalKind::hangup()).expect("Failed to install SIGHUP handler");
+    let mut sigusr1 = signal(SignalKind::user_defined1()).expect("Failed to install SIGUSR1 handler");
+    let shutdown_signal = wait_for_shutdown_signal();
+    tokio::pin!(shutdown_signal);
+    let exit_code = loop {
+        tokio::select! {
+            _ = sighup.recv() => {
+                info!("Received SIGHUP");
+                if let Err(e) = bridge.reload().await {
+                    warn!("Reload failed: {}", e);
+                }
+            }
+            _ = sigusr1.recv() => {
+                let level = cycle_log_level();
+                info!("Received SIGUSR1, log level is now {}", level);
+            }
+            _ = &mut shutdown_signal => break 0,
+            reason = bridge.failed() => {
+                error!("Giving up, {}", reason);
+                break 1;
+            }
+        }
+    };
+    info!("Shutting down...");
 
-    agent_manager(&agent_socket_path, mqtt_client, rx, bind_id).await;
+    // A second SIGTERM or SIGINT doesn't wait for the tasks
+    tokio::select! {
+        res = timeout(SHUTDOWN_TIMEOUT, bridge.shutdown()) => if res.is_err() {
+            warn!("Shutdown timed out after {:?}", SHUTDOWN_TIMEOUT);
+        },
+        _ = wait_for_shutdown_signal() => warn!("Exiting without waiting for the tasks"),
+    }
+    exit_code
 }
@@ -0,0 +1,89 @@
+use serde_json::{json, Map, Value};
+
+// Matter commands in a simplified form, validated and sent to matter.control
+pub const TOPIC_MATTER_SET: &str = "aqara2mqtt/matter/set";
+
+const MATTER_EVENT_KEY: &str = "matter.event";
+
+// Aliases the firmwares use for the same fields
+const FIELDS: [(&str, &[&str]); 6] = [
+    ("node", &["node_id", "nodeId", "node"]),
+    ("endpoint", &["endpoint_id", "endpointId", "endpoint", "ep"]),
+    ("cluster", &["cluster_id", "clusterId", "cluster"]),
+    ("attribute", &["attribute_id", "attributeId", "attribute", "attr_id"]),
+    ("event", &["event_id", "eventId", "event"]),
+    ("value", &["value", "data"]),
+];
+
+fn decode_item(item: &Map<String, Value>) -> Option<Value> {
+    let mut event = Map::new();
+    for (name, aliases) in FIELDS {
+        if let Some(value) = aliases.iter().find_map(|alias| item.get(*alias)) {
+            event.insert(name.to_string(), value.clone());
+        }
+    }
+    // Without a cluster it isn't anything we know how to decode
+    event.contains_key("cluster").then_some(Value::Object(event))
+}
+
+// Flat {"node","endpoint","cluster","attribute"|"event","value"} objects of a
+// matter.event frame, None for other frames or unknown layouts
+pub fn decode_event(report: &Value) -> Option<Vec<u8>> {
+    if report.get("key").and_then(Value::as_str) != Some(MATTER_EVENT_KEY) {
+        return None;
+    }
+    let params = report.get("params")?;
+    let mut decoded = match params {
+        Value::Object(item) => decode_item(item)?,
+        Value::Array(items) => {
+            let events: Option<Vec<Value>> = items.iter().map(|item| item.as_object().and_then(decode_item)).collect();
+            Value::Array(events?)
+        }
+        _ => return None,
+    };
+    if let (Value::Object(event), Some(method)) = (&mut decoded, report.get("method")) {
+        event.insert("method".to_string(), method.clone());
+    }
+    Some(decoded.to_string().into_bytes())
+}
+
+fn required_u64(request: &Map<String, Value>, field: &str) -> Result<u64, String> {
+    request
+        .get(field)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| format!("'{}' must be a non-negative integer", field))
+}
+
+// Turns a matter/set request into an rpc for the matter route:
+// {"node":1,"endpoint":1,"cluster":6,"command":"On","args":{}} invokes a command,
+// {"node":1,"endpoint":1,"cluster":8,"attribute":0,"value":128} writes an attribute
+pub fn set_command(payload: &[u8]) -> Result<Vec<u8>, String> {
+    let request = match serde_json::from_slice::<Value>(payload) {
+        Ok(Value::Object(map)) => map,
+        Ok(_) => return Err("request must be a JSON object".to_string()),
+        Err(e) => return Err(format!("request is not valid JSON: {}", e)),
+    };
+    let node = required_u64(&request, "node")?;
+    let endpoint = required_u64(&request, "endpoint")?;
+    let cluster = required_u64(&request, "cluster")?;
+    let (method, params) = match (request.get("command"), request.get("attribute")) {
+        (Some(command), None) if command.is_string() || command.is_u64() => {
+            let args = request.get("args").cloned().unwrap_or_else(|| json!({}));
+            if !args.is_object() {
+                return Err("'args' must be an object".to_string());
+            }
+            ("invoke_command", json!({ "node_id": node, "endpoint_id": endpoint, "cluster_id": cluster, "command": command, "args": args }))
+        }
+        (None, Some(attribute)) if attribute.is_u64() => {
+            let value = request.get("value").ok_or("'value' is missing")?;
+            ("write_attribute", json!({ "node_id": node, "endpoint_id": endpoint, "cluster_id": cluster, "attribute_id": attribute, "value": value }))
+        }
+        (Some(_), Some(_)) => return Err("give either 'command' or 'attribute'".to_string()),
+        _ => return Err("'command' (name or id) or 'attribute' (id) is required".to_string()),
+    };
+    let mut rpc = json!({ "method": method, "params": params });
+    if let Some(id) = request.get("id") {
+        rpc["id"] = id.clone();
+    }
+    Ok(rpc.to_string().into_bytes())
+}
@@ -0,0 +1,166 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+
+use log::{debug, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::time::{timeout, Duration};
+
+use crate::context;
+use crate::health;
+use crate::publisher::Publisher;
+use crate::stats::{self, Counter, LATENCY_BUCKETS_MS, LATENCY_QUANTILES};
+
+// Scrapers send a short GET, anything longer or slower is dropped
+const MAX_REQUEST: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4";
+
+fn label(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', r#"\""#).replace('\n', r"\n")
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP aqara2mqtt_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE aqara2mqtt_{} {}", name, kind);
+}
+
+// The stats counters in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    let topics = stats::topic_counts();
+    metric(&mut out, "messages_received_total", "counter", "Messages received from the broker");
+    for (topic, received, _) in &topics {
+        let _ = writeln!(out, "aqara2mqtt_messages_received_total{{topic=\"{}\"}} {}", label(topic), received);
+    }
+    metric(&mut out, "messages_published_total", "counter", "Messages the primary broker accepted");
+    for (topic, _, published) in &topics {
+        let _ = writeln!(out, "aqara2mqtt_messages_published_total{{topic=\"{}\"}} {}", label(topic), published);
+    }
+    let counters: [(&str, &str, Counter); 6] = [
+        ("parse_errors_total", "Frames and commands that could not be parsed", |stats| &stats.parse_errors),
+        ("mqtt_reconnects_total", "Reconnects to the MQTT brokers", |stats| &stats.mqtt_reconnects),
+        ("agent_reconnects_total", "Reconnects to the agent socket", |stats| &stats.agent_reconnects),
+        ("ha_driven_forwarded_total", "Messages from the ha_driven reader", |stats| &stats.ha_driven_forwarded),
+        ("duplicates_suppressed_total", "Reports dropped as already forwarded from the other source", |stats| &stats.duplicates_suppressed),
+        ("coalesced_total", "Reports merged by the rate limiter", |stats| &stats.coalesced),
+    ];
+    for (name, help, counter) in counters {
+        metric(&mut out, name, "counter", help);
+        let _ = writeln!(out, "aqara2mqtt_{} {}", name, stats::get(counter));
+    }
+    metric(&mut out, "dropped_total", "counter", "Messages dropped by reason");
+    let dropped: [(&str, Counter); 3] =
+        [("queue", |stats| &stats.queue_dropped), ("rate_limited", |stats| &stats.rate_limited), ("commands", |stats| &stats.commands_dropped)];
+    for (reason, counter) in dropped {
+        let _ = writeln!(out, "aqara2mqtt_dropped_total{{reason=\"{}\"}} {}", reason, stats::get(counter));
+    }
+    metric(&mut out, "queue_depth", "gauge", "Messages waiting in the publish queue");
+    let _ = writeln!(out, "aqara2mqtt_queue_depth {}", stats::get(|stats| &stats.queue_depth));
+
+    let latency = stats::latency();
+    metric(&mut out, "command_latency_seconds", "histogram", "Time from a command arriving to its ack being published");
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS_MS.iter().zip(latency.buckets) {
+        cumulative += count;
+        let le = *bound as f64 / 1000.0;
+        let _ = writeln!(out, "aqara2mqtt_command_latency_seconds_bucket{{le=\"{}\"}} {}", le, cumulative);
+    }
+    let _ = writeln!(out, "aqara2mqtt_command_latency_seconds_bucket{{le=\"+Inf\"}} {}", latency.count);
+    let _ = writeln!(out, "aqara2mqtt_command_latency_seconds_sum {}", latency.sum.as_secs_f64());
+    let _ = writeln!(out, "aqara2mqtt_command_latency_seconds_count {}", latency.count);
+    if let Some(percentiles) = stats::latency_percentiles() {
+        metric(&mut out, "command_latency_recent_seconds", "gauge", "Percentiles of the latest command round trips");
+        for ((_, quantile), value) in LATENCY_QUANTILES.iter().zip(percentiles) {
+            let _ = writeln!(out, "aqara2mqtt_command_latency_recent_seconds{{quantile=\"{}\"}} {}", quantile, value.as_secs_f64());
+        }
+    }
+    out
+}
+
+// What the endpoints need besides the global stats
+#[derive(Clone)]
+pub struct Context {
+    pub publisher: Publisher,
+    pub health_max_idle: Option<Duration>,
+}
+
+// Reads the request head and answers GET /metrics and /healthz, everything else is a 404
+async fn handle(mut stream: TcpStream, context: Context) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", PROMETHEUS_TEXT, render()),
+        (Some("GET"), Some("/healthz")) => {
+            let health = health::check(&context.publisher, context.health_max_idle);
+            let status = if health.is_ok() { "200 OK" } else { "503 Service Unavailable" };
+            (status, "application/json", format!("{}\n", health.to_json()))
+        }
+        _ => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+pub async fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    Ok(listener)
+}
+
+pub async fn serve(listener: TcpListener, context: Context, mut shutdown: broadcast::Receiver<()>) {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let context = context.clone();
+                    context::spawn(async move {
+                        match timeout(REQUEST_TIMEOUT, handle(stream, context)).await {
+                            Ok(Err(e)) => debug!("Metrics request from {} failed: {:?}", peer, e),
+                            Err(_) => debug!("Metrics request from {} timed out", peer),
+                            Ok(Ok(())) => {}
+                        }
+                    });
+                }
+                Err(e) => debug!("Error accepting a metrics connection: {:?}", e),
+            },
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+// The health subcommand: asks a running bridge for /healthz, Ok(true) when it answered 200
+pub async fn probe(addr: SocketAddr) -> Result<bool, String> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(format!("GET /healthz HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", addr).as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = match timeout(REQUEST_TIMEOUT, exchange).await {
+        Ok(Ok(response)) => String::from_utf8_lossy(&response).into_owned(),
+        Ok(Err(e)) => return Err(format!("http://{}/healthz: {}", addr, e)),
+        Err(_) => return Err(format!("http://{}/healthz: no response within {:?}", addr, REQUEST_TIMEOUT)),
+    };
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    print!("{}", body);
+    Ok(head.split_whitespace().nth(1) == Some("200"))
+}
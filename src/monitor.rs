@@ -0,0 +1,99 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::BytesMut;
+use serde_json::Value;
+
+use crate::agent_socket::{self, AgentTransport};
+use crate::base64;
+use crate::filter::glob_match;
+use crate::send;
+
+// `field=glob` on a top-level field of the frame, e.g. key=auto.report or method=*.ota
+#[derive(Clone)]
+pub struct FrameFilter {
+    field: String,
+    pattern: String,
+}
+
+pub fn parse_filter(filter: &str) -> Result<FrameFilter, String> {
+    let (field, pattern) = filter
+        .split_once('=')
+        .ok_or_else(|| format!("expected field=pattern, got '{}'", filter))?;
+    Ok(FrameFilter { field: field.trim().to_string(), pattern: pattern.trim().to_string() })
+}
+
+impl FrameFilter {
+    fn matches(&self, document: &Value) -> bool {
+        match document.get(&self.field) {
+            Some(Value::String(value)) => glob_match(&self.pattern, value),
+            Some(value @ (Value::Number(_) | Value::Bool(_))) => glob_match(&self.pattern, &value.to_string()),
+            _ => false,
+        }
+    }
+}
+
+// Local wall clock time with milliseconds, like tcpdump prints it
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+    format!("{:02}:{:02}:{:02}.{:03}", tm.tm_hour, tm.tm_min, tm.tm_sec, now.subsec_millis())
+}
+
+// What the frame is, the first of key, method and id it has
+fn summary(document: &Value) -> String {
+    ["key", "method"]
+        .iter()
+        .find_map(|field| document.get(*field).and_then(Value::as_str).map(str::to_string))
+        .or_else(|| document.get("id").map(|id| format!("reply {}", id)))
+        .unwrap_or_default()
+}
+
+fn print_frame(data: &[u8], len: usize, filters: &[FrameFilter]) {
+    let time = timestamp();
+    if len > data.len() {
+        println!("{} frame of {} bytes truncated to {}", time, len, data.len());
+    }
+    let (documents, rest) = agent_socket::split_documents(data);
+    for (_, document) in documents {
+        if !filters.iter().all(|filter| filter.matches(&document)) {
+            continue;
+        }
+        let pretty = serde_json::to_string_pretty(&document).unwrap_or_default();
+        println!("{} {}\n{}", time, summary(&document), pretty);
+    }
+    // Binary frames can't match a filter on fields
+    if let Some((rest, _)) = rest
+        && filters.is_empty()
+    {
+        println!("{} non-JSON frame of {} bytes, base64\n{}", time, rest.len(), base64::encode(rest));
+    }
+}
+
+// The monitor subcommand: binds, registers the keys and prints every frame until Ctrl-C
+pub async fn run(address: &str, bind_id: u32, register_keys: &[String], filters: &[FrameFilter]) -> Result<(), String> {
+    let mut socket = send::connect(address, bind_id).await?;
+    let io_error = |e: std::io::Error| format!("agent socket: {}", e);
+    for key in register_keys {
+        let msg = format!(r#"{{"key":"{}","method":"register"}}"#, key);
+        socket.send(msg.as_bytes()).await.map_err(io_error)?;
+    }
+    eprintln!("Listening on '{}' with bind id {}, Ctrl-C to stop", address, bind_id);
+
+    let mut buf = BytesMut::zeroed(4096);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        tokio::select! {
+            res = socket.recv(&mut buf) => match res {
+                Ok((0, _)) => return Err(format!("the agent closed the connection, is bind id {} taken?", bind_id)),
+                Ok((n, len)) => print_frame(&buf[..n], len, filters),
+                Err(e) => return Err(io_error(e)),
+            },
+            _ = &mut ctrl_c => break,
+        }
+    }
+    send::disconnect(socket, bind_id).await;
+    Ok(())
+}
@@ -0,0 +1,518 @@
+use std::sync::{Arc, Mutex};
+
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+use tokio_stream::StreamExt;
+
+use crate::backoff::Backoff;
+use crate::bridge::{self, QosConfig, Reloader};
+use crate::command;
+use crate::command_channel::{CommandSender, SendError};
+use crate::context;
+use crate::correlation::Correlator;
+use crate::filter::CommandFilter;
+use crate::info::{self, BridgeInfo};
+use crate::mqtt_client::{Client, Message, MqttClient, MqttConfig};
+use crate::routing::{self, TOPIC_COMMAND};
+use crate::state::StateCache;
+use crate::stats;
+use crate::{availability, deadletter, device, gateway, logger, matter, ota, pairing, topics, trace, uds_proxy, zigbee2mqtt};
+
+pub const TOPIC_DIAGNOSTICS: &str = "aqara2mqtt/bridge/diagnostics";
+// Same as SIGHUP, answered on the response topic
+pub const TOPIC_RELOAD_REQUEST: &str = "aqara2mqtt/bridge/request/reload";
+pub const TOPIC_RELOAD_RESPONSE: &str = "aqara2mqtt/bridge/response/reload";
+// `debug` or {"value":"debug"}, SIGUSR1 cycles info, debug and trace
+pub const TOPIC_LOG_LEVEL_REQUEST: &str = "aqara2mqtt/bridge/request/log_level";
+pub const TOPIC_LOG_LEVEL_RESPONSE: &str = "aqara2mqtt/bridge/response/log_level";
+// Repeated drops within this window usually mean a client id collision
+const CONNECTION_LOST_WINDOW: Duration = Duration::from_secs(60);
+const CONNECTION_LOST_WARN_COUNT: usize = 3;
+
+// unix:// brokers are reached through a local TCP proxy with paho
+pub async fn create_client(server_uri: &str, client_id: &str) -> Result<Client, String> {
+    let server_uri = if uds_proxy::needed(server_uri) {
+        uds_proxy::start(server_uri)
+            .await
+            .map_err(|e| format!("Error starting the unix socket proxy for '{}': {:?}", server_uri, e))?
+    } else {
+        server_uri.to_string()
+    };
+    Client::new(&server_uri, client_id).map_err(|e| format!("Error creating the MQTT client for '{}': {:?}", server_uri, e))
+}
+
+// Returns the number of failed attempts before the connection came back,
+// None when shutdown came first
+async fn mqtt_reconnect(
+    client: &Client,
+    sub_qos: Option<i32>,
+    backoff: &mut Backoff,
+    info: &BridgeInfo,
+    shutdown: &mut broadcast::Receiver<()>,
+) -> Option<u64> {
+    let mut attempts = 0;
+    loop {
+        let reconnected = match sub_qos {
+            Some(qos) => client.reconnect().await.is_ok() && mqtt_subscribe(client, qos).await,
+            None => client.reconnect().await.is_ok(),
+        };
+        if reconnected {
+            publish_birth(client, info).await;
+            warn!("Successfully reconnected after {} failed attempts", attempts);
+            backoff.reset();
+            return Some(attempts);
+        }
+        attempts += 1;
+        let delay = backoff.next_delay();
+        debug!("Reconnect attempt {} failed, retrying in {:?}", attempts, delay);
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = shutdown.recv() => return None,
+        }
+    }
+}
+
+async fn publish_birth(client: &Client, info: &BridgeInfo) {
+    availability::publish_online(client).await;
+    info::publish(client, info).await;
+}
+
+async fn publish_diagnostics(client: &Client, reconnects: u64, reconnect_attempts: u64) {
+    let payload = serde_json::json!({
+        "mqtt_reconnects": reconnects,
+        "mqtt_reconnect_attempts": reconnect_attempts,
+        "rate_limited": stats::get(|stats| &stats.rate_limited),
+        "coalesced": stats::get(|stats| &stats.coalesced),
+    });
+    let msg = topics::tag(Message::new_retained(topics::prefixed(TOPIC_DIAGNOSTICS), payload.to_string(), 0));
+    if let Err(e) = client.publish(msg).await {
+        error!("Error publishing diagnostics: {:?}", e);
+    }
+}
+
+async fn mqtt_subscribe(client: &Client, qos: i32) -> bool {
+    let subscriptions = [
+        format!("{}/#", topics::prefixed(TOPIC_COMMAND)),
+        topics::prefixed(zigbee2mqtt::TOPIC_RENAME_REQUEST),
+        topics::prefixed(pairing::TOPIC_PERMIT_JOIN_REQUEST),
+        topics::prefixed(pairing::TOPIC_REMOVE_REQUEST),
+        topics::prefixed(matter::TOPIC_MATTER_SET),
+        device::subscription(device::TOPIC_DEVICE_SET),
+        device::subscription(device::TOPIC_DEVICE_GET),
+        gateway::subscription(),
+        topics::prefixed(ota::TOPIC_OTA_REQUEST),
+        topics::prefixed(TOPIC_RELOAD_REQUEST),
+        topics::prefixed(TOPIC_LOG_LEVEL_REQUEST),
+        topics::prefixed(stats::TOPIC_STATS_RESET_REQUEST),
+    ];
+    for topic in subscriptions {
+        if let Err(err) = client.subscribe(&topic, qos).await {
+            let _ = client.disconnect().await;
+            error!("Error subscribing to topics: {:?}", err);
+            return false;
+        }
+    }
+    true
+}
+
+// Removes a device from the hub and clears everything the bridge published about it
+async fn handle_remove(
+    client: &Client,
+    command_tx: &CommandSender,
+    state_cache: &StateCache,
+    bind_id: u32,
+    payload: &[u8],
+    qos: i32,
+) {
+    let result = match pairing::parse_remove_request(payload) {
+        Ok(name) => {
+            let did = state_cache.names().resolve(&name);
+            match command_tx.send(pairing::remove_command(&did, bind_id)).await {
+                Ok(_) => state_cache.remove(&did).map(|messages| (did, messages)),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    let response = match result {
+        Ok((did, messages)) => {
+            info!("Removed '{}'", did);
+            for msg in messages {
+                if let Err(e) = client.publish(msg).await {
+                    error!("Error clearing topic of removed device: {:?}", e);
+                }
+            }
+            serde_json::json!({ "status": "ok", "data": { "did": did } })
+        }
+        Err(e) => {
+            warn!("Remove request failed: {}", e);
+            serde_json::json!({ "status": "error", "error": e })
+        }
+    };
+    let msg = Message::new(topics::prefixed(pairing::TOPIC_REMOVE_RESPONSE), response.to_string(), qos);
+    if let Err(e) = client.publish(topics::tag(msg)).await {
+        error!("Error publishing remove response: {:?}", e);
+    }
+}
+
+// Opens or closes the Zigbee network, the end of the window is announced by a timer task
+async fn handle_permit_join(
+    client: &Client,
+    command_tx: &CommandSender,
+    bind_id: u32,
+    payload: &[u8],
+    qos: i32,
+    window: &mut Option<JoinHandle<()>>,
+) {
+    let response = match pairing::parse_request(payload) {
+        Ok(seconds) => match command_tx.send(pairing::command(seconds, bind_id)).await {
+            Ok(_) => {
+                info!("Permit join for {}s", seconds);
+                if let Some(window) = window.take() {
+                    window.abort();
+                }
+                let event = pairing::event("permit_join", serde_json::json!({ "value": seconds > 0, "time": seconds }));
+                let _ = client.publish(topics::tag(event)).await;
+                if seconds > 0 {
+                    let client = client.clone();
+                    *window = Some(context::spawn(async move {
+                        sleep(Duration::from_secs(seconds)).await;
+                        let event = pairing::event("permit_join", serde_json::json!({ "value": false, "time": 0 }));
+                        let _ = client.publish(topics::tag(event)).await;
+                    }));
+                }
+                serde_json::json!({ "status": "ok", "data": { "time": seconds } })
+            }
+            Err(e) => serde_json::json!({ "status": "error", "error": e.to_string() }),
+        },
+        Err(e) => {
+            warn!("Permit join request rejected: {}", e);
+            serde_json::json!({ "status": "error", "error": e })
+        }
+    };
+    let msg = Message::new(topics::prefixed(pairing::TOPIC_PERMIT_JOIN_RESPONSE), response.to_string(), qos);
+    if let Err(e) = client.publish(topics::tag(msg)).await {
+        error!("Error publishing permit join response: {:?}", e);
+    }
+}
+
+// zigbee2mqtt style rename request, {"from":"<did or name>","to":"<new name>"}
+async fn handle_rename(client: &Client, state_cache: &StateCache, payload: &[u8], qos: i32) {
+    let request = serde_json::from_slice::<Value>(payload).unwrap_or_default();
+    let from = request.get("from").and_then(|v| v.as_str()).unwrap_or_default();
+    let to = request.get("to").and_then(|v| v.as_str()).unwrap_or_default();
+    let (response, messages) = match state_cache.rename(from, to, qos) {
+        Ok((did, messages)) => {
+            info!("Renamed '{}' to '{}'", did, to);
+            (serde_json::json!({ "status": "ok", "data": { "from": from, "to": to, "did": did } }), messages)
+        }
+        Err(e) => {
+            warn!("Rename of '{}' failed: {}", from, e);
+            (serde_json::json!({ "status": "error", "error": e }), Vec::new())
+        }
+    };
+    let response = Message::new(topics::prefixed(zigbee2mqtt::TOPIC_RENAME_RESPONSE), response.to_string(), qos);
+    for msg in messages.into_iter().chain([response]) {
+        if let Err(e) = client.publish(topics::tag(msg)).await {
+            error!("Error publishing rename: {:?}", e);
+        }
+    }
+}
+
+async fn handle_reload(
+    client: &Client,
+    reloader: Option<&Reloader>,
+    filter: &Mutex<CommandFilter>,
+    state_cache: &StateCache,
+    qos: i32,
+) {
+    let (response, messages) = match bridge::reload(reloader, filter, state_cache) {
+        Ok(messages) => (serde_json::json!({ "status": "ok", "data": {} }), messages),
+        Err(e) => {
+            warn!("Reload failed: {}", e);
+            (serde_json::json!({ "status": "error", "error": e }), Vec::new())
+        }
+    };
+    for msg in messages {
+        if let Err(e) = client.publish(msg).await {
+            error!("Error publishing discovery config: {:?}", e);
+        }
+    }
+    let msg = Message::new(topics::prefixed(TOPIC_RELOAD_RESPONSE), response.to_string(), qos);
+    if let Err(e) = client.publish(topics::tag(msg)).await {
+        error!("Error publishing reload response: {:?}", e);
+    }
+}
+
+async fn handle_log_level(client: &Client, payload: &[u8], qos: i32) {
+    let text = String::from_utf8_lossy(payload);
+    let value = match serde_json::from_str::<Value>(&text) {
+        Ok(Value::Object(map)) => map.get("value").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+        Ok(Value::String(value)) => value,
+        _ => text.trim().to_string(),
+    };
+    let response = match logger::parse_levels(&value) {
+        Ok(levels) => {
+            let value = levels.to_string();
+            logger::set_levels(levels);
+            info!("Log level set to {}", value);
+            serde_json::json!({ "status": "ok", "data": { "value": value } })
+        }
+        Err(e) => {
+            warn!("Log level request rejected: {}", e);
+            serde_json::json!({ "status": "error", "error": e })
+        }
+    };
+    let msg = Message::new(topics::prefixed(TOPIC_LOG_LEVEL_RESPONSE), response.to_string(), qos);
+    if let Err(e) = client.publish(topics::tag(msg)).await {
+        error!("Error publishing log level response: {:?}", e);
+    }
+}
+
+// Answers with the counters as they were before zeroing them
+async fn handle_stats_reset(client: &Client, qos: i32) {
+    let before = stats::reset();
+    info!("Bridge statistics reset");
+    let response = serde_json::json!({ "status": "ok", "data": before });
+    let msg = Message::new(topics::prefixed(stats::TOPIC_STATS_RESET_RESPONSE), response.to_string(), qos);
+    if let Err(e) = client.publish(topics::tag(msg)).await {
+        error!("Error publishing stats reset response: {:?}", e);
+    }
+}
+
+// Error ack for a command refused by the command filter
+async fn publish_rejection(client: &Client, reply_topic: Option<String>, id: Option<Value>, reason: &str, qos: i32) {
+    let msg = topics::tag(routing::command_error(routing::ack_topic(reply_topic), id, command::ERROR_REJECTED, reason, qos));
+    if let Err(e) = client.publish(msg).await {
+        error!("Error publishing command rejection: {:?}", e);
+    }
+}
+
+// Error ack for a command that never reached the agent task, it is no longer pending
+async fn publish_dropped(client: &Client, correlator: &Correlator, payload: &[u8], code: i32, message: &str, qos: i32) {
+    let id = command::command_id(payload);
+    let pending = match id.as_ref().and_then(|id| id.as_u64()) {
+        Some(id) => correlator.take(id).await,
+        None => None,
+    };
+    let topic = routing::ack_topic(pending.and_then(|pending| pending.reply_topic));
+    let msg = topics::tag(routing::command_error(topic, id, code, message, qos));
+    if let Err(e) = client.publish(msg).await {
+        error!("Error publishing command error: {:?}", e);
+    }
+}
+
+// What the primary broker needs to handle commands and bridge requests
+#[derive(Clone)]
+pub struct CommandInput {
+    pub tx: CommandSender,
+    // Replaced on reload
+    pub filter: Arc<Mutex<CommandFilter>>,
+    pub state_cache: StateCache,
+    // Without one the reload request fails
+    pub reloader: Option<Reloader>,
+    pub correlator: Correlator,
+}
+
+// Owns the connection to one broker. Only the primary broker gets a command
+// input and subscribes to commands, other brokers are publish only.
+pub async fn mqtt_manager(
+    mut mqtt_client: Client,
+    commands: Option<CommandInput>,
+    config: MqttConfig,
+    qos: QosConfig,
+    info: BridgeInfo,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let sub_qos = commands.as_ref().map(|_| qos.command_sub);
+    let mut use_v5 = true;
+    let mut backoff = Backoff::new(Duration::from_millis(500), config.reconnect_max);
+    let mut reconnects: u64 = 0;
+    let mut reconnect_attempts: u64 = 0;
+
+    // Make the connection to the broker
+    loop {
+        info!(
+            "Connecting to the MQTT broker at '{}'...",
+            mqtt_client.server_uri()
+        );
+        match mqtt_client.connect(&config, use_v5).await {
+            Ok(mqtt_version) => {
+                info!(
+                    "Connected to: '{}' with MQTT version {}",
+                    mqtt_client.server_uri(), mqtt_version
+                );
+
+                if let Some(qos) = sub_qos {
+                    mqtt_subscribe(&mqtt_client, qos).await;
+                }
+                publish_birth(&mqtt_client, &info).await;
+                backoff.reset();
+                break;
+            }
+            Err(e) => {
+                error!("Error connecting to the MQTT broker: {:?}", e);
+                // Alternate protocol versions so brokers without v5 support still get through
+                use_v5 = !use_v5;
+                tokio::select! {
+                    _ = sleep(backoff.next_delay()) => {}
+                    _ = shutdown.recv() => {
+                        info!("Gave up connecting to the MQTT broker at '{}'", mqtt_client.server_uri());
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut connection_lost: Vec<Instant> = Vec::new();
+    let mut permit_join_window: Option<JoinHandle<()>> = None;
+
+    // Outer loop to recreate stream if it closes
+    loop {
+        let mut stream = mqtt_client.get_stream(25);
+
+        loop {
+            let msg = tokio::select! {
+                msg = stream.next() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = shutdown.recv() => {
+                    availability::publish_offline(&mqtt_client).await;
+                    if let Err(e) = mqtt_client.disconnect().await {
+                        error!("Error disconnecting from the MQTT broker: {:?}", e);
+                    }
+                    info!("Disconnected from the MQTT broker at '{}'", mqtt_client.server_uri());
+                    return;
+                }
+            };
+            match msg {
+                Some(msg) => {
+                    stats::count_in(msg.topic());
+                    let Some(CommandInput { tx: command_tx, filter, state_cache, reloader, correlator }) = &commands else { continue };
+                    if msg.topic() == topics::prefixed(zigbee2mqtt::TOPIC_RENAME_REQUEST) {
+                        handle_rename(&mqtt_client, state_cache, msg.payload(), qos.ack).await;
+                        continue;
+                    }
+                    if msg.topic() == topics::prefixed(TOPIC_RELOAD_REQUEST) {
+                        handle_reload(&mqtt_client, reloader.as_ref(), filter, state_cache, qos.ack).await;
+                        continue;
+                    }
+                    if msg.topic() == topics::prefixed(TOPIC_LOG_LEVEL_REQUEST) {
+                        handle_log_level(&mqtt_client, msg.payload(), qos.ack).await;
+                        continue;
+                    }
+                    if msg.topic() == topics::prefixed(stats::TOPIC_STATS_RESET_REQUEST) {
+                        handle_stats_reset(&mqtt_client, qos.ack).await;
+                        continue;
+                    }
+                    if msg.topic() == topics::prefixed(pairing::TOPIC_PERMIT_JOIN_REQUEST) {
+                        let bind_id = info.current_bind_id();
+                        handle_permit_join(&mqtt_client, command_tx, bind_id, msg.payload(), qos.ack, &mut permit_join_window).await;
+                        continue;
+                    }
+                    if msg.topic() == topics::prefixed(pairing::TOPIC_REMOVE_REQUEST) {
+                        let bind_id = info.current_bind_id();
+                        handle_remove(&mqtt_client, command_tx, state_cache, bind_id, msg.payload(), qos.ack).await;
+                        continue;
+                    }
+                    let Some(routed) = routing::command(msg.topic(), msg.payload(), state_cache, info.current_bind_id()) else {
+                        continue;
+                    };
+                    debug!("get command '{}'", msg);
+                    let payload = match routed.payload {
+                        Ok(payload) => payload,
+                        Err(reason) => {
+                            warn!("Rejected command '{}': {}", msg, reason);
+                            let id = command::command_id(msg.payload());
+                            publish_rejection(&mqtt_client, routed.reply_topic, id, &reason, qos.ack).await;
+                            continue;
+                        }
+                    };
+                    let parsed = serde_json::from_slice::<Value>(&payload);
+                    let verdict = {
+                        let filter = filter.lock().unwrap();
+                        match &parsed {
+                            _ if filter.is_empty() => Ok(()),
+                            Ok(json_msg) => filter.check(json_msg),
+                            Err(_) => Err("command is not valid JSON".to_string()),
+                        }
+                    };
+                    if let Err(reason) = verdict {
+                        warn!("Rejected command '{}': {}", msg, reason);
+                        let id = parsed.as_ref().ok().and_then(|v| v.get("id")).cloned();
+                        publish_rejection(&mqtt_client, routed.reply_topic, id, &reason, qos.ack).await;
+                        continue;
+                    }
+                    if let Ok(json_msg) = &parsed
+                        && let Some(id) = json_msg.get("id").and_then(|v| v.as_u64())
+                    {
+                        trace::received(id, json_msg.get("method").and_then(|v| v.as_str()).unwrap_or_default());
+                    }
+                    // Tracked first, so the answer can't reach the correlation task before the command
+                    if let Ok(json_msg) = &parsed {
+                        correlator.track(json_msg, payload.clone(), routed.reply_topic, routed.refresh_state).await;
+                    }
+                    match command_tx.send(payload.clone()).await {
+                        Ok(None) => {}
+                        Ok(Some(dropped)) => {
+                            warn!("Command queue full, dropped '{}'", String::from_utf8_lossy(&dropped));
+                            publish_dropped(&mqtt_client, correlator, &dropped, command::ERROR_QUEUE_FULL, "command queue full", qos.ack).await;
+                        }
+                        Err(SendError::Full) => {
+                            warn!("Command queue full, dropped '{}'", msg);
+                            publish_dropped(&mqtt_client, correlator, &payload, command::ERROR_QUEUE_FULL, "command queue full", qos.ack).await;
+                            continue;
+                        }
+                        Err(SendError::Closed) => {
+                            // The agent task is gone or restarting, the command won't be answered
+                            error!("Agent task gone, dropped '{}'", msg);
+                            stats::inc(|stats| &stats.commands_dropped);
+                            publish_dropped(&mqtt_client, correlator, &payload, command::ERROR_AGENT_UNAVAILABLE, "agent unavailable", qos.ack).await;
+                            continue;
+                        }
+                    }
+                    match parsed {
+                        Ok(_) => {}
+                        // Raw frames are expected not to be JSON, there is no id to track
+                        Err(_) if routed.route.is_some_and(command::is_raw) => {}
+                        Err(e) => {
+                            error!("Failed to parse JSON from MQTT: {:?}", e);
+                            let msg = topics::tag(deadletter::message("mqtt", &payload, &e.to_string()));
+                            if let Err(e) = mqtt_client.publish(msg).await {
+                                error!("Error publishing dead letter: {:?}", e);
+                            }
+                            continue;
+                        }
+                    }
+                }
+                None => {
+                    warn!("MQTT Connection lost. Reconnecting...");
+                    let now = Instant::now();
+                    connection_lost.retain(|t| now.duration_since(*t) < CONNECTION_LOST_WINDOW);
+                    connection_lost.push(now);
+                    if connection_lost.len() >= CONNECTION_LOST_WARN_COUNT {
+                        warn!(
+                            "Disconnected {} times in {}s, is another client using the id '{}'?",
+                            connection_lost.len(), CONNECTION_LOST_WINDOW.as_secs(), mqtt_client.client_id()
+                        );
+                    }
+                    let Some(attempts) = mqtt_reconnect(&mqtt_client, sub_qos, &mut backoff, &info, &mut shutdown).await else {
+                        info!("Gave up reconnecting to the MQTT broker at '{}'", mqtt_client.server_uri());
+                        return;
+                    };
+                    reconnect_attempts += attempts;
+                    reconnects += 1;
+                    stats::inc(|stats| &stats.mqtt_reconnects);
+                    publish_diagnostics(&mqtt_client, reconnects, reconnect_attempts).await;
+                }
+            }
+        }
+        info!("MQTT stream ended. Re-acquiring stream...");
+        sleep(Duration::from_millis(1000)).await;
+    }
+}
@@ -0,0 +1,269 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
+use bytes::Bytes;
+use tokio::time::Duration;
+use tokio_stream::Stream;
+
+#[cfg(any(test, feature = "testing"))]
+mod memory;
+#[cfg(feature = "paho")]
+mod paho;
+#[cfg(all(feature = "rumqttc", not(feature = "paho")))]
+mod rumqtt;
+
+#[cfg(any(test, feature = "testing"))]
+pub use memory::{MemoryBroker, MemoryClient};
+#[cfg(feature = "paho")]
+use paho::check_options as check_backend_options;
+#[cfg(feature = "paho")]
+pub use paho::PahoClient as BackendClient;
+#[cfg(all(feature = "rumqttc", not(feature = "paho")))]
+use rumqtt::check_options as check_backend_options;
+#[cfg(all(feature = "rumqttc", not(feature = "paho")))]
+pub use rumqtt::RumqttClient as BackendClient;
+
+#[cfg(not(any(feature = "paho", feature = "rumqttc")))]
+compile_error!("either the \"paho\" or the \"rumqttc\" feature must be enabled");
+
+pub const MQTT_VERSION_5: u32 = 5;
+// A MemoryBroker in the same process, only built with the testing feature
+pub const MEMORY_SCHEME: &str = "memory://";
+
+#[derive(Clone)]
+pub struct MqttConfig {
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub insecure: bool,
+    pub reconnect_max: Duration,
+    pub persistent_session: bool,
+    pub session_expiry: u32,
+    pub keep_alive: Duration,
+    pub connect_timeout: Duration,
+    pub max_inflight: i32,
+}
+
+pub fn is_ssl_uri(uri: &str) -> bool {
+    uri.starts_with("mqtts://") || uri.starts_with("ssl://") || uri.starts_with("wss://")
+}
+
+// Checks what the backend would only find out when connecting, e.g. unreadable TLS files
+pub fn check_options(server_uri: &str, config: &MqttConfig) -> Result<()> {
+    if server_uri.starts_with(MEMORY_SCHEME) {
+        return if cfg!(any(test, feature = "testing")) { Ok(()) } else { Err(memory_unavailable()) };
+    }
+    check_backend_options(config, server_uri)
+}
+
+#[derive(Debug)]
+pub struct Error(pub String);
+
+fn memory_unavailable() -> Error {
+    Error(format!("{} brokers need a build with the testing feature", MEMORY_SCHEME))
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Backend independent MQTT message, user properties are only sent on v5 connections.
+// The payload is shared, cloning a message for another broker or the queue doesn't copy it.
+#[derive(Clone, Debug)]
+pub struct Message {
+    topic: String,
+    payload: Bytes,
+    qos: i32,
+    retained: bool,
+    user_properties: Vec<(String, String)>,
+}
+
+impl Message {
+    pub fn new(topic: impl Into<String>, payload: impl Into<Bytes>, qos: i32) -> Self {
+        Message {
+            topic: topic.into(),
+            payload: payload.into(),
+            qos,
+            retained: false,
+            user_properties: Vec::new(),
+        }
+    }
+
+    pub fn new_retained(topic: impl Into<String>, payload: impl Into<Bytes>, qos: i32) -> Self {
+        Message {
+            retained: true,
+            ..Message::new(topic, payload, qos)
+        }
+    }
+
+    pub fn with_user_properties(mut self, user_properties: Vec<(String, String)>) -> Self {
+        self.user_properties = user_properties;
+        self
+    }
+
+    pub fn with_payload(mut self, payload: impl Into<Bytes>) -> Self {
+        self.payload = payload.into();
+        self
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn payload_bytes(&self) -> Bytes {
+        self.payload.clone()
+    }
+
+    pub fn payload_str(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.payload)
+    }
+
+    pub fn qos(&self) -> i32 {
+        self.qos
+    }
+
+    pub fn retained(&self) -> bool {
+        self.retained
+    }
+
+    pub fn user_properties(&self) -> &[(String, String)] {
+        &self.user_properties
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.topic, self.payload_str())
+    }
+}
+
+// Incoming messages, `None` signals that the connection was lost
+pub type MessageStream = Pin<Box<dyn Stream<Item = Option<Message>> + Send>>;
+
+// The operations the bridge needs from an MQTT client library
+pub trait MqttClient: Clone + Send + Sync + 'static {
+    fn new(server_uri: &str, client_id: &str) -> Result<Self>;
+    fn server_uri(&self) -> String;
+    fn client_id(&self) -> String;
+    fn is_connected(&self) -> bool;
+    // Protocol version of the current or most recent connection
+    fn mqtt_version(&self) -> u32;
+    // Connects and returns the negotiated protocol version
+    fn connect(&self, config: &MqttConfig, v5: bool) -> impl Future<Output = Result<u32>> + Send;
+    fn reconnect(&self) -> impl Future<Output = Result<()>> + Send;
+    fn subscribe(&self, topic: &str, qos: i32) -> impl Future<Output = Result<()>> + Send;
+    fn publish(&self, msg: Message) -> impl Future<Output = Result<()>> + Send;
+    fn disconnect(&self) -> impl Future<Output = Result<()>> + Send;
+    fn get_stream(&mut self, buffer: usize) -> MessageStream;
+}
+
+// The client of the MQTT backend, or one of a MemoryBroker for memory:// URIs
+#[derive(Clone)]
+pub enum Client {
+    Backend(BackendClient),
+    #[cfg(any(test, feature = "testing"))]
+    Memory(MemoryClient),
+}
+
+impl MqttClient for Client {
+    fn new(server_uri: &str, client_id: &str) -> Result<Self> {
+        if server_uri.starts_with(MEMORY_SCHEME) {
+            #[cfg(any(test, feature = "testing"))]
+            return Ok(Client::Memory(MemoryClient::new(server_uri, client_id)?));
+            #[cfg(not(any(test, feature = "testing")))]
+            return Err(memory_unavailable());
+        }
+        Ok(Client::Backend(BackendClient::new(server_uri, client_id)?))
+    }
+
+    fn server_uri(&self) -> String {
+        match self {
+            Client::Backend(client) => client.server_uri(),
+            #[cfg(any(test, feature = "testing"))]
+            Client::Memory(client) => client.server_uri(),
+        }
+    }
+
+    fn client_id(&self) -> String {
+        match self {
+            Client::Backend(client) => client.client_id(),
+            #[cfg(any(test, feature = "testing"))]
+            Client::Memory(client) => client.client_id(),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        match self {
+            Client::Backend(client) => client.is_connected(),
+            #[cfg(any(test, feature = "testing"))]
+            Client::Memory(client) => client.is_connected(),
+        }
+    }
+
+    fn mqtt_version(&self) -> u32 {
+        match self {
+            Client::Backend(client) => client.mqtt_version(),
+            #[cfg(any(test, feature = "testing"))]
+            Client::Memory(client) => client.mqtt_version(),
+        }
+    }
+
+    async fn connect(&self, config: &MqttConfig, v5: bool) -> Result<u32> {
+        match self {
+            Client::Backend(client) => client.connect(config, v5).await,
+            #[cfg(any(test, feature = "testing"))]
+            Client::Memory(client) => client.connect(config, v5).await,
+        }
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        match self {
+            Client::Backend(client) => client.reconnect().await,
+            #[cfg(any(test, feature = "testing"))]
+            Client::Memory(client) => client.reconnect().await,
+        }
+    }
+
+    async fn subscribe(&self, topic: &str, qos: i32) -> Result<()> {
+        match self {
+            Client::Backend(client) => client.subscribe(topic, qos).await,
+            #[cfg(any(test, feature = "testing"))]
+            Client::Memory(client) => client.subscribe(topic, qos).await,
+        }
+    }
+
+    async fn publish(&self, msg: Message) -> Result<()> {
+        match self {
+            Client::Backend(client) => client.publish(msg).await,
+            #[cfg(any(test, feature = "testing"))]
+            Client::Memory(client) => client.publish(msg).await,
+        }
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        match self {
+            Client::Backend(client) => client.disconnect().await,
+            #[cfg(any(test, feature = "testing"))]
+            Client::Memory(client) => client.disconnect().await,
+        }
+    }
+
+    fn get_stream(&mut self, buffer: usize) -> MessageStream {
+        match self {
+            Client::Backend(client) => client.get_stream(buffer),
+            #[cfg(any(test, feature = "testing"))]
+            Client::Memory(client) => client.get_stream(buffer),
+        }
+    }
+}
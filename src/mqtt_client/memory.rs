@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use super::{Error, Message, MessageStream, MqttClient, MqttConfig, Result, MEMORY_SCHEME};
+use crate::availability;
+
+// One client as the broker sees it
+#[derive(Default)]
+struct ClientState {
+    connected: bool,
+    version: u32,
+    // The stream of the last get_stream, None on it means the connection was lost
+    stream: Option<mpsc::UnboundedSender<Option<Message>>>,
+    // What arrived before the first get_stream, client libraries buffer it too
+    backlog: Vec<Message>,
+}
+
+impl ClientState {
+    fn deliver(&mut self, msg: Message) {
+        if !self.connected {
+            return;
+        }
+        match &self.stream {
+            Some(stream) => {
+                let _ = stream.send(Some(msg));
+            }
+            None => self.backlog.push(msg),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Broker {
+    clients: HashMap<u64, (Arc<Mutex<ClientState>>, Message)>,
+    subscriptions: Vec<(u64, String)>,
+    observers: Vec<(String, mpsc::UnboundedSender<Message>)>,
+    retained: BTreeMap<String, Message>,
+}
+
+static BROKERS: Mutex<Option<HashMap<String, Arc<Mutex<Broker>>>>> = Mutex::new(None);
+static NEXT_CLIENT: AtomicU64 = AtomicU64::new(1);
+
+// Topic filters with the + and # wildcards
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match part {
+            "#" => return true,
+            "+" if levels.next().is_some() => {}
+            part if part != "+" && levels.next() == Some(part) => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+impl Broker {
+    fn route(&mut self, msg: Message) {
+        if msg.retained() {
+            if msg.payload().is_empty() {
+                self.retained.remove(msg.topic());
+            } else {
+                self.retained.insert(msg.topic().to_string(), msg.clone());
+            }
+        }
+        for (id, filter) in &self.subscriptions {
+            if topic_matches(filter, msg.topic())
+                && let Some((client, _)) = self.clients.get(id)
+            {
+                client.lock().unwrap().deliver(msg.clone());
+            }
+        }
+        self.observers.retain(|(filter, tx)| !topic_matches(filter, msg.topic()) || tx.send(msg.clone()).is_ok());
+    }
+
+    fn retained_for(&self, filter: &str) -> Vec<Message> {
+        self.retained.values().filter(|msg| topic_matches(filter, msg.topic())).cloned().collect()
+    }
+}
+
+// A broker inside the process, for tests and tools embedding the bridge. Clients
+// connect to memory://<name> while it exists. Sessions are always clean.
+pub struct MemoryBroker {
+    name: String,
+    broker: Arc<Mutex<Broker>>,
+}
+
+impl MemoryBroker {
+    pub fn bind(name: &str) -> Result<Self> {
+        let mut brokers = BROKERS.lock().unwrap();
+        let brokers = brokers.get_or_insert_with(HashMap::new);
+        if brokers.contains_key(name) {
+            return Err(Error(format!("memory broker '{}' already exists", name)));
+        }
+        let broker = Arc::new(Mutex::new(Broker::default()));
+        brokers.insert(name.to_string(), broker.clone());
+        Ok(MemoryBroker { name: name.to_string(), broker })
+    }
+
+    // Publishes as another client would, e.g. a command for the bridge
+    pub fn publish(&self, msg: Message) {
+        self.broker.lock().unwrap().route(msg);
+    }
+
+    // Everything published on matching topics from now on, retained messages first
+    pub fn subscribe(&self, filter: &str) -> mpsc::UnboundedReceiver<Message> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut broker = self.broker.lock().unwrap();
+        for msg in broker.retained_for(filter) {
+            let _ = tx.send(msg);
+        }
+        broker.observers.push((filter.to_string(), tx));
+        rx
+    }
+
+    pub fn retained(&self, topic: &str) -> Option<Message> {
+        self.broker.lock().unwrap().retained.get(topic).cloned()
+    }
+
+    // Drops every client connection as a broker restart would, their last wills are published
+    pub fn drop_connections(&self) {
+        let mut broker = self.broker.lock().unwrap();
+        let clients: Vec<_> = broker.clients.drain().map(|(_, client)| client).collect();
+        broker.subscriptions.clear();
+        for (client, will) in clients {
+            let mut client = client.lock().unwrap();
+            client.connected = false;
+            if let Some(stream) = &client.stream {
+                let _ = stream.send(None);
+            }
+            drop(client);
+            broker.route(will);
+        }
+    }
+}
+
+impl Drop for MemoryBroker {
+    fn drop(&mut self) {
+        if let Some(brokers) = BROKERS.lock().unwrap().as_mut() {
+            brokers.remove(&self.name);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MemoryClient {
+    name: String,
+    client_id: String,
+    id: u64,
+    state: Arc<Mutex<ClientState>>,
+}
+
+impl MemoryClient {
+    fn broker(&self) -> Result<Arc<Mutex<Broker>>> {
+        let brokers = BROKERS.lock().unwrap();
+        let broker = brokers.as_ref().and_then(|brokers| brokers.get(&self.name));
+        broker.cloned().ok_or_else(|| Error(format!("no memory broker '{}'", self.name)))
+    }
+
+    fn connected_broker(&self) -> Result<Arc<Mutex<Broker>>> {
+        if !self.state.lock().unwrap().connected {
+            return Err(Error("not connected".to_string()));
+        }
+        self.broker()
+    }
+
+    fn attach(&self, version: u32) -> Result<u32> {
+        let broker = self.broker()?;
+        let mut state = self.state.lock().unwrap();
+        state.connected = true;
+        state.version = version;
+        drop(state);
+        broker.lock().unwrap().clients.insert(self.id, (self.state.clone(), availability::last_will()));
+        Ok(version)
+    }
+}
+
+impl MqttClient for MemoryClient {
+    fn new(server_uri: &str, client_id: &str) -> Result<Self> {
+        let name = server_uri
+            .strip_prefix(MEMORY_SCHEME)
+            .ok_or_else(|| Error(format!("'{}' is not a {} URI", server_uri, MEMORY_SCHEME)))?;
+        Ok(MemoryClient {
+            name: name.to_string(),
+            client_id: client_id.to_string(),
+            id: NEXT_CLIENT.fetch_add(1, Ordering::Relaxed),
+            state: Arc::default(),
+        })
+    }
+
+    fn server_uri(&self) -> String {
+        format!("{}{}", MEMORY_SCHEME, self.name)
+    }
+
+    fn client_id(&self) -> String {
+        self.client_id.clone()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
+    }
+
+    fn mqtt_version(&self) -> u32 {
+        self.state.lock().unwrap().version
+    }
+
+    async fn connect(&self, _config: &MqttConfig, v5: bool) -> Result<u32> {
+        self.attach(if v5 { 5 } else { 4 })
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        let version = self.state.lock().unwrap().version;
+        self.attach(version).map(|_| ())
+    }
+
+    async fn subscribe(&self, topic: &str, _qos: i32) -> Result<()> {
+        let broker = self.connected_broker()?;
+        let mut broker = broker.lock().unwrap();
+        broker.subscriptions.push((self.id, topic.to_string()));
+        let mut state = self.state.lock().unwrap();
+        for msg in broker.retained_for(topic) {
+            state.deliver(msg);
+        }
+        Ok(())
+    }
+
+    async fn publish(&self, msg: Message) -> Result<()> {
+        self.connected_broker()?.lock().unwrap().route(msg);
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        let broker = self.connected_broker()?;
+        let mut broker = broker.lock().unwrap();
+        broker.clients.remove(&self.id);
+        broker.subscriptions.retain(|(id, _)| *id != self.id);
+        self.state.lock().unwrap().connected = false;
+        Ok(())
+    }
+
+    fn get_stream(&mut self, _buffer: usize) -> MessageStream {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut state = self.state.lock().unwrap();
+        for msg in state.backlog.drain(..) {
+            let _ = tx.send(Some(msg));
+        }
+        state.stream = Some(tx);
+        Box::pin(UnboundedReceiverStream::new(rx))
+    }
+}
@@ -0,0 +1,163 @@
+use bytes::Bytes;
+use paho_mqtt as mqtt;
+use tokio_stream::StreamExt;
+
+use super::{is_ssl_uri, Error, Message, MessageStream, MqttClient, MqttConfig, Result};
+use crate::availability;
+
+#[derive(Clone)]
+pub struct PahoClient {
+    client: mqtt::AsyncClient,
+}
+
+fn to_error(e: mqtt::Error) -> Error {
+    Error(e.to_string())
+}
+
+fn to_paho_message(msg: Message) -> mqtt::Message {
+    let mut props = mqtt::Properties::new();
+    for (key, value) in msg.user_properties() {
+        let _ = props.push_string_pair(mqtt::PropertyCode::UserProperty, key, value);
+    }
+    mqtt::MessageBuilder::new()
+        .topic(msg.topic())
+        .payload(msg.payload())
+        .qos(msg.qos())
+        .retained(msg.retained())
+        .properties(props)
+        .finalize()
+}
+
+fn from_paho_message(msg: &mqtt::Message) -> Message {
+    let mut user_properties = Vec::new();
+    for (key, value) in msg.properties().user_iter() {
+        user_properties.push((key, value));
+    }
+    let converted = if msg.retained() {
+        Message::new_retained(msg.topic(), Bytes::copy_from_slice(msg.payload()), msg.qos())
+    } else {
+        Message::new(msg.topic(), Bytes::copy_from_slice(msg.payload()), msg.qos())
+    };
+    converted.with_user_properties(user_properties)
+}
+
+fn ssl_options(config: &MqttConfig) -> mqtt::Result<mqtt::SslOptions> {
+    let mut ssl_builder = mqtt::SslOptionsBuilder::new();
+    if let Some(ca_cert) = &config.ca_cert {
+        ssl_builder.trust_store(ca_cert)?;
+    }
+    if let Some(client_cert) = &config.client_cert {
+        ssl_builder.key_store(client_cert)?;
+    }
+    if let Some(client_key) = &config.client_key {
+        ssl_builder.private_key(client_key)?;
+    }
+    if config.insecure {
+        ssl_builder
+            .enable_server_cert_auth(false)
+            .verify(false);
+    }
+    Ok(ssl_builder.finalize())
+}
+
+// Loads the TLS files of an ssl:// broker, so a missing or bad one fails at startup
+pub fn check_options(config: &MqttConfig, server_uri: &str) -> Result<()> {
+    if is_ssl_uri(server_uri) {
+        ssl_options(config).map_err(|e| Error(format!("Error loading the MQTT TLS certificates: {}", e)))?;
+    }
+    Ok(())
+}
+
+fn connect_options(config: &MqttConfig, server_uri: &str, v5: bool) -> Result<mqtt::ConnectOptions> {
+    let clean = !config.persistent_session;
+    let mut conn_builder = if v5 {
+        let mut builder = mqtt::ConnectOptionsBuilder::new_v5();
+        builder.clean_start(clean);
+        if config.persistent_session {
+            let mut props = mqtt::Properties::new();
+            let _ = props.push_u32(mqtt::PropertyCode::SessionExpiryInterval, config.session_expiry);
+            builder.properties(props);
+        }
+        builder
+    } else {
+        let mut builder = mqtt::ConnectOptionsBuilder::new();
+        builder.clean_session(clean);
+        builder
+    };
+    conn_builder
+        .keep_alive_interval(config.keep_alive)
+        .connect_timeout(config.connect_timeout)
+        .max_inflight(config.max_inflight)
+        .will_message(to_paho_message(availability::last_will()));
+    if let Some(user) = &config.user {
+        conn_builder.user_name(user);
+    }
+    if let Some(password) = &config.password {
+        conn_builder.password(password);
+    }
+    if is_ssl_uri(server_uri) {
+        let ssl_opts = ssl_options(config).map_err(|e| Error(format!("Error loading the MQTT TLS certificates: {}", e)))?;
+        conn_builder.ssl_options(ssl_opts);
+    }
+    Ok(conn_builder.finalize())
+}
+
+impl MqttClient for PahoClient {
+    fn new(server_uri: &str, client_id: &str) -> Result<Self> {
+        let create_opts = mqtt::CreateOptionsBuilder::new()
+            .server_uri(server_uri)
+            .client_id(client_id)
+            .finalize();
+        let client = mqtt::AsyncClient::new(create_opts).map_err(to_error)?;
+        Ok(PahoClient { client })
+    }
+
+    fn server_uri(&self) -> String {
+        self.client.server_uri()
+    }
+
+    fn client_id(&self) -> String {
+        self.client.client_id()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
+    fn mqtt_version(&self) -> u32 {
+        self.client.mqtt_version()
+    }
+
+    async fn connect(&self, config: &MqttConfig, v5: bool) -> Result<u32> {
+        let conn_opts = connect_options(config, &self.client.server_uri(), v5)?;
+        let response = self.client.connect(conn_opts).await.map_err(to_error)?;
+        response
+            .connect_response()
+            .map(|response| response.mqtt_version)
+            .ok_or_else(|| Error("Bad connect response".to_string()))
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        self.client.reconnect().await.map(|_| ()).map_err(to_error)
+    }
+
+    async fn subscribe(&self, topic: &str, qos: i32) -> Result<()> {
+        let rsp = self.client.subscribe(topic, qos).await.map_err(to_error)?;
+        rsp.subscribe_response()
+            .map(|_| ())
+            .ok_or_else(|| Error("Bad subscribe response".to_string()))
+    }
+
+    async fn publish(&self, msg: Message) -> Result<()> {
+        self.client.publish(to_paho_message(msg)).await.map_err(to_error)
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.client.disconnect(None).await.map(|_| ()).map_err(to_error)
+    }
+
+    fn get_stream(&mut self, buffer: usize) -> MessageStream {
+        let stream = self.client.get_stream(buffer);
+        Box::pin(stream.map(|msg| msg.as_ref().map(from_paho_message)))
+    }
+}
@@ -0,0 +1,333 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{timeout, Duration};
+use tokio_stream::wrappers::ReceiverStream;
+
+use super::{is_ssl_uri, Error, Message, MessageStream, MqttClient, MqttConfig, Result};
+use crate::{availability, context};
+use crate::uds_proxy::UNIX_SCHEME;
+
+// rumqttc speaks MQTT 3.1.1 here, matching the paho version numbering
+const MQTT_VERSION_3_1_1: u32 = 4;
+
+#[derive(Clone)]
+pub struct RumqttClient {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    server_uri: String,
+    client_id: String,
+    client: Mutex<Option<AsyncClient>>,
+    connected: AtomicBool,
+    connect_timeout: Mutex<Duration>,
+    // Outcome of each connection attempt made by the event loop task
+    conn_rx: tokio::sync::Mutex<Option<mpsc::Receiver<Result<()>>>>,
+    // The stream of the last get_stream, None on it means the connection was lost
+    incoming_tx: Mutex<Option<mpsc::Sender<Option<Message>>>>,
+    // Made with the client, so messages arriving before the first get_stream aren't lost
+    first_rx: Mutex<Option<mpsc::Receiver<Option<Message>>>>,
+    // MQTT v5 is asked for on every connect, the missing support is logged once
+    v5_warned: AtomicBool,
+    // The event loop task parks after a failure until the manager asks for a reconnect
+    retry: Notify,
+}
+
+fn to_qos(qos: i32) -> QoS {
+    match qos {
+        2 => QoS::ExactlyOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::AtMostOnce,
+    }
+}
+
+fn from_qos(qos: QoS) -> i32 {
+    match qos {
+        QoS::AtMostOnce => 0,
+        QoS::AtLeastOnce => 1,
+        QoS::ExactlyOnce => 2,
+    }
+}
+
+fn is_websocket_uri(uri: &str) -> bool {
+    uri.starts_with("ws://") || uri.starts_with("wss://")
+}
+
+// Splits scheme://host:port/path into scheme, host and port
+fn parse_uri(uri: &str) -> Result<(String, String, u16)> {
+    let (scheme, rest) = uri.split_once("://").unwrap_or(("tcp", uri));
+    let authority = rest.split('/').next().unwrap_or(rest);
+    let default_port = match scheme {
+        "ws" => 80,
+        "wss" => 443,
+        _ if is_ssl_uri(uri) => 8883,
+        _ => 1883,
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| Error(format!("Bad port in broker URI '{}'", uri)))?;
+            (host, port)
+        }
+        None => (authority, default_port),
+    };
+    Ok((scheme.to_string(), host.to_string(), port))
+}
+
+// Builds the options once, so a missing TLS file or an unsupported option fails at startup
+pub fn check_options(config: &MqttConfig, server_uri: &str) -> Result<()> {
+    mqtt_options(config, server_uri, "").map(|_| ())
+}
+
+fn mqtt_options(config: &MqttConfig, server_uri: &str, client_id: &str) -> Result<MqttOptions> {
+    let (host, port) = if let Some(path) = server_uri.strip_prefix(UNIX_SCHEME) {
+        // The unix transport takes the socket path as host
+        (path.to_string(), 0)
+    } else {
+        let (_scheme, host, port) = parse_uri(server_uri)?;
+        // Websocket transports take the whole URL as host
+        let host = if is_websocket_uri(server_uri) { server_uri.to_string() } else { host };
+        (host, port)
+    };
+    let mut options = MqttOptions::new(client_id, host, port);
+    options
+        .set_keep_alive(config.keep_alive)
+        .set_clean_session(!config.persistent_session)
+        .set_inflight(config.max_inflight.clamp(1, u16::MAX as i32) as u16);
+    let will = availability::last_will();
+    options.set_last_will(LastWill::new(
+        will.topic(),
+        will.payload().to_vec(),
+        to_qos(will.qos()),
+        will.retained(),
+    ));
+    if let Some(user) = &config.user {
+        options.set_credentials(user, config.password.clone().unwrap_or_default());
+    }
+    set_transport(&mut options, config, server_uri)?;
+    Ok(options)
+}
+
+#[cfg_attr(not(feature = "ssl"), allow(unused_variables))]
+fn set_transport(options: &mut MqttOptions, config: &MqttConfig, server_uri: &str) -> Result<()> {
+    use rumqttc::Transport;
+
+    if server_uri.starts_with(UNIX_SCHEME) {
+        options.set_transport(Transport::Unix);
+        return Ok(());
+    }
+    #[allow(unreachable_patterns)]
+    match (is_websocket_uri(server_uri), is_ssl_uri(server_uri)) {
+        (false, false) => {}
+        #[cfg(feature = "ssl")]
+        (false, true) => {
+            options.set_transport(Transport::Tls(tls_configuration(config)?));
+        }
+        #[cfg(feature = "websocket")]
+        (true, false) => {
+            options.set_transport(Transport::Ws);
+        }
+        #[cfg(all(feature = "ssl", feature = "websocket"))]
+        (true, true) => {
+            options.set_transport(Transport::Wss(tls_configuration(config)?));
+        }
+        _ => {
+            return Err(Error(format!(
+                "'{}' needs a build with the \"ssl\" and/or \"websocket\" features",
+                server_uri
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "ssl")]
+fn tls_configuration(config: &MqttConfig) -> Result<rumqttc::TlsConfiguration> {
+    if config.insecure {
+        return Err(Error("--insecure is not supported by the rumqttc backend".to_string()));
+    }
+    let read = |path: &String| std::fs::read(path).map_err(|e| Error(format!("{}: {}", path, e)));
+    let client_auth = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        _ => None,
+    };
+    match (&config.ca_cert, client_auth) {
+        (Some(path), client_auth) => Ok(rumqttc::TlsConfiguration::Simple {
+            ca: read(path)?,
+            alpn: None,
+            client_auth,
+        }),
+        // Without a CA the broker is verified against the system certificates
+        (None, None) => Ok(rumqttc::TlsConfiguration::default()),
+        (None, Some(_)) => Err(Error(
+            "The rumqttc backend needs --mqtt-ca-cert with a client certificate".to_string(),
+        )),
+    }
+}
+
+// Hands a message to the stream of the last get_stream, if there is one
+async fn deliver(inner: &Inner, msg: Option<Message>) {
+    let incoming_tx = inner.incoming_tx.lock().unwrap().clone();
+    if let Some(incoming_tx) = incoming_tx {
+        let _ = incoming_tx.send(msg).await;
+    }
+}
+
+async fn event_loop(inner: Arc<Inner>, mut eventloop: EventLoop, conn_tx: mpsc::Sender<Result<()>>) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                inner.connected.store(true, Ordering::SeqCst);
+                let _ = conn_tx.send(Ok(())).await;
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let msg = if publish.retain {
+                    Message::new_retained(publish.topic, publish.payload, from_qos(publish.qos))
+                } else {
+                    Message::new(publish.topic, publish.payload, from_qos(publish.qos))
+                };
+                deliver(&inner, Some(msg)).await;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                debug!("MQTT event loop error: {:?}", e);
+                if inner.connected.swap(false, Ordering::SeqCst) {
+                    deliver(&inner, None).await;
+                } else {
+                    let _ = conn_tx.send(Err(Error(e.to_string()))).await;
+                }
+                inner.retry.notified().await;
+            }
+        }
+    }
+}
+
+impl RumqttClient {
+    fn client(&self) -> Result<AsyncClient> {
+        self.inner
+            .client
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| Error("Not connected".to_string()))
+    }
+
+    async fn wait_connection(&self) -> Result<()> {
+        let connect_timeout = *self.inner.connect_timeout.lock().unwrap();
+        let mut conn_rx = self.inner.conn_rx.lock().await;
+        let Some(conn_rx) = conn_rx.as_mut() else {
+            return Err(Error("Not connected".to_string()));
+        };
+        match timeout(connect_timeout, conn_rx.recv()).await {
+            Ok(Some(result)) => result,
+            Ok(None) => Err(Error("MQTT event loop stopped".to_string())),
+            Err(_) => Err(Error("Connect timeout".to_string())),
+        }
+    }
+}
+
+impl MqttClient for RumqttClient {
+    fn new(server_uri: &str, client_id: &str) -> Result<Self> {
+        if !server_uri.starts_with(UNIX_SCHEME) {
+            parse_uri(server_uri)?;
+        }
+        let (incoming_tx, first_rx) = mpsc::channel(64);
+        Ok(RumqttClient {
+            inner: Arc::new(Inner {
+                server_uri: server_uri.to_string(),
+                client_id: client_id.to_string(),
+                client: Mutex::new(None),
+                connected: AtomicBool::new(false),
+                connect_timeout: Mutex::new(Duration::from_secs(30)),
+                conn_rx: tokio::sync::Mutex::new(None),
+                incoming_tx: Mutex::new(Some(incoming_tx)),
+                first_rx: Mutex::new(Some(first_rx)),
+                v5_warned: AtomicBool::new(false),
+                retry: Notify::new(),
+            }),
+        })
+    }
+
+    fn server_uri(&self) -> String {
+        self.inner.server_uri.clone()
+    }
+
+    fn client_id(&self) -> String {
+        self.inner.client_id.clone()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.connected.load(Ordering::SeqCst)
+    }
+
+    fn mqtt_version(&self) -> u32 {
+        MQTT_VERSION_3_1_1
+    }
+
+    async fn connect(&self, config: &MqttConfig, v5: bool) -> Result<u32> {
+        *self.inner.connect_timeout.lock().unwrap() = config.connect_timeout;
+        if v5 && !self.inner.v5_warned.swap(true, Ordering::SeqCst) {
+            warn!(
+                "The rumqttc backend only speaks MQTT 3.1.1: acks carry no user properties{}",
+                if config.persistent_session { " and --session-expiry is ignored" } else { "" }
+            );
+        }
+        // The event loop keeps its options, later attempts just wake it up
+        if self.inner.client.lock().unwrap().is_some() {
+            self.reconnect().await?;
+            return Ok(MQTT_VERSION_3_1_1);
+        }
+
+        let options = mqtt_options(config, &self.inner.server_uri, &self.inner.client_id)?;
+        let (client, eventloop) = AsyncClient::new(options, 64);
+        let (conn_tx, conn_rx) = mpsc::channel(1);
+        *self.inner.client.lock().unwrap() = Some(client);
+        *self.inner.conn_rx.lock().await = Some(conn_rx);
+        context::spawn(event_loop(self.inner.clone(), eventloop, conn_tx));
+
+        self.wait_connection().await?;
+        Ok(MQTT_VERSION_3_1_1)
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        self.inner.retry.notify_one();
+        self.wait_connection().await
+    }
+
+    async fn subscribe(&self, topic: &str, qos: i32) -> Result<()> {
+        self.client()?
+            .try_subscribe(topic, to_qos(qos))
+            .map_err(|e| Error(e.to_string()))
+    }
+
+    async fn publish(&self, msg: Message) -> Result<()> {
+        // try_publish never blocks on a stalled event loop. Once rumqttc has taken the
+        // message it keeps it and sends it again after a reconnect, so that counts as
+        // published and the caller mustn't queue it. Only a message it handed back, with
+        // the request channel full, fails and goes to the caller's queue. Routing only adds
+        // user properties on v5 connections, so none get lost here.
+        self.client()?
+            .try_publish(msg.topic(), to_qos(msg.qos()), msg.retained(), msg.payload().to_vec())
+            .map_err(|e| Error(e.to_string()))
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.client()?.try_disconnect().map_err(|e| Error(e.to_string()))
+    }
+
+    // Every later call gets a new stream and ends the one before, so the manager
+    // loop and a restarted manager keep receiving commands
+    fn get_stream(&mut self, buffer: usize) -> MessageStream {
+        if let Some(first_rx) = self.inner.first_rx.lock().unwrap().take() {
+            return Box::pin(ReceiverStream::new(first_rx));
+        }
+        let (incoming_tx, incoming_rx) = mpsc::channel(buffer.max(1));
+        *self.inner.incoming_tx.lock().unwrap() = Some(incoming_tx);
+        Box::pin(ReceiverStream::new(incoming_rx))
+    }
+}
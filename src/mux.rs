@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+use tokio_seqpacket::{UnixSeqpacket, UnixSeqpacketListener};
+
+use crate::agent_socket::AgentTransport;
+use crate::command;
+use crate::command_channel::{CommandSender, SendError};
+use crate::context;
+
+struct MuxClient {
+    tx: mpsc::Sender<Vec<u8>>,
+    // Agent keys the client registered for, it gets a copy of those frames
+    keys: Vec<String>,
+}
+
+// A forwarded command waiting for its reply
+struct Route {
+    client: u32,
+    // The id the client used
+    client_id: Value,
+    sent: Instant,
+}
+
+// Lets other processes on the hub share the bridge's agent connection. Their
+// commands get bridge ids on the way out, replies are matched by id and get
+// the client's own id back before they are handed to the client.
+#[derive(Clone)]
+pub struct Mux {
+    clients: Arc<Mutex<HashMap<u32, MuxClient>>>,
+    // Bridge id of a forwarded command -> where its reply goes
+    routes: Arc<Mutex<HashMap<u64, Route>>>,
+    next_client: Arc<AtomicU32>,
+    // The bridge's bind address, stamped on the forwarded commands
+    address: Arc<AtomicU32>,
+    // Registered by the bridge itself, the agent already sends these
+    bridge_keys: Arc<Vec<String>>,
+}
+
+impl Mux {
+    pub fn new(address: Arc<AtomicU32>, bridge_keys: Vec<String>) -> Self {
+        Mux {
+            clients: Arc::default(),
+            routes: Arc::default(),
+            next_client: Arc::default(),
+            address,
+            bridge_keys: Arc::new(bridge_keys),
+        }
+    }
+
+    // Keys only clients registered, registered again when the agent socket reconnects
+    pub fn keys(&self) -> Vec<String> {
+        let clients = self.clients.lock().unwrap();
+        let mut keys: Vec<String> = clients.values().flat_map(|client| client.keys.iter().cloned()).collect();
+        keys.sort();
+        keys.dedup();
+        keys.retain(|key| !self.bridge_keys.contains(key));
+        keys
+    }
+
+    fn is_held(clients: &HashMap<u32, MuxClient>, key: &str) -> bool {
+        clients.values().any(|client| client.keys.iter().any(|k| k == key))
+    }
+
+    // Gives up on commands unanswered for longer than the timeout, their clients get a timeout error
+    pub fn expire(&self, timeout: Duration) {
+        let mut expired = Vec::new();
+        self.routes.lock().unwrap().retain(|_, route| {
+            if route.sent.elapsed() <= timeout {
+                return true;
+            }
+            expired.push((route.client, route.client_id.clone()));
+            false
+        });
+        for (client, client_id) in expired {
+            debug!("Mux client {}: no reply to command {} within {:?}", client, client_id, timeout);
+            self.send(client, command::error_ack(Some(client_id), command::ERROR_TIMEOUT, "command timed out").into_bytes());
+        }
+    }
+
+    // Hands an agent frame to the clients. Returns true when it was the reply
+    // to a client's command and must not go anywhere else. Frames addressed to
+    // another bind address are never taken as replies.
+    pub fn deliver(&self, frame: &[u8], document: &Value, addressed: Option<bool>) -> bool {
+        if addressed != Some(false)
+            && let Some(id) = document.get("id").and_then(Value::as_u64)
+        {
+            let route = self.routes.lock().unwrap().remove(&id);
+            if let Some(route) = route {
+                let mut reply = document.clone();
+                reply["id"] = route.client_id;
+                self.send(route.client, reply.to_string().into_bytes());
+                return true;
+            }
+        }
+        if let Some(key) = document.get("key").and_then(Value::as_str) {
+            let clients = self.clients.lock().unwrap();
+            for client in clients.values().filter(|client| client.keys.iter().any(|k| k == key)) {
+                let _ = client.tx.try_send(frame.to_vec());
+            }
+        }
+        false
+    }
+
+    fn send(&self, client: u32, frame: Vec<u8>) {
+        if let Some(client) = self.clients.lock().unwrap().get(&client)
+            && client.tx.try_send(frame).is_err()
+        {
+            warn!("Mux client too slow, dropping frame");
+        }
+    }
+
+    // Turns a frame from a client into what goes to the agent, None when the
+    // bridge handles it itself. bind is answered here since the bridge holds the
+    // only real binding. register goes to the agent for keys nobody registered yet.
+    fn translate(&self, client: u32, frame: &[u8]) -> Option<Vec<u8>> {
+        let Ok(Value::Object(mut map)) = serde_json::from_slice::<Value>(frame) else {
+            return Some(frame.to_vec());
+        };
+        match map.get("method").and_then(Value::as_str) {
+            Some("bind") => {
+                let mut reply = json!({ "method": "bind", "result": "ok" });
+                for field in ["id", "address"] {
+                    if let Some(value) = map.get(field) {
+                        reply[field] = value.clone();
+                    }
+                }
+                self.send(client, reply.to_string().into_bytes());
+                return None;
+            }
+            Some("register") => {
+                let key = map.get("key").and_then(Value::as_str)?;
+                let mut clients = self.clients.lock().unwrap();
+                let forward = !Self::is_held(&clients, key) && !self.bridge_keys.iter().any(|k| k == key);
+                if let Some(client) = clients.get_mut(&client)
+                    && !client.keys.iter().any(|k| k == key)
+                {
+                    client.keys.push(key.to_string());
+                }
+                return forward.then(|| frame.to_vec());
+            }
+            _ => {}
+        }
+        map.insert("_from".to_string(), Value::from(self.address.load(Ordering::Relaxed)));
+        if let Some(client_id) = map.remove("id") {
+            let id = command::next_id();
+            self.routes.lock().unwrap().insert(id, Route { client, client_id, sent: Instant::now() });
+            map.insert("id".to_string(), Value::from(id));
+        }
+        Some(Value::Object(map).to_string().into_bytes())
+    }
+
+    // Forgets the client, returns the unregisters for keys no one else holds
+    fn remove(&self, client: u32) -> Vec<Vec<u8>> {
+        let mut clients = self.clients.lock().unwrap();
+        let keys = clients.remove(&client).map(|client| client.keys).unwrap_or_default();
+        self.routes.lock().unwrap().retain(|_, route| route.client != client);
+        keys.into_iter()
+            .filter(|key| !Self::is_held(&clients, key) && !self.bridge_keys.contains(key))
+            .map(|key| json!({ "key": key, "method": "unregister" }).to_string().into_bytes())
+            .collect()
+    }
+}
+
+async fn serve_client(mux: Mux, client: u32, mut socket: UnixSeqpacket, mut rx: mpsc::Receiver<Vec<u8>>, agent_tx: CommandSender) {
+    let mut buf = BytesMut::zeroed(4096);
+    loop {
+        tokio::select! {
+            frame = rx.recv() => {
+                let Some(frame) = frame else { break };
+                if AgentTransport::send(&mut socket, &frame).await.is_err() {
+                    break;
+                }
+            }
+            res = AgentTransport::recv(&mut socket, &mut buf) => {
+                match res {
+                    Ok((n, _)) if n > 0 => {
+                        debug!("mux client {}: '{}'", client, String::from_utf8_lossy(&buf[..n]));
+                        if let Some(frame) = mux.translate(client, &buf[..n]) {
+                            match agent_tx.send(frame).await {
+                                Ok(None) => {}
+                                // Tracked commands among them get their timeout ack
+                                Ok(Some(_)) | Err(SendError::Full) => warn!("Command queue full, dropped a command"),
+                                Err(SendError::Closed) => break,
+                            }
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        }
+    }
+    info!("Mux client {} disconnected", client);
+    for frame in mux.remove(client) {
+        let _ = agent_tx.send(frame).await;
+    }
+}
+
+pub async fn serve(path: String, mux: Mux, agent_tx: CommandSender) {
+    let _ = fs::remove_file(&path);
+    let mut listener = match UnixSeqpacketListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Error binding mux socket '{}': {:?}", path, e);
+            return;
+        }
+    };
+    info!("Mux socket listening at '{}'", path);
+    loop {
+        let socket = match listener.accept().await {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Error accepting mux client: {:?}", e);
+                continue;
+            }
+        };
+        let client = mux.next_client.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(64);
+        mux.clients.lock().unwrap().insert(client, MuxClient { tx, keys: Vec::new() });
+        info!("Mux client {} connected", client);
+        context::spawn(serve_client(mux.clone(), client, socket, rx, agent_tx.clone()));
+    }
+}
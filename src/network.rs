@@ -0,0 +1,74 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::context;
+use crate::mqtt_client::Message;
+use crate::state::TOPIC_DEVICE_PREFIX;
+use crate::topics;
+
+pub const TOPIC_NETWORK_MAP: &str = "aqara2mqtt/bridge/network_map";
+
+// Zigbee heartbeat resources of lumi devices
+const RES_LQI: &str = "8.0.2007";
+const RES_PARENT: &str = "8.0.2036";
+
+pub fn is_enabled() -> bool {
+    context::current().config.network_quality
+}
+
+#[derive(Clone, Default, PartialEq, Serialize)]
+pub struct Link {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lqi: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i64>,
+    // Router the device is attached to, the gateway when unknown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+}
+
+// Some firmwares put lqi/rssi next to the did instead of into a resource
+fn find_field(report: &Value, key: &str) -> Option<i64> {
+    report
+        .get(key)
+        .and_then(Value::as_i64)
+        .or_else(|| report.get("params").and_then(|params| find_field(params, key)))
+}
+
+// Link quality from a report and the merged device state, None if the device never reported any
+pub fn link(report: &Value, state: &Value) -> Option<Link> {
+    let link = Link {
+        lqi: find_field(report, "lqi").or_else(|| state.get(RES_LQI).and_then(Value::as_i64)),
+        rssi: find_field(report, "rssi"),
+        parent: state.get(RES_PARENT).and_then(Value::as_str).filter(|parent| !parent.is_empty()).map(String::from),
+    };
+    (link != Link::default()).then_some(link)
+}
+
+pub fn device_topic(did: &str) -> String {
+    topics::prefixed(&format!("{}/{}/link_quality", TOPIC_DEVICE_PREFIX, did))
+}
+
+pub fn device_message(did: &str, link: &Link) -> Message {
+    let payload = serde_json::to_string(link).unwrap_or_default();
+    Message::new_retained(device_topic(did), payload, 1)
+}
+
+// Nodes and edges for mesh graphs, devices without a known parent hang off the gateway
+pub fn map_message(links: &BTreeMap<String, Link>, models: &HashMap<String, String>) -> Message {
+    let nodes: Vec<Value> = links
+        .iter()
+        .map(|(did, link)| json!({ "did": did, "model": models.get(did), "lqi": link.lqi, "rssi": link.rssi }))
+        .collect();
+    let edges: Vec<Value> = links
+        .iter()
+        .map(|(did, link)| {
+            let target = link.parent.as_deref().unwrap_or("gateway");
+            json!({ "source": did, "target": target, "lqi": link.lqi })
+        })
+        .collect();
+    let payload = json!({ "nodes": nodes, "links": edges });
+    Message::new_retained(topics::prefixed(TOPIC_NETWORK_MAP), payload.to_string(), 1)
+}
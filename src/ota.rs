@@ -0,0 +1,58 @@
+use serde_json::{json, Map, Value};
+
+use crate::command;
+use crate::mqtt_client::Message;
+use crate::state::TOPIC_DEVICE_PREFIX;
+use crate::topics;
+
+// {"did":"lumi.158d0001","url":"http://..","md5":".."}, acked on the response topic
+pub const TOPIC_OTA_REQUEST: &str = "aqara2mqtt/bridge/request/ota";
+pub const TOPIC_OTA_RESPONSE: &str = "aqara2mqtt/bridge/response/ota";
+
+// Agent method updating a sub device from a firmware url
+const OTA_METHOD: &str = "miIO.subdev_ota";
+const PROGRESS_FIELDS: [&str; 2] = ["progress", "ota_progress"];
+
+fn string_field<'a>(request: &'a Map<String, Value>, field: &str) -> Result<&'a str, String> {
+    request
+        .get(field)
+        .and_then(Value::as_str)
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| format!("'{}' is required", field))
+}
+
+pub fn command(payload: &[u8], address: u32) -> Result<Vec<u8>, String> {
+    let request = match serde_json::from_slice::<Value>(payload) {
+        Ok(Value::Object(map)) => map,
+        Ok(_) => return Err("request must be a JSON object".to_string()),
+        Err(e) => return Err(format!("request is not valid JSON: {}", e)),
+    };
+    let did = string_field(&request, "did")?;
+    let url = string_field(&request, "url")?;
+    let md5 = string_field(&request, "md5")?;
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Err("'url' must be http:// or https://".to_string());
+    }
+    if md5.len() != 32 || !md5.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("'md5' must be 32 hex digits".to_string());
+    }
+    let mut params = json!({ "did": did, "url": url, "md5": md5.to_ascii_lowercase() });
+    if let Some(version) = request.get("version") {
+        params["version"] = version.clone();
+    }
+    let rpc = json!({ "id": command::next_id(), "method": OTA_METHOD, "params": params, "_from": address });
+    Ok(rpc.to_string().into_bytes())
+}
+
+// aqara2mqtt/<did>/ota_progress for frames reporting update progress of a device
+pub fn progress(report: &Value) -> Option<Message> {
+    let params = report.get("params")?.as_object()?;
+    let progress = PROGRESS_FIELDS.iter().find_map(|field| params.get(*field))?;
+    let did = params.get("did").or_else(|| params.get("sdid"))?.as_str()?;
+    let mut payload = json!({ "progress": progress });
+    if let Some(status) = params.get("status").or_else(|| params.get("state")) {
+        payload["status"] = status.clone();
+    }
+    let topic = topics::prefixed(&format!("{}/{}/ota_progress", TOPIC_DEVICE_PREFIX, did));
+    Some(Message::new(topic, payload.to_string(), 1))
+}
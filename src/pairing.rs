@@ -0,0 +1,101 @@
+use serde_json::{json, Value};
+
+use crate::command;
+use crate::mqtt_client::Message;
+use crate::topics;
+
+// {"time": 60} opens the network for 60 seconds, {"time": 0} or {"value": false} closes it
+pub const TOPIC_PERMIT_JOIN_REQUEST: &str = "aqara2mqtt/bridge/request/permit_join";
+pub const TOPIC_PERMIT_JOIN_RESPONSE: &str = "aqara2mqtt/bridge/response/permit_join";
+// Pairing progress, {"type":"permit_join"|"device_joined","data":{..}}
+pub const TOPIC_BRIDGE_EVENT: &str = "aqara2mqtt/bridge/event";
+
+// "<did or friendly name>", also accepted as {"did": ".."}
+pub const TOPIC_REMOVE_REQUEST: &str = "aqara2mqtt/bridge/request/remove";
+pub const TOPIC_REMOVE_RESPONSE: &str = "aqara2mqtt/bridge/response/remove";
+
+// The Zigbee coordinator of the hub and its pairing resources
+const GATEWAY_DID: &str = "lumi.0";
+const RES_PERMIT_JOIN: &str = "8.0.2111";
+const RES_REMOVE_DEVICE: &str = "8.0.2082";
+const RES_DEVICE_JOINED: &str = "8.0.2084";
+// Zigbee caps permit join at 254 seconds
+pub const MAX_PERMIT_JOIN: u64 = 254;
+pub const DEFAULT_PERMIT_JOIN: u64 = 60;
+
+// Seconds to keep the network open, 0 closes it
+pub fn parse_request(payload: &[u8]) -> Result<u64, String> {
+    let request: Value = serde_json::from_slice(payload).map_err(|e| format!("invalid request: {}", e))?;
+    let time = match &request {
+        Value::Number(time) => time.as_u64(),
+        Value::Bool(false) => Some(0),
+        Value::Bool(true) => Some(DEFAULT_PERMIT_JOIN),
+        Value::Object(map) => match (map.get("value"), map.get("time")) {
+            (Some(Value::Bool(false)), _) => Some(0),
+            (_, Some(time)) => time.as_u64(),
+            (Some(Value::Bool(true)), None) => Some(DEFAULT_PERMIT_JOIN),
+            _ => None,
+        },
+        _ => None,
+    };
+    time.map(|time| time.min(MAX_PERMIT_JOIN)).ok_or_else(|| format!("invalid permit join request '{}'", request))
+}
+
+// Writes a resource of the coordinator
+fn write_gateway(res_name: &str, value: Value, address: u32) -> Vec<u8> {
+    json!({
+        "id": command::next_id(),
+        "method": "write",
+        "params": { "did": GATEWAY_DID, "res_list": [{ "res_name": res_name, "value": value }] },
+        "_from": address,
+    })
+    .to_string()
+    .into_bytes()
+}
+
+pub fn command(seconds: u64, address: u32) -> Vec<u8> {
+    write_gateway(RES_PERMIT_JOIN, Value::from(seconds), address)
+}
+
+// Did or friendly name of the device to remove
+pub fn parse_remove_request(payload: &[u8]) -> Result<String, String> {
+    let did = match serde_json::from_slice::<Value>(payload) {
+        Ok(Value::String(did)) => did,
+        Ok(request) => request.get("did").and_then(Value::as_str).unwrap_or_default().to_string(),
+        // A bare did isn't valid JSON
+        Err(_) => String::from_utf8_lossy(payload).trim().to_string(),
+    };
+    if did.is_empty() {
+        return Err("missing did".to_string());
+    }
+    Ok(did)
+}
+
+pub fn remove_command(did: &str, address: u32) -> Vec<u8> {
+    write_gateway(RES_REMOVE_DEVICE, Value::from(did), address)
+}
+
+pub fn event(kind: &str, data: Value) -> Message {
+    let payload = json!({ "type": kind, "data": data });
+    Message::new(topics::prefixed(TOPIC_BRIDGE_EVENT), payload.to_string(), 1)
+}
+
+// The coordinator announces a device once its interview completed, the value
+// holds the device info, sometimes as a JSON string
+pub fn joined_device(report: &Value) -> Option<Value> {
+    match report {
+        Value::Array(items) => items.iter().find_map(joined_device),
+        Value::Object(map) => {
+            if map.get("res_name").and_then(Value::as_str) == Some(RES_DEVICE_JOINED) {
+                let info = match map.get("value")? {
+                    Value::String(info) => serde_json::from_str(info).ok()?,
+                    info => info.clone(),
+                };
+                let did = info.get("did")?.clone();
+                return Some(json!({ "did": did, "model": info.get("model") }));
+            }
+            map.values().filter(|value| value.is_object() || value.is_array()).find_map(joined_device)
+        }
+        _ => None,
+    }
+}
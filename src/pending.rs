@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// A command sent to the agent that has not been answered yet
+pub struct PendingCommand {
+    pub id: u64,
+    pub to: u64,
+    pub from: u64,
+    pub method: String,
+    pub payload: Vec<u8>,
+    // Sends so far, 1 for a command that was never retried
+    pub attempts: u32,
+    // Where the ack goes instead of miio/command_ack
+    pub reply_topic: Option<String>,
+    // The reply is merged into the device state instead of published as ack
+    pub refresh_state: bool,
+    sent: Instant,
+    // When the command came in, retries don't move it
+    received: Instant,
+}
+
+impl PendingCommand {
+    pub fn new(id: u64, to: u64, from: u64, method: String, payload: Vec<u8>) -> Self {
+        PendingCommand { id, to, from, method, payload, attempts: 1, reply_topic: None, refresh_state: false, sent: Instant::now(), received: Instant::now() }
+    }
+
+    // Time since the command came in
+    pub fn age(&self) -> Duration {
+        self.received.elapsed()
+    }
+}
+
+// Outstanding commands by id, so overlapping commands each get their own ack
+#[derive(Default)]
+pub struct PendingCommands {
+    commands: HashMap<u64, PendingCommand>,
+}
+
+impl PendingCommands {
+    pub fn track(&mut self, command: PendingCommand) {
+        self.commands.insert(command.id, command);
+    }
+
+    // Puts a command back after it was sent once more
+    pub fn retry(&mut self, mut command: PendingCommand) {
+        command.attempts += 1;
+        command.sent = Instant::now();
+        self.commands.insert(command.id, command);
+    }
+
+    // Removes and returns the command a response with this id answers
+    pub fn take(&mut self, id: u64) -> Option<PendingCommand> {
+        self.commands.remove(&id)
+    }
+
+    // Restarts the timeout of a command that was held back and sent late
+    pub fn restart(&mut self, id: u64) {
+        if let Some(command) = self.commands.get_mut(&id) {
+            command.sent = Instant::now();
+        }
+    }
+
+    // Removes and returns the commands that got no response within `timeout`
+    pub fn take_expired(&mut self, timeout: Duration) -> Vec<PendingCommand> {
+        let expired: Vec<u64> = self
+            .commands
+            .values()
+            .filter(|command| command.sent.elapsed() >= timeout)
+            .map(|command| command.id)
+            .collect();
+        expired.iter().filter_map(|id| self.commands.remove(id)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn command(id: u64) -> PendingCommand {
+        PendingCommand::new(id, 0, 7, "get_properties".to_string(), Vec::new())
+    }
+
+    fn ids(mut commands: Vec<PendingCommand>) -> Vec<u64> {
+        commands.sort_by_key(|command| command.id);
+        commands.iter().map(|command| command.id).collect()
+    }
+
+    #[test]
+    fn take_expired_leaves_recent_commands() {
+        let mut pending = PendingCommands::default();
+        pending.track(command(1));
+        sleep(Duration::from_millis(30));
+        pending.track(command(2));
+
+        assert_eq!(ids(pending.take_expired(Duration::from_millis(20))), vec![1]);
+        assert!(pending.take(1).is_none());
+        assert_eq!(ids(pending.take_expired(Duration::ZERO)), vec![2]);
+        assert!(pending.take_expired(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn restart_and_retry_start_the_timeout_again() {
+        let mut pending = PendingCommands::default();
+        pending.track(command(1));
+        pending.track(command(2));
+        sleep(Duration::from_millis(30));
+        pending.restart(1);
+        let retried = pending.take(2).unwrap();
+        pending.retry(retried);
+
+        assert!(pending.take_expired(Duration::from_millis(20)).is_empty());
+        let retried = pending.take(2).unwrap();
+        assert_eq!(retried.attempts, 2);
+        assert_eq!(pending.take(1).unwrap().attempts, 1);
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+
+use crate::context::{self, BridgeContext};
+use crate::mqtt_client::{Client, Message, MqttClient, Result};
+use crate::rate_limit::RateLimiter;
+use crate::stats;
+use crate::topics;
+
+// Fans every publish out to all configured brokers. The first client is the
+// primary one, its connection state and result decide queueing and acks.
+#[derive(Clone)]
+pub struct Publisher {
+    clients: Vec<Client>,
+    limiter: Arc<Mutex<RateLimiter>>,
+    // The bridge it publishes for, also when called from outside its tasks
+    context: Arc<BridgeContext>,
+}
+
+impl Publisher {
+    // Belongs to the bridge of the calling task
+    pub fn new(primary: Client, limiter: RateLimiter) -> Self {
+        Publisher {
+            clients: vec![primary],
+            limiter: Arc::new(Mutex::new(limiter)),
+            context: context::current(),
+        }
+    }
+
+    pub fn add_broker(&mut self, client: Client) {
+        self.clients.push(client);
+    }
+
+    pub fn primary(&self) -> &Client {
+        &self.clients[0]
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.primary().is_connected()
+    }
+
+    pub fn mqtt_version(&self) -> u32 {
+        self.primary().mqtt_version()
+    }
+
+    // Applies the outbound rate limit, None means the message was dropped or coalesced
+    pub fn admit(&self, msg: Message) -> Option<Message> {
+        self.limiter.lock().unwrap().admit(msg)
+    }
+
+    pub fn take_coalesced(&self) -> Vec<Message> {
+        self.limiter.lock().unwrap().take_pending()
+    }
+
+    pub async fn publish(&self, msg: Message) -> Result<()> {
+        context::scope(self.context.clone(), async { self.publish_all(topics::tag(msg)).await }).await
+    }
+
+    // Publishes the payload exactly as given, without the gateway tag
+    pub async fn publish_raw(&self, msg: Message) -> Result<()> {
+        context::scope(self.context.clone(), self.publish_all(msg)).await
+    }
+
+    async fn publish_all(&self, msg: Message) -> Result<()> {
+        // Secondary brokers are best effort, their failures don't fail the publish
+        for client in &self.clients[1..] {
+            if !client.is_connected() {
+                debug!("Broker '{}' disconnected, skipping publish", client.server_uri());
+                continue;
+            }
+            if let Err(e) = client.publish(msg.clone()).await {
+                debug!("Error publishing to '{}': {:?}", client.server_uri(), e);
+            }
+        }
+        let topic = msg.topic().to_string();
+        let result = self.primary().publish(msg).await;
+        if result.is_ok() {
+            stats::count_out(&topic);
+        }
+        result
+    }
+}
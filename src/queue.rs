@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::base64;
+use crate::mqtt_client::Message;
+use crate::publisher::Publisher;
+use crate::stats;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+}
+
+// On-disk form of a queued publish, one JSON object per line
+#[derive(Serialize, Deserialize)]
+struct StoredMessage {
+    topic: String,
+    qos: i32,
+    payload: String,
+    // Device states are retained, older files don't have the field
+    #[serde(default)]
+    retained: bool,
+    // The payload is base64, older files have it as text
+    #[serde(default)]
+    base64: bool,
+}
+
+// Buffers publishes while the broker is unreachable and replays them in order
+pub struct PublishQueue {
+    messages: VecDeque<Message>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    file: Option<PathBuf>,
+    dropped: u64,
+}
+
+impl PublishQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy, file: Option<PathBuf>) -> Self {
+        let mut queue = PublishQueue {
+            messages: VecDeque::with_capacity(capacity.min(64)),
+            capacity,
+            policy,
+            file,
+            dropped: 0,
+        };
+        queue.load();
+        queue
+    }
+
+    // Publishes directly when possible, otherwise queues behind older messages
+    pub async fn publish(&mut self, publisher: &Publisher, msg: Message) {
+        self.flush(publisher).await;
+        if self.messages.is_empty() && publisher.is_connected() {
+            match publisher.publish(msg.clone()).await {
+                Ok(()) => return,
+                Err(e) => debug!("Error publishing, queueing message: {:?}", e),
+            }
+        }
+        self.push(msg);
+    }
+
+    fn push(&mut self, msg: Message) {
+        if self.messages.len() >= self.capacity {
+            self.dropped += 1;
+            stats::inc(|stats| &stats.queue_dropped);
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    self.messages.pop_front();
+                    // Appending would leave the evicted entry in the file, so it is
+                    // rewritten and never holds more than `capacity` messages
+                    self.messages.push_back(msg);
+                    self.store();
+                    warn!("Publish queue full, dropped oldest message ({} dropped)", self.dropped);
+                    return;
+                }
+                OverflowPolicy::DropNewest => {
+                    warn!("Publish queue full, dropping message for '{}' ({} dropped)", msg.topic(), self.dropped);
+                    return;
+                }
+            }
+        }
+        self.append(&msg);
+        self.messages.push_back(msg);
+        stats::set(|stats| &stats.queue_depth, self.messages.len() as u64);
+    }
+
+    // Publishes queued messages in order, stopping at the first failure
+    pub async fn flush(&mut self, publisher: &Publisher) {
+        if self.messages.is_empty() {
+            return;
+        }
+        let queued = self.messages.len();
+        while let Some(msg) = self.messages.front() {
+            if !publisher.is_connected() {
+                break;
+            }
+            if let Err(e) = publisher.publish(msg.clone()).await {
+                debug!("Error publishing queued message: {:?}", e);
+                break;
+            }
+            self.messages.pop_front();
+        }
+        stats::set(|stats| &stats.queue_depth, self.messages.len() as u64);
+        if self.messages.len() < queued {
+            if queued > 1 {
+                info!("Flushed {} queued messages", queued - self.messages.len());
+            }
+            self.store();
+        }
+    }
+
+    fn load(&mut self) {
+        let Some(path) = &self.file else { return };
+        let Ok(content) = fs::read_to_string(path) else { return };
+        let mut loaded = 0;
+        for line in content.lines() {
+            match serde_json::from_str::<StoredMessage>(line) {
+                Ok(stored) => {
+                    let payload = if stored.base64 {
+                        match base64::decode(stored.payload.as_bytes()) {
+                            Ok(payload) => payload,
+                            Err(e) => {
+                                error!("Skipping bad payload in queue file: {}", e);
+                                continue;
+                            }
+                        }
+                    } else {
+                        stored.payload.into_bytes()
+                    };
+                    let msg = if stored.retained {
+                        Message::new_retained(stored.topic, payload, stored.qos)
+                    } else {
+                        Message::new(stored.topic, payload, stored.qos)
+                    };
+                    self.messages.push_back(msg);
+                    loaded += 1;
+                }
+                Err(e) => error!("Skipping bad line in queue file: {:?}", e),
+            }
+        }
+        // Keep only the newest entries, e.g. after lowering --queue-size
+        if self.messages.len() > self.capacity {
+            while self.messages.len() > self.capacity {
+                self.messages.pop_front();
+            }
+            self.store();
+        }
+        stats::set(|stats| &stats.queue_depth, self.messages.len() as u64);
+        info!("Loaded {} queued messages from '{}'", loaded, path.display());
+    }
+
+    fn append(&self, msg: &Message) {
+        let Some(path) = &self.file else { return };
+        let result = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(stored_line(msg).as_bytes()));
+        if let Err(e) = result {
+            error!("Error appending to queue file '{}': {:?}", path.display(), e);
+        }
+    }
+
+    fn store(&self) {
+        let Some(path) = &self.file else { return };
+        let content: String = self.messages.iter().map(stored_line).collect();
+        // Written next to it and renamed, a kill halfway leaves the old file intact
+        let temp = path.with_extension("tmp");
+        if let Err(e) = fs::write(&temp, content).and_then(|()| fs::rename(&temp, path)) {
+            error!("Error writing queue file '{}': {:?}", path.display(), e);
+        }
+    }
+}
+
+fn stored_line(msg: &Message) -> String {
+    let stored = StoredMessage {
+        topic: msg.topic().to_string(),
+        qos: msg.qos(),
+        // Raw frames aren't always UTF-8
+        payload: base64::encode(msg.payload()),
+        retained: msg.retained(),
+        base64: true,
+    };
+    let mut line = serde_json::to_string(&stored).unwrap_or_default();
+    line.push('\n');
+    line
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::mqtt_client::Message;
+use crate::stats;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum RateLimitPolicy {
+    // Excess messages are discarded
+    Drop,
+    // Only the newest excess message per topic and device is kept and sent later
+    Coalesce,
+}
+
+// Parses "50/s", "600/m", "1000/h" or a bare number of messages per second
+pub fn parse_rate(rate: &str) -> Result<f64, String> {
+    let (count, unit) = rate.split_once('/').unwrap_or((rate, "s"));
+    let count: f64 = count.trim().parse().map_err(|_| format!("invalid rate '{}'", rate))?;
+    let seconds = match unit.trim() {
+        "s" | "sec" => 1.0,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("invalid rate unit in '{}', use s, m or h", rate)),
+    };
+    if count <= 0.0 {
+        return Err(format!("rate must be positive: '{}'", rate));
+    }
+    Ok(count / seconds)
+}
+
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        // Allow bursts of up to one second worth of messages
+        let capacity = rate.max(1.0);
+        TokenBucket { rate, capacity, tokens: capacity, last: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    fn available(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+pub struct RateLimiter {
+    global: Option<TokenBucket>,
+    topic_rate: Option<f64>,
+    topics: HashMap<String, TokenBucket>,
+    policy: RateLimitPolicy,
+    pending: HashMap<(String, String), Message>,
+}
+
+// Reports of different devices share a topic, so coalescing keys on the did too
+fn coalesce_key(msg: &Message) -> (String, String) {
+    let did = serde_json::from_slice::<Value>(msg.payload())
+        .ok()
+        .and_then(|json| find_did(&json))
+        .unwrap_or_default();
+    (msg.topic().to_string(), did)
+}
+
+fn find_did(json: &Value) -> Option<String> {
+    if let Some(did) = json.get("did").and_then(|v| v.as_str()) {
+        return Some(did.to_string());
+    }
+    json.get("params").and_then(find_did)
+}
+
+impl RateLimiter {
+    pub fn new(global_rate: Option<f64>, topic_rate: Option<f64>, policy: RateLimitPolicy) -> Self {
+        RateLimiter {
+            global: global_rate.map(TokenBucket::new),
+            topic_rate,
+            topics: HashMap::new(),
+            policy,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn try_acquire(&mut self, topic: &str) -> bool {
+        let topic_rate = self.topic_rate;
+        let topic_bucket = topic_rate.map(|rate| {
+            self.topics
+                .entry(topic.to_string())
+                .or_insert_with(|| TokenBucket::new(rate))
+        });
+        let topic_ok = topic_bucket.map(|bucket| bucket.available()).unwrap_or(true);
+        let global_ok = self.global.as_mut().map(|bucket| bucket.available()).unwrap_or(true);
+        if !(topic_ok && global_ok) {
+            return false;
+        }
+        if let Some(bucket) = self.global.as_mut() {
+            bucket.take();
+        }
+        if let Some(bucket) = self.topics.get_mut(topic) {
+            bucket.take();
+        }
+        true
+    }
+
+    // Returns the message if it may be published now
+    pub fn admit(&mut self, msg: Message) -> Option<Message> {
+        if self.global.is_none() && self.topic_rate.is_none() {
+            return Some(msg);
+        }
+        if self.try_acquire(msg.topic()) {
+            // An older state still waiting would overwrite this one once sent
+            if msg.retained() {
+                self.pending.remove(&coalesce_key(&msg));
+            }
+            return Some(msg);
+        }
+        // Retained states are coalesced under either policy, a dropped one would leave
+        // the topic stale until the device changes again
+        match self.policy {
+            RateLimitPolicy::Drop if !msg.retained() => stats::inc(|stats| &stats.rate_limited),
+            _ => {
+                if self.pending.insert(coalesce_key(&msg), msg).is_some() {
+                    stats::inc(|stats| &stats.coalesced);
+                }
+            }
+        }
+        None
+    }
+
+    // Coalesced messages that fit into the current rate budget
+    pub fn take_pending(&mut self) -> Vec<Message> {
+        let keys: Vec<(String, String)> = self.pending.keys().cloned().collect();
+        let mut ready = Vec::new();
+        for key in keys {
+            if !self.try_acquire(&key.0) {
+                continue;
+            }
+            if let Some(msg) = self.pending.remove(&key) {
+                ready.push(msg);
+            }
+        }
+        ready
+    }
+}
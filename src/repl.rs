@@ -0,0 +1,302 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::PathBuf;
+
+use serde_json::{json, Value};
+use tokio::task;
+use tokio::time::Duration;
+
+use crate::send;
+
+const PROMPT: &str = "agent> ";
+// In $HOME, shared by all sessions
+const HISTORY_FILE: &str = ".aqara2mqtt_history";
+const HISTORY_SIZE: usize = 500;
+
+const HELP: &str = r#"Enter a JSON command, e.g. {"method":"get_device_list","params":{}}
+or a method followed by its JSON params, e.g. get_properties [{"did":"lumi.0","siid":2,"piid":1}]
+An id is added when missing and the response with that id is printed.
+Up/Down browse the history, Ctrl-C drops the line, :quit or Ctrl-D leaves."#;
+
+// `method params` is a shorthand for {"method":..,"params":..}, JSON objects are sent as they are
+fn parse_command(line: &str) -> Result<Vec<u8>, String> {
+    if line.starts_with('{') {
+        return Ok(line.as_bytes().to_vec());
+    }
+    let (method, params) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let mut command = json!({ "method": method });
+    let params = params.trim();
+    if !params.is_empty() {
+        let params: Value = serde_json::from_str(params).map_err(|e| format!("params are not valid JSON: {}", e))?;
+        command["params"] = params;
+    }
+    Ok(command.to_string().into_bytes())
+}
+
+// Canonical mode, echo and signals off while a line is edited, restored on drop
+struct RawMode(libc::termios);
+
+impl RawMode {
+    fn enable() -> Option<Self> {
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return None;
+        }
+        let original = termios;
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+            return None;
+        }
+        Some(RawMode(original))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    KillLine,
+    Interrupt,
+    Eof,
+    Other,
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    Ok((input.read(&mut byte)? > 0).then_some(byte[0]))
+}
+
+// VT100 sequences after ESC, e.g. ESC [ A for Up or ESC [ 3 ~ for Delete
+fn read_escape(input: &mut impl Read) -> io::Result<Key> {
+    let Some(b'[' | b'O') = read_byte(input)? else {
+        return Ok(Key::Other);
+    };
+    let mut code = Vec::new();
+    while let Some(byte) = read_byte(input)? {
+        if byte.is_ascii_digit() || byte == b';' {
+            code.push(byte);
+            continue;
+        }
+        return Ok(match (byte, code.as_slice()) {
+            (b'A', _) => Key::Up,
+            (b'B', _) => Key::Down,
+            (b'C', _) => Key::Right,
+            (b'D', _) => Key::Left,
+            (b'H', _) | (b'~', b"1" | b"7") => Key::Home,
+            (b'F', _) | (b'~', b"4" | b"8") => Key::End,
+            (b'~', b"3") => Key::Delete,
+            _ => Key::Other,
+        });
+    }
+    Ok(Key::Other)
+}
+
+// None at the end of the input
+fn read_key(input: &mut impl Read) -> io::Result<Option<Key>> {
+    let Some(byte) = read_byte(input)? else {
+        return Ok(None);
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        0x01 => Key::Home,
+        0x03 => Key::Interrupt,
+        0x04 => Key::Eof,
+        0x05 => Key::End,
+        0x15 => Key::KillLine,
+        0x1b => read_escape(input)?,
+        byte if byte < 0x20 => Key::Other,
+        byte => {
+            // The leading byte tells how many continuation bytes follow
+            let mut bytes = vec![byte];
+            for _ in 1..(byte.leading_ones().max(1) as usize) {
+                bytes.extend(read_byte(input)?);
+            }
+            match std::str::from_utf8(&bytes).ok().and_then(|text| text.chars().next()) {
+                Some(c) => Key::Char(c),
+                None => Key::Other,
+            }
+        }
+    };
+    Ok(Some(key))
+}
+
+fn redraw(line: &[char], cursor: usize) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    write!(out, "\r\x1b[K{}{}", PROMPT, line.iter().collect::<String>())?;
+    if cursor < line.len() {
+        write!(out, "\x1b[{}D", line.len() - cursor)?;
+    }
+    out.flush()
+}
+
+// Just enough line editing for typing JSON: cursor keys, Home/End, Ctrl-U and history
+struct LineEditor {
+    history: Vec<String>,
+    history_file: Option<PathBuf>,
+}
+
+impl LineEditor {
+    fn load() -> Self {
+        let history_file = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        let history = history_file
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|content| content.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        LineEditor { history, history_file }
+    }
+
+    fn add_history(&mut self, line: &str) {
+        if self.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.history.push(line.to_string());
+        if self.history.len() > HISTORY_SIZE {
+            self.history.remove(0);
+        }
+        if let Some(path) = &self.history_file
+            && let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path)
+        {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    // None on Ctrl-D or the end of the input. Piped input is read line by line without editing.
+    fn read_line(&self) -> io::Result<Option<String>> {
+        let stdin = io::stdin();
+        let Some(_raw_mode) = stdin.is_terminal().then(RawMode::enable).flatten() else {
+            let mut line = String::new();
+            return Ok((stdin.lock().read_line(&mut line)? > 0).then_some(line));
+        };
+        let mut input = stdin.lock();
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // Position in the history, history.len() is the line being typed
+        let mut index = self.history.len();
+        let mut draft = Vec::new();
+        redraw(&line, cursor)?;
+        loop {
+            let Some(key) = read_key(&mut input)? else {
+                return Ok(None);
+            };
+            match key {
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Enter => {
+                    print!("\r\n");
+                    return Ok(Some(line.into_iter().collect()));
+                }
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::Delete if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::Left if cursor > 0 => cursor -= 1,
+                Key::Right if cursor < line.len() => cursor += 1,
+                Key::Home => cursor = 0,
+                Key::End => cursor = line.len(),
+                Key::KillLine => {
+                    line.clear();
+                    cursor = 0;
+                }
+                Key::Up | Key::Down => {
+                    let next = match key {
+                        Key::Up => index.checked_sub(1),
+                        _ => (index < self.history.len()).then_some(index + 1),
+                    };
+                    if let Some(next) = next {
+                        if index == self.history.len() {
+                            draft = line.clone();
+                        }
+                        index = next;
+                        line = match self.history.get(index) {
+                            Some(entry) => entry.chars().collect(),
+                            None => draft.clone(),
+                        };
+                        cursor = line.len();
+                    }
+                }
+                Key::Interrupt => {
+                    print!("^C\r\n");
+                    line.clear();
+                    cursor = 0;
+                    index = self.history.len();
+                }
+                Key::Eof if line.is_empty() => {
+                    print!("\r\n");
+                    return Ok(None);
+                }
+                _ => {}
+            }
+            redraw(&line, cursor)?;
+        }
+    }
+}
+
+// The repl subcommand: reads commands, sends them and prints the responses
+pub async fn run(address: &str, bind_id: u32, wait: Duration) -> Result<(), String> {
+    let mut socket = send::connect(address, bind_id).await?;
+    eprintln!("Connected to '{}' with bind id {}, :help for help", address, bind_id);
+    let mut editor = LineEditor::load();
+    loop {
+        // The terminal is read on a blocking thread, the editor goes there and back
+        let (returned, line) = task::spawn_blocking(move || {
+            let line = editor.read_line();
+            (editor, line)
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+        editor = returned;
+        let line = match line {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => return Err(format!("stdin: {}", e)),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history(line);
+        match line {
+            ":quit" | ":exit" => break,
+            ":help" => {
+                println!("{}", HELP);
+                continue;
+            }
+            _ => {}
+        }
+        let command = match parse_command(line) {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                continue;
+            }
+        };
+        match send::request(&mut socket, bind_id, &command, wait).await {
+            Ok(response) => println!("{}", serde_json::to_string_pretty(&response).unwrap_or_default()),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+    send::disconnect(socket, bind_id).await;
+    Ok(())
+}
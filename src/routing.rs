@@ -0,0 +1,300 @@
+use bytes::Bytes;
+use log::debug;
+use serde_json::Value;
+
+use crate::bridge::QosConfig;
+use crate::command::{self, Route};
+use crate::mqtt_client::{Message, MQTT_VERSION_5};
+use crate::correlation::{Correlator, Reply};
+use crate::dedup::{self, Origin};
+use crate::pending::PendingCommand;
+use crate::publisher::Publisher;
+use crate::queue::PublishQueue;
+use crate::state::StateCache;
+use crate::stats;
+use crate::{compat, device, enrich, gateway, matter, ota, scene, topics, trace};
+
+pub const TOPIC_COMMAND: &str = "miio/command";
+pub const TOPIC_COMMAND_ACK: &str = "miio/command_ack";
+pub const TOPIC_RESPONSE: &str = "openmiio/report";
+// Agent frames that aren't JSON, base64 encoded
+pub const TOPIC_COMMAND_ACK_RAW: &str = "miio/command_ack/raw";
+
+// MQTT v5 user properties carrying the correlation fields of a command
+fn command_properties(command: &PendingCommand) -> Vec<(String, String)> {
+    [("id", command.id), ("_to", command.to), ("_from", command.from)]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+// miio/command_ack unless the command asked for its own reply topic
+pub fn ack_topic(reply_topic: Option<String>) -> String {
+    reply_topic.unwrap_or_else(|| topics::prefixed(TOPIC_COMMAND_ACK))
+}
+
+pub fn command_error(topic: String, id: Option<Value>, code: i32, message: &str, qos: i32) -> Message {
+    Message::new(topic, command::error_ack(id, code, message), qos)
+}
+
+// A command built from a message on one of the command topics
+pub struct Routed {
+    pub route: Option<Route>,
+    pub payload: Result<Vec<u8>, String>,
+    // Where the ack goes instead of miio/command_ack
+    pub reply_topic: Option<String>,
+    // The answer updates the device state instead of being acked
+    pub refresh_state: bool,
+}
+
+// miio/command itself, one of the miio/command/<route> topics, matter/set, <did>/set|get,
+// gateway/<control>/set or bridge/request/ota. None for topics that carry no command.
+pub fn command(topic: &str, payload: &[u8], state_cache: &StateCache, bind_id: u32) -> Option<Routed> {
+    let command_topic = topics::prefixed(TOPIC_COMMAND);
+    let mut reply_topic = None;
+    let mut refresh_state = false;
+    let (route, payload) = if let Some(suffix) = topic.strip_prefix(&command_topic) {
+        let suffix = suffix.trim_start_matches('/');
+        let route = command::parse_route(suffix);
+        let built = match route {
+            Some(route) => command::build(route, payload, bind_id),
+            None => Err(format!("unknown command route '{}'", suffix)),
+        };
+        (route, built)
+    } else if topic == topics::prefixed(matter::TOPIC_MATTER_SET) {
+        let built = matter::set_command(payload).and_then(|rpc| command::build(Route::Matter, &rpc, bind_id));
+        (Some(Route::Matter), built)
+    } else if let Some(did) = device::topic_did(topic, device::TOPIC_DEVICE_SET) {
+        reply_topic = Some(device::set_result_topic(did));
+        let resolve = |name: &str| state_cache.property_key(did, name);
+        (Some(Route::Json), device::set_command(did, payload, resolve, bind_id))
+    } else if topic == topics::prefixed(ota::TOPIC_OTA_REQUEST) {
+        reply_topic = Some(topics::prefixed(ota::TOPIC_OTA_RESPONSE));
+        (Some(Route::Json), ota::command(payload, bind_id))
+    } else if let Some((name, control)) = gateway::parse_topic(topic) {
+        reply_topic = Some(gateway::result_topic(name));
+        (Some(Route::Json), gateway::command(control, payload, bind_id))
+    } else if let Some(did) = device::topic_did(topic, device::TOPIC_DEVICE_GET) {
+        refresh_state = true;
+        let resolve = |name: &str| state_cache.property_key(did, name);
+        let known = state_cache.known_properties(did);
+        (Some(Route::Json), device::get_command(did, payload, resolve, known, bind_id))
+    } else {
+        return None;
+    };
+    Some(Routed { route, payload, reply_topic, refresh_state })
+}
+
+// Error ack for a command that never reached the agent, it is no longer pending
+pub async fn publish_agent_unavailable(
+    publish_queue: &mut PublishQueue,
+    publisher: &Publisher,
+    correlator: &Correlator,
+    payload: &[u8],
+    qos: i32,
+) {
+    stats::inc(|stats| &stats.commands_dropped);
+    let id = command::command_id(payload);
+    let pending = match id.as_ref().and_then(|id| id.as_u64()) {
+        Some(id) => correlator.take(id).await,
+        None => None,
+    };
+    let topic = ack_topic(pending.and_then(|pending| pending.reply_topic));
+    let msg = command_error(topic, id, command::ERROR_AGENT_UNAVAILABLE, "agent unavailable", qos);
+    publish_queue.publish(publisher, msg).await;
+}
+
+// Routes one JSON document from the agent to its topic
+pub async fn handle_agent_document(
+    frame: Bytes,
+    report: Value,
+    reply: Reply,
+    publisher: &Publisher,
+    publish_queue: &mut PublishQueue,
+    state_cache: &StateCache,
+    qos: QosConfig,
+) {
+    let mut topic: &str = TOPIC_RESPONSE;
+    let mut msg_qos = qos.report;
+    let mut props = Vec::new();
+    let mut reply_topic = None;
+    let mut latency = None;
+    let mut traced = None;
+
+    if compat::is_openmiio() {
+        topic = compat::openmiio_topic(&report);
+    } else if let Some(key_topic) = report.get("key").and_then(|v| v.as_str()).and_then(topics::for_agent_key) {
+        topic = key_topic;
+    }
+
+    let pending_command = match reply {
+        Reply::Answer(pending_command) => Some(pending_command),
+        Reply::Late(id) => {
+            debug!("Late reply to command {}", id);
+            topic = TOPIC_COMMAND_ACK;
+            msg_qos = qos.ack;
+            None
+        }
+        Reply::Report => None,
+    };
+    if let Some(pending_command) = &pending_command {
+        let age = pending_command.age();
+        stats::record_latency(age);
+        latency = Some(age);
+        trace::answered(pending_command.id);
+        traced = Some(pending_command.id);
+    }
+    if let Some(pending_command) = &pending_command
+        && pending_command.refresh_state
+        && report.get("result").is_some()
+    {
+        state_cache.publish_refresh(publisher, publish_queue, &report, qos.report).await;
+        trace::published(pending_command.id);
+        return;
+    }
+    if let Some(pending_command) = pending_command {
+        topic = TOPIC_COMMAND_ACK;
+        msg_qos = qos.ack;
+        if publisher.mqtt_version() >= MQTT_VERSION_5 {
+            props = command_properties(&pending_command);
+        }
+        reply_topic = pending_command.reply_topic;
+    }
+
+    // Only the state and the report itself, a scene event is not forwarded from ha_driven
+    let duplicate = (topic == TOPIC_RESPONSE || topic == compat::TOPIC_MIIO_REPORT) && dedup::is_duplicate(Origin::Agent, &report);
+    if duplicate {
+        debug!("Report already forwarded from ha_driven: {}", report);
+    } else if topic == TOPIC_RESPONSE || topic == compat::TOPIC_MIIO_REPORT {
+        state_cache.publish_update(publisher, publish_queue, &report, qos.report).await;
+    }
+    // The raw rule frame still goes to its key topic below
+    if topic != TOPIC_COMMAND_ACK && let Some(event) = scene::decode(&report) {
+        publish_queue.publish(publisher, event).await;
+    }
+
+    if duplicate {
+        return;
+    }
+
+    // matter.event frames go out decoded, unless they are in a layout we don't know
+    let decoded = (topic != TOPIC_COMMAND_ACK && !compat::is_openmiio()).then(|| matter::decode_event(&report)).flatten();
+    let frame = decoded.map(Bytes::from).unwrap_or(frame);
+    let payload = if topic == TOPIC_COMMAND_ACK { enrich::ack(frame, latency) } else { enrich::report(frame) };
+    let topic_name = if topic == TOPIC_COMMAND_ACK { ack_topic(reply_topic) } else { topics::prefixed(topic) };
+    let msg = Message::new(topic_name, payload, msg_qos).with_user_properties(props);
+    // Acks are never rate limited, callers wait for them
+    let msg = if topic == TOPIC_COMMAND_ACK { Some(msg) } else { publisher.admit(msg) };
+    if let Some(msg) = msg {
+        publish_queue.publish(publisher, msg).await;
+    }
+    if let Some(id) = traced {
+        trace::published(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use tokio::time::Duration;
+
+    use super::*;
+    use crate::context::{self, BridgeConfig, BridgeContext};
+
+    const BIND_ID: u32 = 7;
+
+    fn route(topic: &str, payload: &[u8]) -> Option<Routed> {
+        command(topic, payload, &StateCache::default(), BIND_ID)
+    }
+
+    fn json_payload(routed: &Routed) -> Value {
+        serde_json::from_slice(routed.payload.as_ref().unwrap()).unwrap()
+    }
+
+    fn bridge(topic_prefix: &str, gateway_id: Option<&str>) -> Arc<BridgeContext> {
+        let config = BridgeConfig {
+            topic_prefix: topic_prefix.to_string(),
+            gateway_id: gateway_id.map(str::to_string),
+            ..BridgeConfig::default()
+        };
+        Arc::new(BridgeContext::new(config, Duration::ZERO, 0))
+    }
+
+    #[test]
+    fn json_commands_get_the_bind_address() {
+        let routed = route("miio/command", br#"{"id":1,"method":"get_properties"}"#).unwrap();
+        assert_eq!(routed.route, Some(Route::Json));
+        assert_eq!(json_payload(&routed), json!({"id": 1, "method": "get_properties", "_from": BIND_ID}));
+        assert!(routed.reply_topic.is_none() && !routed.refresh_state);
+
+        // An address given by the sender is kept
+        let routed = route("miio/command", br#"{"id":1,"method":"get_properties","_from":3}"#).unwrap();
+        assert_eq!(json_payload(&routed)["_from"], 3);
+    }
+
+    #[test]
+    fn raw_commands_pass_as_they_are() {
+        let routed = route("miio/command/raw", b"not json").unwrap();
+        assert_eq!(routed.route, Some(Route::Raw));
+        assert_eq!(routed.payload.unwrap(), b"not json");
+
+        let routed = route("miio/command/raw/base64", b"bm90IGpzb24=").unwrap();
+        assert_eq!(routed.route, Some(Route::RawBase64));
+        assert_eq!(routed.payload.unwrap(), b"not json");
+    }
+
+    #[test]
+    fn rpc_commands_need_a_method() {
+        let routed = route("miio/command/rpc", br#"{"method":"get_properties","params":[]}"#).unwrap();
+        let payload = json_payload(&routed);
+        assert!(payload["id"].is_u64());
+        assert_eq!(payload["_from"], BIND_ID);
+
+        assert!(route("miio/command/rpc", br#"{"params":[]}"#).unwrap().payload.is_err());
+        assert!(route("miio/command/rpc", b"[1]").unwrap().payload.is_err());
+    }
+
+    #[test]
+    fn unknown_routes_and_other_topics() {
+        let routed = route("miio/command/nope", b"{}").unwrap();
+        assert!(routed.route.is_none() && routed.payload.is_err());
+        assert!(route("miio/report", b"{}").is_none());
+        assert!(route("aqara2mqtt/lumi.1/state", b"{}").is_none());
+    }
+
+    #[test]
+    fn device_get_refreshes_the_state() {
+        let routed = route("aqara2mqtt/lumi.1/get", b"[]").unwrap();
+        assert_eq!(routed.route, Some(Route::Json));
+        assert!(routed.refresh_state);
+        assert!(route("aqara2mqtt/a/b/get", b"[]").is_none());
+    }
+
+    #[test]
+    fn ack_topic_prefers_the_reply_topic() {
+        assert_eq!(ack_topic(None), TOPIC_COMMAND_ACK);
+        assert_eq!(ack_topic(Some("my/acks".to_string())), "my/acks");
+    }
+
+    #[tokio::test]
+    async fn each_bridge_routes_its_own_topics() {
+        context::scope(bridge("gw1/", Some("hub1")), async {
+            assert!(route("gw1/miio/command", b"{}").is_some());
+            assert!(route("gw2/miio/command", b"{}").is_none());
+            assert!(route("miio/command", b"{}").is_none());
+            assert_eq!(ack_topic(None), "gw1/miio/command_ack");
+            let routed = route("gw1/aqara2mqtt/hub1/lumi.1/set", br#"{"2.1": true}"#).unwrap();
+            assert_eq!(routed.reply_topic.as_deref(), Some("gw1/aqara2mqtt/hub1/lumi.1/set_result"));
+        })
+        .await;
+        context::scope(bridge("gw2/", None), async {
+            assert!(route("gw2/miio/command", b"{}").is_some());
+            assert_eq!(ack_topic(None), "gw2/miio/command_ack");
+        })
+        .await;
+        // Outside of a bridge nothing is prefixed
+        assert_eq!(ack_topic(None), TOPIC_COMMAND_ACK);
+    }
+}
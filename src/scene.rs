@@ -0,0 +1,78 @@
+use serde_json::{json, Map, Value};
+
+use crate::mqtt_client::Message;
+use crate::topics;
+
+// aqara2mqtt/scene/<id>, one event per scene or rule the hub ran
+pub const TOPIC_SCENE_PREFIX: &str = "aqara2mqtt/scene";
+
+const SCENE_KEYS: [&str; 2] = ["auto.ifttt", "auto.cross.ifttt"];
+// Field names the hub uses for the scene or rule id, depending on firmware
+const ID_FIELDS: [&str; 4] = ["scene_id", "linkage_id", "ifttt_id", "rule_id"];
+// Wireless buttons report their clicks on this resource
+const RES_BUTTON: &str = "13.1.85";
+
+fn button_action(value: i64) -> Option<&'static str> {
+    match value {
+        1 => Some("single"),
+        2 => Some("double"),
+        3 => Some("triple"),
+        4 => Some("quadruple"),
+        16 => Some("hold"),
+        17 => Some("release"),
+        18 => Some("shake"),
+        _ => None,
+    }
+}
+
+// Depth first search for the first of the given fields
+fn find<'a>(json: &'a Value, fields: &[&str]) -> Option<&'a Value> {
+    match json {
+        Value::Object(map) => fields
+            .iter()
+            .find_map(|field| map.get(*field))
+            .or_else(|| map.values().find_map(|value| find(value, fields))),
+        Value::Array(items) => items.iter().find_map(|item| find(item, fields)),
+        _ => None,
+    }
+}
+
+// The device resource that triggered the rule, e.g. {"did":..,"res_name":"13.1.85","value":2}
+fn find_trigger(json: &Value) -> Option<&Map<String, Value>> {
+    match json {
+        Value::Object(map) if map.contains_key("res_name") => Some(map),
+        Value::Object(map) => map.values().find_map(find_trigger),
+        Value::Array(items) => items.iter().find_map(find_trigger),
+        _ => None,
+    }
+}
+
+fn id_string(id: &Value) -> Option<String> {
+    match id {
+        Value::String(id) if !id.is_empty() => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+// Structured event for auto.ifttt/auto.cross.ifttt frames, None for any other frame
+pub fn decode(report: &Value) -> Option<Message> {
+    let key = report.get("key").and_then(Value::as_str).filter(|key| SCENE_KEYS.contains(key))?;
+    let params = report.get("params").unwrap_or(report);
+    let id = find(params, &ID_FIELDS).and_then(id_string)?;
+    let mut event = json!({ "scene": id, "source": key });
+    if let Some(trigger) = find_trigger(params) {
+        let res_name = trigger.get("res_name").cloned().unwrap_or_default();
+        let value = trigger.get("value").cloned().unwrap_or_default();
+        event["did"] = trigger.get("did").or_else(|| find(params, &["did", "sdid"])).cloned().unwrap_or_default();
+        if res_name == RES_BUTTON && let Some(action) = value.as_i64().and_then(button_action) {
+            event["action"] = Value::from(action);
+        }
+        event["res_name"] = res_name;
+        event["value"] = value;
+    }
+    // Ids can hold dots or slashes, keep them to one topic level
+    let topic_id: String = id.chars().map(|c| if matches!(c, '/' | '+' | '#') { '_' } else { c }).collect();
+    let topic = format!("{}/{}", TOPIC_SCENE_PREFIX, topic_id);
+    Some(Message::new(topics::prefixed(&topic), event.to_string(), 1))
+}
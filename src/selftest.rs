@@ -0,0 +1,139 @@
+use std::env;
+use std::fs::File;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::BytesMut;
+use tokio::time::{timeout_at, Duration, Instant};
+use tokio_stream::StreamExt;
+
+use crate::agent_socket::{self, AgentTransport};
+use crate::hadriven::Source;
+use crate::mqtt_client::{Client, Message, MqttClient, MqttConfig};
+use crate::{check, logger, send, topics};
+
+pub const TOPIC_SELFTEST: &str = "aqara2mqtt/bridge/selftest";
+
+// The agent closes the socket or answers with an error this soon after a bind it rejects
+const BIND_CHECK_WINDOW: Duration = Duration::from_secs(2);
+// Hubs without a battery backed clock start in 1970 until NTP synced, 2025-01-01
+const CLOCK_FLOOR: u64 = 1_735_689_600;
+
+// Connects and binds like the bridge does
+async fn agent(address: &str, bind_id: u32) -> Result<String, String> {
+    let mut socket = send::connect(address, bind_id).await?;
+    let deadline = Instant::now() + BIND_CHECK_WINDOW;
+    let mut buf = BytesMut::zeroed(4096);
+    let result = loop {
+        let n = match timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(Ok((0, _))) => break Err(format!("the agent closed the connection, is bind id {} taken?", bind_id)),
+            Ok(Ok((n, _))) => n,
+            Ok(Err(e)) => break Err(format!("agent socket: {}", e)),
+            // Silence is fine, not every agent answers the bind
+            Err(_) => break Ok(()),
+        };
+        let (documents, _) = agent_socket::split_documents(&buf[..n]);
+        if documents.iter().any(|(_, document)| agent_socket::is_bind_rejection(document)) {
+            break Err(format!("the agent rejected bind id {}", bind_id));
+        }
+    };
+    send::disconnect(socket, bind_id).await;
+    result.map(|()| format!("bound as {} at '{}'", bind_id, address))
+}
+
+// Publishes on a topic of its own and waits for the message to come back
+async fn loopback(client: &mut Client, client_id: &str, wait: Duration) -> Result<Duration, String> {
+    let topic = topics::prefixed(&format!("{}/{}", TOPIC_SELFTEST, client_id));
+    let mut stream = client.get_stream(8);
+    client.subscribe(&topic, 1).await.map_err(|e| format!("can't subscribe to '{}': {}", topic, e))?;
+    let nonce = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+    let sent = Instant::now();
+    client
+        .publish(Message::new(topic.clone(), nonce.clone(), 1))
+        .await
+        .map_err(|e| format!("can't publish on '{}': {}", topic, e))?;
+    let deadline = sent + wait;
+    loop {
+        match timeout_at(deadline, stream.next()).await {
+            Ok(Some(Some(msg))) if msg.topic() == topic && msg.payload() == nonce.as_bytes() => return Ok(sent.elapsed()),
+            Ok(Some(Some(_))) => {}
+            Ok(_) => return Err("connection lost during the loopback".to_string()),
+            Err(_) => return Err(format!("message on '{}' didn't come back within {:?}", topic, wait)),
+        }
+    }
+}
+
+async fn broker(uri: &str, client_id: &str, config: &MqttConfig, wait: Duration) -> Result<String, String> {
+    let (mut client, version) = check::connect_client(uri, client_id, config).await?;
+    let result = loopback(&mut client, client_id, wait).await;
+    let _ = client.disconnect().await;
+    let latency = result?;
+    Ok(format!("'{}' with MQTT version {}, loopback in {} ms", uri, version, latency.as_millis()))
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata().is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+// The reports ha_driven logs are forwarded from its output, so it must be startable
+fn ha_driven() -> Result<String, String> {
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .map(|dir| dir.join("ha_driven"))
+        .find(|path| is_executable(path))
+        .map(|path| format!("found at {}", path.display()))
+        .ok_or_else(|| "ha_driven is not in PATH, the reports it logs won't be forwarded".to_string())
+}
+
+// Tailing needs the log ha_driven writes to
+fn ha_driven_log(path: &Path) -> Result<String, String> {
+    File::open(path)
+        .map(|_| format!("log at {}", path.display()))
+        .map_err(|e| format!("log '{}' can't be read, the reports ha_driven logs won't be forwarded: {}", path.display(), e))
+}
+
+// TLS certificates and the timestamps in reports need the real time
+fn clock() -> Result<String, String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let time = logger::timestamp(true);
+    if now < CLOCK_FLOOR {
+        return Err(format!("{} is before 2025, the clock isn't synced yet", time));
+    }
+    Ok(time)
+}
+
+// The selftest subcommand, prints one PASS or FAIL line per check and returns whether all passed
+pub async fn run(
+    agent_address: &str,
+    bind_id: u32,
+    brokers: &[(String, MqttConfig)],
+    client_id: &str,
+    wait: Duration,
+    ha_driven: Option<&Source>,
+) -> bool {
+    let mut results = vec![("agent", agent(agent_address, bind_id).await)];
+    for (uri, config) in brokers {
+        results.push(("broker", broker(uri, &format!("{}-selftest", client_id), config, wait).await));
+    }
+    match ha_driven {
+        Some(Source::Spawn) => results.push(("ha_driven", self::ha_driven())),
+        Some(Source::Tail(path)) => results.push(("ha_driven", ha_driven_log(path))),
+        None => {}
+    }
+    results.push(("clock", clock()));
+
+    for (name, result) in &results {
+        match result {
+            Ok(detail) => println!("PASS  {:<10}{}", name, detail),
+            Err(e) => println!("FAIL  {:<10}{}", name, e),
+        }
+    }
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if failed == 0 {
+        println!("All {} checks passed", results.len());
+    } else {
+        println!("{} of {} checks failed", failed, results.len());
+    }
+    failed == 0
+}
@@ -0,0 +1,60 @@
+use bytes::BytesMut;
+use serde_json::Value;
+use tokio::time::{timeout_at, Duration, Instant};
+
+use crate::agent_socket::{self, AgentSocket, AgentTransport};
+use crate::command::{self, Route};
+
+fn io_error(e: std::io::Error) -> String {
+    format!("agent socket: {}", e)
+}
+
+// Connection of the subcommands that talk to the agent themselves
+pub async fn connect(address: &str, bind_id: u32) -> Result<AgentSocket, String> {
+    let mut socket = AgentSocket::connect(address)
+        .await
+        .map_err(|e| format!("can't connect to the agent at '{}': {}", address, e))?;
+    socket.send(agent_socket::bind_message(bind_id).as_bytes()).await.map_err(io_error)?;
+    Ok(socket)
+}
+
+// Sends one command and returns the agent's reply with the same id.
+// Other frames arriving meanwhile are skipped.
+pub async fn request(socket: &mut AgentSocket, bind_id: u32, payload: &[u8], wait: Duration) -> Result<Value, String> {
+    // Like miio/command/rpc, an id and our address are added when missing
+    let frame = command::build(Route::Rpc, payload, bind_id)?;
+    let id = command::command_id(&frame);
+    socket.send(&frame).await.map_err(io_error)?;
+
+    let deadline = Instant::now() + wait;
+    let mut buf = BytesMut::zeroed(4096);
+    loop {
+        let n = match timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(Ok((0, _))) => return Err(format!("the agent closed the connection, is bind id {} taken?", bind_id)),
+            Ok(Ok((n, _))) => n,
+            Ok(Err(e)) => return Err(io_error(e)),
+            Err(_) => return Err(format!("no response within {:?}", wait)),
+        };
+        let (documents, _) = agent_socket::split_documents(&buf[..n]);
+        for (_, document) in documents {
+            if agent_socket::is_bind_rejection(&document) {
+                return Err(format!("the agent rejected bind id {}, pick another one with --bind-id", bind_id));
+            }
+            if document.get("id") == id.as_ref() {
+                return Ok(document);
+            }
+        }
+    }
+}
+
+pub async fn disconnect(mut socket: AgentSocket, bind_id: u32) {
+    let _ = socket.send(agent_socket::unbind_message(bind_id).as_bytes()).await;
+}
+
+// The send subcommand
+pub async fn send(address: &str, bind_id: u32, payload: &str, wait: Duration) -> Result<Value, String> {
+    let mut socket = connect(address, bind_id).await?;
+    let response = request(&mut socket, bind_id, payload.as_bytes(), wait).await;
+    disconnect(socket, bind_id).await;
+    response
+}
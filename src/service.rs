@@ -0,0 +1,222 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum InitSystem {
+    // OpenWrt style /etc/rc.common scripts
+    Procd,
+    Systemd,
+    // Plain start/stop scripts for BusyBox init
+    Sysv,
+}
+
+// Options whose value is a path, made absolute since init starts services in /
+const PATH_OPTIONS: [&str; 10] = [
+    "--config",
+    "--friendly-names",
+    "--spec-file",
+    "--queue-file",
+    "--mqtt-ca-cert",
+    "--mqtt-client-cert",
+    "--mqtt-client-key",
+    "--log-file",
+    "--pidfile",
+    "--mux-socket",
+];
+
+// The init system keeps track of the process, it must stay in the foreground
+const FOREGROUND_ONLY: &str = "--daemon";
+
+const PROCD: &str = r#"#!/bin/sh /etc/rc.common
+# Generated by aqara-agent2mqtt install
+
+# After the broker, mosquitto starts at 80
+START=99
+STOP=10
+USE_PROCD=1
+
+start_service() {
+    procd_open_instance
+    procd_set_param command @COMMAND@
+    # Restarted 5s after exiting, without a limit
+    procd_set_param respawn 3600 5 0
+    procd_set_param stdout 1
+    procd_set_param stderr 1
+    procd_close_instance
+}
+
+reload_service() {
+    procd_send_signal aqara2mqtt
+}
+"#;
+
+const SYSTEMD: &str = r#"# Generated by aqara-agent2mqtt install
+[Unit]
+Description=Aqara agent to MQTT bridge
+Wants=network-online.target
+After=network-online.target mosquitto.service
+
+[Service]
+# Ready once connected to the broker and the agent, restarted when either stays down
+Type=notify
+NotifyAccess=main
+TimeoutStartSec=infinity
+WatchdogSec=120
+ExecStart=@COMMAND@
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=always
+RestartSec=5
+
+[Install]
+WantedBy=multi-user.target
+"#;
+
+const SYSV: &str = r#"#!/bin/sh
+### BEGIN INIT INFO
+# Provides:          aqara2mqtt
+# Required-Start:    $network
+# Should-Start:      mosquitto
+# Required-Stop:     $network
+# Default-Start:     2 3 4 5
+# Default-Stop:      0 1 6
+# Short-Description: Aqara agent to MQTT bridge
+### END INIT INFO
+# Generated by aqara-agent2mqtt install
+
+PIDFILE=/var/run/aqara2mqtt.pid
+CHILD_PIDFILE=/var/run/aqara2mqtt.child.pid
+
+# Restarts the bridge 5s after it exits, until stopped
+supervise() {
+    trap 'kill "$child" 2>/dev/null; rm -f "$CHILD_PIDFILE"; exit 0' TERM INT
+    while :; do
+        @COMMAND@ &
+        child=$!
+        echo "$child" > "$CHILD_PIDFILE"
+        wait "$child"
+        sleep 5
+    done
+}
+
+running() {
+    [ -f "$PIDFILE" ] && kill -0 "$(cat "$PIDFILE")" 2>/dev/null
+}
+
+case "$1" in
+    start)
+        if running; then
+            echo "aqara2mqtt is already running"
+            exit 0
+        fi
+        supervise </dev/null >/dev/null 2>&1 &
+        echo $! > "$PIDFILE"
+        ;;
+    stop)
+        if running; then
+            kill "$(cat "$PIDFILE")"
+        fi
+        rm -f "$PIDFILE"
+        ;;
+    restart)
+        "$0" stop
+        sleep 1
+        "$0" start
+        ;;
+    reload)
+        [ -f "$CHILD_PIDFILE" ] && kill -HUP "$(cat "$CHILD_PIDFILE")"
+        ;;
+    status)
+        if running; then
+            echo "aqara2mqtt is running"
+        else
+            echo "aqara2mqtt is stopped"
+            exit 3
+        fi
+        ;;
+    *)
+        echo "Usage: $0 {start|stop|restart|reload|status}"
+        exit 1
+        ;;
+esac
+"#;
+
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=,@+".contains(c)) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+// systemd expands % specifiers and $ variables even in quoted arguments
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty() && !escaped.contains(|c: char| c.is_whitespace() || "\"'\\".contains(c)) {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', r"\\").replace('"', r#"\""#))
+}
+
+fn absolute(path: &str) -> String {
+    match std::path::absolute(path) {
+        Ok(path) => path.display().to_string(),
+        Err(_) => path.to_string(),
+    }
+}
+
+// The command line before the install subcommand, as the service should run it
+pub fn service_args(args: &[String]) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut path_value = false;
+    for arg in args {
+        if path_value {
+            out.push(absolute(arg));
+            path_value = false;
+        } else if let Some((option, value)) = arg.split_once('=')
+            && PATH_OPTIONS.contains(&option)
+        {
+            out.push(format!("{}={}", option, absolute(value)));
+        } else if arg != FOREGROUND_ONLY {
+            path_value = PATH_OPTIONS.contains(&arg.as_str());
+            out.push(arg.clone());
+        }
+    }
+    out
+}
+
+pub fn script(init: InitSystem, binary: &str, args: &[String]) -> String {
+    let (template, quote): (&str, fn(&str) -> String) = match init {
+        InitSystem::Procd => (PROCD, shell_quote),
+        InitSystem::Systemd => (SYSTEMD, systemd_quote),
+        InitSystem::Sysv => (SYSV, shell_quote),
+    };
+    let command: Vec<String> = std::iter::once(binary).chain(args.iter().map(String::as_str)).map(quote).collect();
+    template.replace("@COMMAND@", &command.join(" "))
+}
+
+pub fn install_path(init: InitSystem) -> &'static str {
+    match init {
+        InitSystem::Procd | InitSystem::Sysv => "/etc/init.d/aqara2mqtt",
+        InitSystem::Systemd => "/etc/systemd/system/aqara2mqtt.service",
+    }
+}
+
+// Commands that enable and start the installed service
+pub fn next_steps(init: InitSystem) -> &'static str {
+    match init {
+        InitSystem::Procd => "/etc/init.d/aqara2mqtt enable && /etc/init.d/aqara2mqtt start",
+        InitSystem::Systemd => "systemctl daemon-reload && systemctl enable --now aqara2mqtt",
+        InitSystem::Sysv => "link /etc/init.d/aqara2mqtt into the runlevel directory, e.g. /etc/rc.d/S99aqara2mqtt, and run it with start",
+    }
+}
+
+pub fn install(init: InitSystem, content: &str) -> Result<&'static str, String> {
+    let path = install_path(init);
+    fs::write(path, content).map_err(|e| format!("'{}': {}", path, e))?;
+    // Init scripts are run directly, the systemd unit only needs to be readable
+    let mode = if matches!(init, InitSystem::Systemd) { 0o644 } else { 0o755 };
+    fs::set_permissions(Path::new(path), fs::Permissions::from_mode(mode)).map_err(|e| format!("'{}': {}", path, e))?;
+    Ok(path)
+}
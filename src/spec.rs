@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+// MIoT property names of the models we know, matched by model prefix.
+// Keys are siid.piid as stored in the device state.
+const BUNDLED: &[(&str, &[(&str, &str)])] = &[
+    ("lumi.sensor_ht.agl02", &[("3.1", "temperature"), ("3.2", "relative_humidity"), ("3.3", "pressure"), ("4.1", "battery_level")]),
+    ("lumi.magnet.agl02", &[("2.1", "contact_state"), ("3.1", "battery_level")]),
+    ("lumi.motion.agl02", &[("2.1", "illumination"), ("2.2", "no_motion_duration"), ("3.1", "battery_level")]),
+    ("lumi.switch", &[("2.1", "switch"), ("3.1", "switch_2"), ("4.1", "switch_3")]),
+    ("lumi.plug", &[("2.1", "switch"), ("3.1", "electric_power"), ("3.2", "power_consumption")]),
+    ("lumi.light", &[("2.1", "on"), ("2.2", "brightness"), ("2.3", "color_temperature")]),
+    ("lumi.curtain", &[("2.2", "motor_control"), ("2.4", "current_position"), ("2.6", "target_position")]),
+    ("lumi.airrtc", &[("2.1", "on"), ("2.2", "mode"), ("2.4", "target_temperature"), ("3.1", "temperature")]),
+];
+
+// Spec database, the bundled names extended and overridden by a JSON file, e.g.
+// {"lumi.sensor_ht.agl02":{"3.1":"temperature"},"lumi.vibration.agl01":{"2.1":"vibration"}}
+#[derive(Default)]
+pub struct MiotSpec {
+    user: HashMap<String, HashMap<String, String>>,
+}
+
+impl MiotSpec {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let user = serde_json::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(MiotSpec { user })
+    }
+
+    pub fn name(&self, model: &str, key: &str) -> Option<&str> {
+        if let Some(name) = self.user.get(model).and_then(|names| names.get(key)) {
+            return Some(name);
+        }
+        BUNDLED
+            .iter()
+            .find(|(prefix, _)| model.starts_with(prefix))
+            .and_then(|(_, names)| names.iter().find(|(k, _)| *k == key))
+            .map(|(_, name)| *name)
+    }
+
+    // siid.piid of a named property, the reverse of name()
+    pub fn key(&self, model: &str, name: &str) -> Option<String> {
+        if let Some((key, _)) = self.user.get(model).and_then(|names| names.iter().find(|(_, n)| n.as_str() == name)) {
+            return Some(key.clone());
+        }
+        BUNDLED
+            .iter()
+            .find(|(prefix, _)| model.starts_with(prefix))
+            .and_then(|(_, names)| names.iter().find(|(_, n)| *n == name))
+            .map(|(key, _)| key.to_string())
+    }
+
+    // All siid.piid keys known for a model
+    pub fn keys(&self, model: &str) -> Vec<String> {
+        let user = self.user.get(model).into_iter().flat_map(|names| names.keys().cloned());
+        let bundled = BUNDLED
+            .iter()
+            .find(|(prefix, _)| model.starts_with(prefix))
+            .into_iter()
+            .flat_map(|(_, names)| names.iter().map(|(key, _)| key.to_string()));
+        user.chain(bundled).collect()
+    }
+
+    // Renames the known siid.piid keys of a device state, unknown keys stay as they are
+    pub fn translate(&self, model: &str, state: &Value) -> Value {
+        let Value::Object(map) = state else {
+            return state.clone();
+        };
+        let translated: Map<String, Value> = map
+            .iter()
+            .map(|(key, value)| {
+                let name = self.name(model, key).unwrap_or(key);
+                (name.to_string(), value.clone())
+            })
+            .collect();
+        Value::Object(translated)
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use log::debug;
+use serde_json::{Map, Value};
+
+use crate::availability;
+use crate::battery;
+use crate::discovery;
+use crate::inventory::Device;
+use crate::mqtt_client::Message;
+use crate::network::{self, Link};
+use crate::publisher::Publisher;
+use crate::queue::PublishQueue;
+use crate::spec::MiotSpec;
+use crate::topics;
+use crate::zigbee2mqtt::FriendlyNames;
+
+pub const TOPIC_DEVICE_PREFIX: &str = "aqara2mqtt";
+
+pub fn state_topic(did: &str) -> String {
+    topics::prefixed(&format!("{}/{}/state", TOPIC_DEVICE_PREFIX, did))
+}
+
+// Latest known resource values of every device, keyed by did
+#[derive(Clone, Default)]
+pub struct StateCache {
+    devices: Arc<Mutex<HashMap<String, Map<String, Value>>>>,
+    names: Arc<FriendlyNames>,
+    // Also publish zigbee2mqtt style topics
+    zigbee2mqtt: bool,
+    // Names siid.piid keys on the zigbee2mqtt topics, needs the model from the inventory
+    spec: Arc<MiotSpec>,
+    models: Arc<Mutex<HashMap<String, String>>>,
+    // Last device inventory, discovery configs are rebuilt from it on reload
+    inventory: Arc<Mutex<Vec<Device>>>,
+    // When each did last reported and whether it is considered online
+    last_seen: Arc<Mutex<HashMap<String, (Instant, bool)>>>,
+    // Last published battery percentage of each did
+    batteries: Arc<Mutex<BTreeMap<String, u8>>>,
+    // Link quality of each did, dirty until the network map is published again
+    links: Arc<Mutex<BTreeMap<String, Link>>>,
+    links_dirty: Arc<AtomicBool>,
+    // Keeps neither the device states nor the inventory, each report is published as the state
+    low_memory: bool,
+}
+
+// Reports nest the device id differently depending on the source,
+// get_properties replies carry it in each item of the result
+pub fn find_did(json: &Value) -> Option<&str> {
+    if let Value::Array(items) = json {
+        return items.first().and_then(find_did);
+    }
+    for key in ["sdid", "did"] {
+        if let Some(did) = json.get(key).and_then(|v| v.as_str()) {
+            return Some(did);
+        }
+    }
+    json.get("params")
+        .or_else(|| json.get("value"))
+        .or_else(|| json.get("result"))
+        .and_then(find_did)
+}
+
+pub fn is_resource_key(key: &str) -> bool {
+    key.contains('.') && key.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+// Collects resource values from the known report shapes:
+// [{"res_name":"0.1.85","value":1}], [{"siid":2,"piid":1,"value":1}] and {"0.1.85":1}
+fn collect_resources(json: &Value, out: &mut Map<String, Value>) {
+    match json {
+        Value::Array(items) => {
+            for item in items {
+                let value = item.get("value").cloned().unwrap_or(Value::Null);
+                if let Some(res_name) = item.get("res_name").and_then(|v| v.as_str()) {
+                    out.insert(res_name.to_string(), value);
+                } else if let (Some(siid), Some(piid)) = (item.get("siid"), item.get("piid")) {
+                    out.insert(format!("{}.{}", siid, piid), value);
+                } else {
+                    collect_resources(item, out);
+                }
+            }
+        }
+        Value::Object(map) => {
+            for (key, value) in map {
+                if is_resource_key(key) {
+                    out.insert(key.clone(), value.clone());
+                } else if value.is_object() || value.is_array() {
+                    collect_resources(value, out);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+impl StateCache {
+    pub fn new(names: FriendlyNames, zigbee2mqtt: bool, spec: MiotSpec) -> Self {
+        StateCache {
+            devices: Arc::default(),
+            names: Arc::new(names),
+            zigbee2mqtt,
+            spec: Arc::new(spec),
+            models: Arc::default(),
+            inventory: Arc::default(),
+            last_seen: Arc::default(),
+            batteries: Arc::default(),
+            links: Arc::default(),
+            links_dirty: Arc::default(),
+            low_memory: false,
+        }
+    }
+
+    pub fn with_low_memory(mut self) -> Self {
+        self.low_memory = true;
+        self
+    }
+
+    pub fn set_models(&self, devices: &[Device]) {
+        let mut models = self.models.lock().unwrap();
+        for device in devices {
+            models.insert(device.did.clone(), device.model.clone());
+        }
+        if !self.low_memory {
+            *self.inventory.lock().unwrap() = devices.to_vec();
+        }
+    }
+
+    pub fn inventory(&self) -> Vec<Device> {
+        self.inventory.lock().unwrap().clone()
+    }
+
+    // siid.piid of a named property of the device, needs its model from the inventory
+    pub fn property_key(&self, did: &str, name: &str) -> Option<String> {
+        let models = self.models.lock().unwrap();
+        self.spec.key(models.get(did)?, name)
+    }
+
+    // siid.piid keys worth querying: those the device reported so far and those its spec knows
+    pub fn known_properties(&self, did: &str) -> Vec<String> {
+        let mut keys: Vec<String> = match self.devices.lock().unwrap().get(did) {
+            Some(state) => state.keys().filter(|key| key.matches('.').count() == 1).cloned().collect(),
+            None => Vec::new(),
+        };
+        if let Some(model) = self.models.lock().unwrap().get(did) {
+            keys.extend(self.spec.keys(model));
+        }
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    pub fn names(&self) -> &FriendlyNames {
+        &self.names
+    }
+
+    fn zigbee2mqtt_message(&self, did: &str, state: &Value, qos: i32) -> Message {
+        let payload = self.names.flatten(&self.friendly_state(did, state)).to_string();
+        Message::new_retained(self.names.topic(did), payload, qos)
+    }
+
+    // Forgets a device, returns empty retained messages clearing all of its topics
+    pub fn remove(&self, did: &str) -> Result<Vec<Message>, String> {
+        let mut topics = vec![state_topic(did), availability::device_topic(did), battery::device_topic(did), network::device_topic(did)];
+        if self.zigbee2mqtt {
+            topics.push(self.names.topic(did));
+        }
+        self.names.remove(did)?;
+        let mut messages: Vec<Message> = topics.into_iter().map(|topic| Message::new_retained(topic, "", 1)).collect();
+        if let Some(model) = self.models.lock().unwrap().remove(did) {
+            messages.extend(discovery::clear_messages(did, &model));
+        }
+        self.inventory.lock().unwrap().retain(|device| device.did != did);
+        self.devices.lock().unwrap().remove(did);
+        self.last_seen.lock().unwrap().remove(did);
+        if self.links.lock().unwrap().remove(did).is_some() {
+            self.links_dirty.store(true, Ordering::Relaxed);
+        }
+        let mut batteries = self.batteries.lock().unwrap();
+        if batteries.remove(did).is_some() && let Some(threshold) = battery::threshold() {
+            messages.push(battery::low_battery_message(&batteries, threshold));
+        }
+        Ok(messages)
+    }
+
+    // Renames a device, returns its did and the messages moving its zigbee2mqtt topic
+    pub fn rename(&self, from: &str, to: &str, qos: i32) -> Result<(String, Vec<Message>), String> {
+        let old_topic = self.names.topic(from);
+        let did = self.names.rename(from, to)?;
+        let mut messages = Vec::new();
+        let state = self.devices.lock().unwrap().get(&did).cloned();
+        if self.zigbee2mqtt && let Some(state) = state {
+            // An empty retained payload removes the old topic from the broker
+            messages.push(Message::new_retained(old_topic, "", qos));
+            messages.push(self.zigbee2mqtt_message(&did, &Value::Object(state), qos));
+        }
+        Ok((did, messages))
+    }
+
+    fn friendly_state(&self, did: &str, state: &Value) -> Value {
+        match self.models.lock().unwrap().get(did) {
+            Some(model) => self.spec.translate(model, state),
+            None => state.clone(),
+        }
+    }
+
+    // Merges a report into the cache, returns the did and its full state if anything changed
+    pub fn update(&self, report: &Value) -> Option<(String, Value)> {
+        let did = find_did(report)?.to_string();
+        let mut resources = Map::new();
+        collect_resources(report, &mut resources);
+        if resources.is_empty() {
+            return None;
+        }
+        if self.low_memory {
+            return Some((did, Value::Object(resources)));
+        }
+        let mut devices = self.devices.lock().unwrap();
+        let state = devices.entry(did.clone()).or_default();
+        let mut changed = false;
+        for (key, value) in resources {
+            if state.get(&key) != Some(&value) {
+                state.insert(key, value);
+                changed = true;
+            }
+        }
+        changed.then(|| (did, Value::Object(state.clone())))
+    }
+
+    // Merges a get_properties reply, the state is published even when nothing changed
+    pub fn refresh(&self, reply: &Value, qos: i32) -> Vec<Message> {
+        let Some(did) = find_did(reply) else { return Vec::new() };
+        let updated = self.update(reply).map(|(_, state)| state);
+        let Some(state) = updated.or_else(|| self.devices.lock().unwrap().get(did).cloned().map(Value::Object)) else {
+            return Vec::new();
+        };
+        let mut messages = Vec::new();
+        if self.zigbee2mqtt {
+            messages.push(self.zigbee2mqtt_message(did, &state, qos));
+        }
+        messages.push(Message::new_retained(state_topic(did), state.to_string(), qos));
+        messages
+    }
+
+    pub async fn publish_refresh(&self, publisher: &Publisher, publish_queue: &mut PublishQueue, reply: &Value, qos: i32) {
+        for msg in self.refresh(reply, qos) {
+            publish_state(publisher, publish_queue, msg).await;
+        }
+    }
+
+    // Records that the device sent something, returns its availability if it just came online
+    fn seen(&self, report: &Value) -> Option<Message> {
+        availability::device_timeouts()?;
+        let did = find_did(report)?;
+        let mut last_seen = self.last_seen.lock().unwrap();
+        let was_online = last_seen.insert(did.to_string(), (Instant::now(), true)).is_some_and(|(_, online)| online);
+        (!was_online).then(|| availability::device_status(did, true))
+    }
+
+    // Marks devices silent for longer than their timeout as offline
+    pub fn expire_devices(&self) -> Vec<Message> {
+        let Some(timeouts) = availability::device_timeouts() else {
+            return Vec::new();
+        };
+        let models = self.models.lock().unwrap();
+        let mut last_seen = self.last_seen.lock().unwrap();
+        let mut messages = Vec::new();
+        for (did, (seen, online)) in last_seen.iter_mut() {
+            let timeout = timeouts.for_model(models.get(did).map(String::as_str));
+            if *online && seen.elapsed() > timeout {
+                debug!("'{}' silent for {:?}, marking offline", did, seen.elapsed());
+                *online = false;
+                messages.push(availability::device_status(did, false));
+            }
+        }
+        messages
+    }
+
+    // Battery topic of the device if its level changed, plus the low battery list if that changed too
+    fn battery_updates(&self, did: &str, state: &Value) -> Vec<Message> {
+        let Some(threshold) = battery::threshold() else {
+            return Vec::new();
+        };
+        let miot_key = self.models.lock().unwrap().get(did).and_then(|model| {
+            let mut keys = state.as_object()?.keys();
+            keys.find(|key| self.spec.name(model, key) == Some(battery::MIOT_BATTERY_LEVEL)).cloned()
+        });
+        let Some(level) = battery::level(state, miot_key.as_deref()) else {
+            return Vec::new();
+        };
+        let mut batteries = self.batteries.lock().unwrap();
+        let previous = batteries.insert(did.to_string(), level);
+        if previous == Some(level) {
+            return Vec::new();
+        }
+        let mut messages = vec![battery::device_message(did, level)];
+        let was_low = previous.is_some_and(|previous| previous < threshold);
+        if was_low != (level < threshold) {
+            messages.push(battery::low_battery_message(&batteries, threshold));
+        }
+        messages
+    }
+
+    // Link quality topic of the device if it changed
+    fn link_update(&self, report: &Value) -> Option<Message> {
+        if !network::is_enabled() {
+            return None;
+        }
+        let did = find_did(report)?;
+        let state = self.devices.lock().unwrap().get(did).cloned().map(Value::Object).unwrap_or_default();
+        let link = network::link(report, &state)?;
+        let mut links = self.links.lock().unwrap();
+        if links.get(did) == Some(&link) {
+            return None;
+        }
+        let msg = network::device_message(did, &link);
+        links.insert(did.to_string(), link);
+        self.links_dirty.store(true, Ordering::Relaxed);
+        Some(msg)
+    }
+
+    // The network map, if any link changed since it was last taken
+    pub fn take_network_map(&self) -> Option<Message> {
+        if !self.links_dirty.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+        let models = self.models.lock().unwrap();
+        Some(network::map_message(&self.links.lock().unwrap(), &models))
+    }
+
+    // What a report changes: availability, device state and its mirrors, battery and link
+    pub fn updates(&self, report: &Value, qos: i32) -> Vec<Message> {
+        let mut messages: Vec<Message> = self.seen(report).into_iter().collect();
+        if let Some((did, state)) = self.update(report) {
+            debug!("state of '{}' changed: {}", did, state);
+            if self.zigbee2mqtt {
+                messages.push(self.zigbee2mqtt_message(&did, &state, qos));
+            }
+            messages.push(Message::new_retained(state_topic(&did), state.to_string(), qos));
+            messages.extend(self.battery_updates(&did, &state));
+        }
+        messages.extend(self.link_update(report));
+        messages
+    }
+
+    pub async fn publish_update(&self, publisher: &Publisher, publish_queue: &mut PublishQueue, report: &Value, qos: i32) {
+        for msg in self.updates(report, qos) {
+            publish_state(publisher, publish_queue, msg).await;
+        }
+    }
+}
+
+// Rate limited and queued like reports, so changes made while the broker is away are not lost
+async fn publish_state(publisher: &Publisher, publish_queue: &mut PublishQueue, msg: Message) {
+    if let Some(msg) = publisher.admit(msg) {
+        publish_queue.publish(publisher, msg).await;
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+use tokio::time::{interval, Duration};
+
+use crate::context;
+use crate::mqtt_client::Message;
+use crate::publisher::Publisher;
+use crate::topics;
+
+pub const TOPIC_STATS: &str = "aqara2mqtt/bridge/stats";
+pub const TOPIC_STATS_RESET_REQUEST: &str = "aqara2mqtt/bridge/request/stats_reset";
+pub const TOPIC_STATS_RESET_RESPONSE: &str = "aqara2mqtt/bridge/response/stats_reset";
+
+// Counters of a bridge, reported on the diagnostics and stats topics
+pub struct Stats {
+    pub rate_limited: AtomicU64,
+    pub coalesced: AtomicU64,
+    pub parse_errors: AtomicU64,
+    pub mqtt_reconnects: AtomicU64,
+    pub agent_reconnects: AtomicU64,
+    // Messages from the ha_driven reader handed to the publish queue
+    pub ha_driven_forwarded: AtomicU64,
+    // Reports that came in on both the agent socket and the ha_driven log
+    pub duplicates_suppressed: AtomicU64,
+    // Reports lost to a full publish queue, commands lost while the agent was away
+    pub queue_dropped: AtomicU64,
+    pub commands_dropped: AtomicU64,
+    // A gauge, not reset with the counters
+    pub queue_depth: AtomicU64,
+    topics: Mutex<Topics>,
+    latency: Mutex<Latency>,
+    // The most recent round trips, the percentiles are taken over these
+    recent_latency: Mutex<VecDeque<Duration>>,
+}
+
+impl Stats {
+    // The counters cover the time from now
+    pub fn new() -> Self {
+        Stats {
+            rate_limited: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            mqtt_reconnects: AtomicU64::new(0),
+            agent_reconnects: AtomicU64::new(0),
+            ha_driven_forwarded: AtomicU64::new(0),
+            duplicates_suppressed: AtomicU64::new(0),
+            queue_dropped: AtomicU64::new(0),
+            commands_dropped: AtomicU64::new(0),
+            queue_depth: AtomicU64::new(0),
+            topics: Mutex::new(Topics { counts: BTreeMap::new(), since: now() }),
+            latency: Mutex::new(Latency::default()),
+            recent_latency: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+#[derive(Default)]
+struct TopicCounts {
+    received: u64,
+    published: u64,
+}
+
+// Messages per topic and the time of the last reset
+struct Topics {
+    counts: BTreeMap<String, TopicCounts>,
+    since: u64,
+}
+
+// Upper bounds in milliseconds of the command round trip histogram
+pub const LATENCY_BUCKETS_MS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+// Command to ack round trips, counts per bucket are not cumulative
+#[derive(Clone, Default)]
+pub struct Latency {
+    pub buckets: [u64; LATENCY_BUCKETS_MS.len()],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+// Round trips kept for the percentiles
+const LATENCY_SAMPLES: usize = 1000;
+pub const LATENCY_QUANTILES: [(&str, f64); 3] = [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)];
+
+// Picks a counter, e.g. stats::inc(|stats| &stats.parse_errors) counts on the current bridge
+pub type Counter = fn(&Stats) -> &AtomicU64;
+
+pub fn inc(counter: Counter) {
+    counter(&context::current().stats).fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn get(counter: Counter) -> u64 {
+    counter(&context::current().stats).load(Ordering::Relaxed)
+}
+
+pub fn set(counter: Counter, value: u64) {
+    counter(&context::current().stats).store(value, Ordering::Relaxed);
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// A message received from the broker
+pub fn count_in(topic: &str) {
+    context::current().stats.topics.lock().unwrap().counts.entry(topic.to_string()).or_default().received += 1;
+}
+
+// A message the primary broker accepted
+pub fn count_out(topic: &str) {
+    context::current().stats.topics.lock().unwrap().counts.entry(topic.to_string()).or_default().published += 1;
+}
+
+pub fn record_latency(latency: Duration) {
+    let context = context::current();
+    let mut histogram = context.stats.latency.lock().unwrap();
+    let ms = latency.as_millis() as u64;
+    if let Some(bucket) = LATENCY_BUCKETS_MS.iter().position(|bound| ms <= *bound) {
+        histogram.buckets[bucket] += 1;
+    }
+    histogram.count += 1;
+    histogram.sum += latency;
+    drop(histogram);
+    let mut recent = context.stats.recent_latency.lock().unwrap();
+    if recent.len() >= LATENCY_SAMPLES {
+        recent.pop_front();
+    }
+    recent.push_back(latency);
+}
+
+// Nearest rank percentiles of the recent round trips, in the order of LATENCY_QUANTILES
+pub fn latency_percentiles() -> Option<[Duration; LATENCY_QUANTILES.len()]> {
+    let mut samples: Vec<Duration> = context::current().stats.recent_latency.lock().unwrap().iter().copied().collect();
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort();
+    Some(LATENCY_QUANTILES.map(|(_, quantile)| {
+        let rank = (quantile * samples.len() as f64).ceil() as usize;
+        samples[rank.clamp(1, samples.len()) - 1]
+    }))
+}
+
+fn latency_json() -> Value {
+    let mut latency = json!({ "samples": context::current().stats.recent_latency.lock().unwrap().len() });
+    let percentiles = latency_percentiles();
+    for (i, (name, _)) in LATENCY_QUANTILES.iter().enumerate() {
+        latency[*name] = json!(percentiles.map(|percentiles| percentiles[i].as_millis() as u64));
+    }
+    latency
+}
+
+pub fn latency() -> Latency {
+    context::current().stats.latency.lock().unwrap().clone()
+}
+
+// (topic, received, published) for every topic seen since the last reset
+pub fn topic_counts() -> Vec<(String, u64, u64)> {
+    let context = context::current();
+    let topics = context.stats.topics.lock().unwrap();
+    topics.counts.iter().map(|(topic, counts)| (topic.clone(), counts.received, counts.published)).collect()
+}
+
+pub fn snapshot() -> Value {
+    let context = context::current();
+    let stats = &context.stats;
+    let topics = stats.topics.lock().unwrap();
+    let received: u64 = topics.counts.values().map(|counts| counts.received).sum();
+    let published: u64 = topics.counts.values().map(|counts| counts.published).sum();
+    let per_topic: serde_json::Map<String, Value> = topics
+        .counts
+        .iter()
+        .map(|(topic, counts)| (topic.clone(), json!({ "in": counts.received, "out": counts.published })))
+        .collect();
+    json!({
+        "since": topics.since,
+        "messages_in": received,
+        "messages_out": published,
+        "topics": per_topic,
+        "parse_errors": stats.parse_errors.load(Ordering::Relaxed),
+        "mqtt_reconnects": stats.mqtt_reconnects.load(Ordering::Relaxed),
+        "agent_reconnects": stats.agent_reconnects.load(Ordering::Relaxed),
+        "ha_driven_forwarded": stats.ha_driven_forwarded.load(Ordering::Relaxed),
+        "duplicates_suppressed": stats.duplicates_suppressed.load(Ordering::Relaxed),
+        "dropped": {
+            "queue": stats.queue_dropped.load(Ordering::Relaxed),
+            "rate_limited": stats.rate_limited.load(Ordering::Relaxed),
+            "commands": stats.commands_dropped.load(Ordering::Relaxed),
+        },
+        "coalesced": stats.coalesced.load(Ordering::Relaxed),
+        "queue_depth": stats.queue_depth.load(Ordering::Relaxed),
+        "command_latency_ms": latency_json(),
+    })
+}
+
+// Zeroes the counters and returns what they were
+pub fn reset() -> Value {
+    let before = snapshot();
+    let context = context::current();
+    let stats = &context.stats;
+    let mut topics = stats.topics.lock().unwrap();
+    topics.counts.clear();
+    topics.since = now();
+    *stats.latency.lock().unwrap() = Latency::default();
+    stats.recent_latency.lock().unwrap().clear();
+    for counter in [
+        &stats.rate_limited,
+        &stats.coalesced,
+        &stats.parse_errors,
+        &stats.mqtt_reconnects,
+        &stats.agent_reconnects,
+        &stats.ha_driven_forwarded,
+        &stats.duplicates_suppressed,
+        &stats.queue_dropped,
+        &stats.commands_dropped,
+    ] {
+        counter.store(0, Ordering::Relaxed);
+    }
+    before
+}
+
+pub async fn reporter(publisher: Publisher, period: Duration, mut shutdown: broadcast::Receiver<()>) {
+    let mut timer = interval(period);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                let msg = Message::new_retained(topics::prefixed(TOPIC_STATS), snapshot().to_string(), 0);
+                if let Err(e) = publisher.publish(msg).await {
+                    debug!("Error publishing stats: {:?}", e);
+                }
+            }
+            _ = shutdown.recv() => return,
+        }
+    }
+}
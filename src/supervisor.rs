@@ -0,0 +1,70 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::pin;
+
+use log::{error, warn};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::backoff::Backoff;
+use crate::logger;
+
+// More failures than this within the window and the bridge exits for the init system to restart it
+const MAX_FAILURES: usize = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(600);
+// A task that ran this long before failing starts over with the shortest delay
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+// Runs the task made by `spawn` until shutdown, restarting it with backoff whenever it
+// returns. Sends the reason on `failed` and returns after repeated failures. Panics
+// aren't caught, release builds abort and leave the restart to the init system.
+pub async fn supervise<F, Fut>(
+    name: &'static str,
+    mut spawn: F,
+    mut shutdown: broadcast::Receiver<()>,
+    failed: mpsc::Sender<String>,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(30));
+    let mut failures: VecDeque<Instant> = VecDeque::new();
+    loop {
+        // No await between the check and the start, so the task subscribes before any later shutdown
+        if !matches!(shutdown.try_recv(), Err(broadcast::error::TryRecvError::Empty)) {
+            return;
+        }
+        let started = Instant::now();
+        let mut task = pin!(logger::tagged(name, spawn()));
+        tokio::select! {
+            () = &mut task => {}
+            _ = shutdown.recv() => {
+                // The task sees the same shutdown and finishes on its own
+                task.await;
+                return;
+            }
+        }
+        error!("Task {} stopped unexpectedly", name);
+
+        let now = Instant::now();
+        failures.push_back(now);
+        while failures.front().is_some_and(|failure| now.duration_since(*failure) > FAILURE_WINDOW) {
+            failures.pop_front();
+        }
+        if failures.len() > MAX_FAILURES {
+            let _ = failed
+                .send(format!("task {} failed {} times within {:?}", name, failures.len(), FAILURE_WINDOW))
+                .await;
+            return;
+        }
+        if started.elapsed() >= STABLE_RUN {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        warn!("Restarting task {} in {:?}", name, delay);
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = shutdown.recv() => return,
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use log::{debug, info, warn};
+use tokio::sync::broadcast;
+use tokio::time::{interval, Duration, Instant};
+
+use crate::health;
+use crate::publisher::Publisher;
+
+// Sends a sd_notify state, a socket name starting with @ is in the abstract namespace
+fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else { return };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+    if let Err(e) = result {
+        warn!("Error notifying systemd of '{}': {:?}", state.replace('\n', " "), e);
+    }
+}
+
+// WATCHDOG_USEC from WatchdogSec=, only when meant for this process
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID")
+        && pid.parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
+
+pub fn is_enabled() -> bool {
+    std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+// Reports READY=1 once the broker and the agent are both connected, then pings the
+// watchdog at half its interval for as long as both stay healthy
+pub async fn notifier(publisher: Publisher, mut shutdown: broadcast::Receiver<()>) {
+    let watchdog = watchdog_interval();
+    let mut ready = false;
+    let mut last_ping: Option<Instant> = None;
+    let mut timer = interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {}
+            _ = shutdown.recv() => {
+                notify("STOPPING=1");
+                return;
+            }
+        }
+        if !health::check(&publisher, None).links_up() {
+            if ready {
+                debug!("Broker or agent down, not pinging the systemd watchdog");
+            }
+            continue;
+        }
+        if !ready {
+            notify("READY=1\nSTATUS=Connected to the broker and the agent");
+            info!("Notified systemd that the bridge is ready");
+            ready = true;
+        }
+        if let Some(watchdog) = watchdog
+            && last_ping.is_none_or(|last| last.elapsed() >= watchdog / 2)
+        {
+            notify("WATCHDOG=1");
+            last_ping = Some(Instant::now());
+        }
+    }
+}
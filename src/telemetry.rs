@@ -0,0 +1,79 @@
+use std::ffi::CString;
+use std::fs;
+use std::time::Duration;
+
+use log::debug;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+use tokio::time::interval;
+
+use crate::mqtt_client::Message;
+use crate::publisher::Publisher;
+use crate::topics;
+
+pub const TOPIC_TELEMETRY: &str = "aqara2mqtt/bridge/telemetry";
+
+// Writable flash of the hub, the root fs is read-only squashfs
+const FLASH_MOUNT: &str = "/data";
+
+fn uptime() -> Option<u64> {
+    let uptime = fs::read_to_string("/proc/uptime").ok()?;
+    uptime.split_whitespace().next()?.parse::<f64>().ok().map(|secs| secs as u64)
+}
+
+fn load() -> Option<Vec<f64>> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    loadavg.split_whitespace().take(3).map(|load| load.parse().ok()).collect()
+}
+
+// Value in kB of a "Key:   1234 kB" line of /proc/meminfo or /proc/self/status
+fn kb_field(path: &str, key: &str) -> Option<u64> {
+    let content = fs::read_to_string(path).ok()?;
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|value| value.split_whitespace().next()?.parse().ok())
+}
+
+// (total, used) in kB of the filesystem at `path`
+fn disk_usage(path: &str) -> Option<(u64, u64)> {
+    let path = CString::new(path).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let block = stat.f_frsize as u64;
+    let total = stat.f_blocks as u64 * block / 1024;
+    let free = stat.f_bfree as u64 * block / 1024;
+    Some((total, total - free))
+}
+
+pub fn collect() -> Value {
+    let mem_free = kb_field("/proc/meminfo", "MemAvailable").or_else(|| kb_field("/proc/meminfo", "MemFree"));
+    let flash = disk_usage(FLASH_MOUNT).map(|(total, used)| json!({ "total_kb": total, "used_kb": used }));
+    json!({
+        "uptime": uptime(),
+        "load": load(),
+        "mem_total_kb": kb_field("/proc/meminfo", "MemTotal"),
+        "mem_free_kb": mem_free,
+        "flash": flash,
+        "rss_kb": kb_field("/proc/self/status", "VmRSS"),
+        "rss_peak_kb": kb_field("/proc/self/status", "VmHWM"),
+    })
+}
+
+// Publishes the hub's health every `period` until shutdown
+pub async fn reporter(publisher: Publisher, period: Duration, mut shutdown: broadcast::Receiver<()>) {
+    let mut timer = interval(period);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                let msg = Message::new_retained(topics::prefixed(TOPIC_TELEMETRY), collect().to_string(), 0);
+                if let Err(e) = publisher.publish(msg).await {
+                    debug!("Error publishing telemetry: {:?}", e);
+                }
+            }
+            _ = shutdown.recv() => return,
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::mqtt_client::Message;
+use crate::topics;
+
+pub const TOPIC_THREAD_STATUS: &str = "aqara2mqtt/thread/status";
+// Joined and left Thread devices, not retained
+pub const TOPIC_THREAD_EVENT: &str = "aqara2mqtt/thread/event";
+
+const MTBR_KEY: &str = "mtbr.control";
+const STATE_FIELDS: [&str; 4] = ["state", "role", "br_state", "status"];
+const DATASET_FIELDS: [&str; 7] = ["network_name", "channel", "pan_id", "panid", "ext_pan_id", "extpanid", "mesh_local_prefix"];
+// Credentials are never published
+const SECRET_FIELDS: [&str; 3] = ["network_key", "networkkey", "pskc"];
+const DEVICE_FIELDS: [&str; 3] = ["eui64", "ext_addr", "extaddr"];
+
+// What the bridge learned about the hub's Thread border router from mtbr.control traffic
+#[derive(Default, Serialize)]
+pub struct ThreadStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<Value>,
+    dataset: Map<String, Value>,
+    devices: Vec<String>,
+}
+
+fn dataset_fields(json: &Map<String, Value>, out: &mut Map<String, Value>) {
+    for (key, value) in json {
+        if DATASET_FIELDS.contains(&key.as_str()) {
+            out.insert(key.clone(), value.clone());
+        }
+    }
+    if let Some(Value::Object(dataset)) = json.get("dataset").or_else(|| json.get("active_dataset")) {
+        for (key, value) in dataset {
+            if !SECRET_FIELDS.contains(&key.to_lowercase().as_str()) {
+                out.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+impl ThreadStatus {
+    // Merges an mtbr.control frame, returns whether the status changed and a device event if there was one
+    pub fn update(&mut self, report: &Value) -> (bool, Option<Message>) {
+        if report.get("key").and_then(Value::as_str) != Some(MTBR_KEY) {
+            return (false, None);
+        }
+        let Some(params) = report.get("params").and_then(Value::as_object) else {
+            return (false, None);
+        };
+        let mut changed = false;
+        if let Some(state) = STATE_FIELDS.iter().find_map(|field| params.get(*field)) {
+            changed |= self.state.as_ref() != Some(state);
+            self.state = Some(state.clone());
+        }
+        let mut dataset = Map::new();
+        dataset_fields(params, &mut dataset);
+        for (key, value) in dataset {
+            changed |= self.dataset.insert(key, value.clone()) != Some(value);
+        }
+
+        let device = DEVICE_FIELDS.iter().find_map(|field| params.get(*field)).and_then(Value::as_str);
+        let Some(device) = device else {
+            return (changed, None);
+        };
+        let method = report.get("method").and_then(Value::as_str).unwrap_or_default();
+        let event = params.get("event").and_then(Value::as_str).unwrap_or(method);
+        let joined = !(event.contains("leave") || event.contains("left") || event.contains("remove"));
+        let known = self.devices.iter().position(|eui64| eui64 == device);
+        match (joined, known) {
+            (true, None) => self.devices.push(device.to_string()),
+            (false, Some(index)) => {
+                self.devices.remove(index);
+            }
+            _ => {}
+        }
+        changed |= joined != known.is_some();
+        let payload = json!({ "type": if joined { "device_joined" } else { "device_left" }, "eui64": device });
+        (changed, Some(Message::new(topics::prefixed(TOPIC_THREAD_EVENT), payload.to_string(), 1)))
+    }
+
+    pub fn message(&self) -> Message {
+        let payload = serde_json::to_string(self).unwrap_or_default();
+        Message::new_retained(topics::prefixed(TOPIC_THREAD_STATUS), payload, 1)
+    }
+}
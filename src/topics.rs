@@ -0,0 +1,52 @@
+use serde_json::Value;
+
+use crate::{compat, context};
+use crate::mqtt_client::Message;
+
+// Bridge topics under this root get the gateway id inserted, aqara2mqtt/<gwid>/...
+const NAMESPACE_ROOT: &str = "aqara2mqtt/";
+
+// Agent keys with their own topic, auto.report and unknown keys stay on openmiio/report
+const AGENT_KEY_TOPICS: [(&str, &str); 7] = [
+    ("auto.forward", "aqara2mqtt/auto/forward"),
+    ("lanbox.event", "aqara2mqtt/lanbox/event"),
+    ("auto.ifttt", "aqara2mqtt/auto/ifttt"),
+    ("auto.cross.ifttt", "aqara2mqtt/auto/cross_ifttt"),
+    ("matter.control", "aqara2mqtt/matter/control"),
+    ("matter.event", "aqara2mqtt/matter/event"),
+    ("mtbr.control", "aqara2mqtt/mtbr/control"),
+];
+
+pub fn prefixed(topic: &str) -> String {
+    let context = context::current();
+    let config = &context.config;
+    let topic = match (&config.gateway_id, topic.strip_prefix(NAMESPACE_ROOT)) {
+        (Some(gateway_id), Some(rest)) => format!("{}{}/{}", NAMESPACE_ROOT, gateway_id, rest),
+        _ => topic.to_string(),
+    };
+    format!("{}{}", config.topic_prefix, topic)
+}
+
+pub fn for_agent_key(key: &str) -> Option<&'static str> {
+    AGENT_KEY_TOPICS
+        .iter()
+        .find(|(agent_key, _)| *agent_key == key)
+        .map(|(_, topic)| *topic)
+}
+
+// Adds the gateway id as `_gw` to JSON object payloads, anything else is left alone
+pub fn tag(msg: Message) -> Message {
+    // openmiio_agent consumers expect the agent payloads as they are
+    let context = context::current();
+    let Some(gateway_id) = context.config.gateway_id.as_ref().filter(|_| !compat::is_openmiio()) else {
+        return msg;
+    };
+    match serde_json::from_slice::<Value>(msg.payload()) {
+        Ok(Value::Object(mut map)) => {
+            map.insert("_gw".to_string(), Value::String(gateway_id.clone()));
+            let payload = Value::Object(map).to_string();
+            msg.with_payload(payload)
+        }
+        _ => msg,
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{interval, timeout, Duration, Instant};
+
+// Flows of commands that never got an ack are forgotten after this long
+const FLOW_MAX_AGE: Duration = Duration::from_secs(600);
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_BATCH: usize = 256;
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+const SERVICE_NAME: &str = "aqara-agent2mqtt";
+
+// One command on its way MQTT -> channel -> agent socket -> response -> publish
+struct Flow {
+    method: String,
+    received: Instant,
+    // Wall clock of `received`, spans are exported with absolute times
+    received_at: SystemTime,
+    sent: Option<Instant>,
+    answered: Option<Instant>,
+}
+
+static FLOWS: Mutex<Option<HashMap<u64, Flow>>> = Mutex::new(None);
+// Finished spans for the OTLP exporter, only set with --otlp-endpoint
+static EXPORT: OnceCell<mpsc::Sender<Value>> = OnceCell::new();
+
+fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    hasher.finish()
+}
+
+fn with_flows<T>(f: impl FnOnce(&mut HashMap<u64, Flow>) -> T) -> T {
+    f(FLOWS.lock().unwrap().get_or_insert_with(HashMap::new))
+}
+
+// A command came in from MQTT and goes to the agent task next
+pub fn received(id: u64, method: &str) {
+    with_flows(|flows| {
+        flows.retain(|_, flow| flow.received.elapsed() < FLOW_MAX_AGE);
+        let flow = Flow { method: method.to_string(), received: Instant::now(), received_at: SystemTime::now(), sent: None, answered: None };
+        flows.insert(id, flow);
+    });
+}
+
+// The agent task wrote the command to the socket, for a retry only the first write counts
+pub fn sent(id: u64) {
+    with_flows(|flows| {
+        if let Some(flow) = flows.get_mut(&id) {
+            flow.sent.get_or_insert_with(Instant::now);
+        }
+    });
+}
+
+// The agent answered, the ack is published next
+pub fn answered(id: u64) {
+    with_flows(|flows| {
+        if let Some(flow) = flows.get_mut(&id) {
+            flow.answered = Some(Instant::now());
+        }
+    });
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    let value = match value {
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "intValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+// The ack was published, logs the stages and hands the spans to the exporter
+pub fn published(id: u64) {
+    let Some(flow) = with_flows(|flows| flows.remove(&id)) else { return };
+    let done = Instant::now();
+    let (Some(sent), Some(answered)) = (flow.sent, flow.answered) else { return };
+    let channel = sent.duration_since(flow.received);
+    let agent = answered.duration_since(sent);
+    let publish = done.duration_since(answered);
+    debug!("Command {} {}: channel {:?}, agent {:?}, publish {:?}", id, flow.method, channel, agent, publish);
+
+    let Some(export) = EXPORT.get() else { return };
+    let trace_id = format!("{:016x}{:016x}", random_u64(), random_u64());
+    let root_id = format!("{:016x}", random_u64());
+    let at = |instant: Instant| flow.received_at + instant.duration_since(flow.received);
+    let span = |name: &str, parent: &str, start: Instant, end: Instant, attributes: Vec<Value>| {
+        json!({
+            "traceId": trace_id,
+            "spanId": if parent.is_empty() { root_id.clone() } else { format!("{:016x}", random_u64()) },
+            "parentSpanId": parent,
+            "name": name,
+            // SERVER for the root, which handles the MQTT command, INTERNAL for its stages
+            "kind": if parent.is_empty() { 2 } else { 1 },
+            "startTimeUnixNano": nanos(at(start)),
+            "endTimeUnixNano": nanos(at(end)),
+            "attributes": attributes,
+        })
+    };
+    let root_attributes = vec![attribute("command.id", json!(id)), attribute("command.method", json!(flow.method))];
+    let spans = [
+        span(&format!("command {}", flow.method), "", flow.received, done, root_attributes),
+        span("channel", &root_id, flow.received, sent, Vec::new()),
+        span("agent", &root_id, sent, answered, Vec::new()),
+        span("publish", &root_id, answered, done, Vec::new()),
+    ];
+    for span in spans {
+        // A full channel means the collector is behind, those spans are lost
+        let _ = export.try_send(span);
+    }
+}
+
+// http://host:port[/path] of an OTLP/HTTP collector
+#[derive(Clone)]
+pub struct Endpoint {
+    host: String,
+    path: String,
+}
+
+pub fn parse_endpoint(endpoint: &str) -> Result<Endpoint, String> {
+    let rest = endpoint
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("'{}': only http:// OTLP endpoints are supported", endpoint))?;
+    let (host, base) = rest.split_once('/').unwrap_or((rest, ""));
+    if host.is_empty() {
+        return Err(format!("'{}': missing host", endpoint));
+    }
+    let host = if host.contains(':') { host.to_string() } else { format!("{}:4318", host) };
+    let base = base.trim_end_matches('/');
+    let path = if base.is_empty() { "/v1/traces".to_string() } else { format!("/{}/v1/traces", base) };
+    Ok(Endpoint { host, path })
+}
+
+async fn post(endpoint: &Endpoint, body: &str) -> Result<(), String> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(&endpoint.host).await?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            endpoint.path,
+            endpoint.host,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = match timeout(EXPORT_TIMEOUT, exchange).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err(format!("no response within {:?}", EXPORT_TIMEOUT)),
+    };
+    let response = String::from_utf8_lossy(&response);
+    match response.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        status => Err(format!("HTTP status {}", status.unwrap_or("missing"))),
+    }
+}
+
+fn request_body(spans: Vec<Value>) -> String {
+    json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [attribute("service.name", json!(SERVICE_NAME))] },
+            "scopeSpans": [{ "scope": { "name": SERVICE_NAME }, "spans": spans }],
+        }]
+    })
+    .to_string()
+}
+
+// Sends the spans of finished commands to the collector in batches, OTLP/HTTP with JSON
+pub async fn exporter(endpoint: Endpoint, mut shutdown: broadcast::Receiver<()>) {
+    let (tx, mut rx) = mpsc::channel(EXPORT_BATCH * 4);
+    if EXPORT.set(tx).is_err() {
+        return;
+    }
+    let mut timer = interval(EXPORT_INTERVAL);
+    let mut spans = Vec::new();
+    loop {
+        let stopping = tokio::select! {
+            Some(span) = rx.recv() => {
+                spans.push(span);
+                if spans.len() < EXPORT_BATCH {
+                    continue;
+                }
+                false
+            }
+            _ = timer.tick() => false,
+            _ = shutdown.recv() => true,
+        };
+        if !spans.is_empty() {
+            let count = spans.len();
+            if let Err(e) = post(&endpoint, &request_body(std::mem::take(&mut spans))).await {
+                warn!("Error exporting {} spans to http://{}{}: {}", count, endpoint.host, endpoint.path, e);
+            }
+        }
+        if stopping {
+            return;
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use log::{debug, error, info};
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, UnixStream};
+
+use crate::context;
+
+pub const UNIX_SCHEME: &str = "unix://";
+
+// Whether the backend needs the proxy for this URI, rumqttc connects to the socket itself
+pub fn needed(uri: &str) -> bool {
+    uri.starts_with(UNIX_SCHEME) && cfg!(feature = "paho")
+}
+
+// The Paho C library has no unix socket transport, so unix:// broker URIs are
+// served through a loopback listener that splices each connection onto the socket.
+// Any local process can connect to that port and reaches the broker without the
+// socket's file permissions, the broker's own authentication still applies.
+pub async fn start(uri: &str) -> std::io::Result<String> {
+    let path = uri.trim_start_matches(UNIX_SCHEME).to_string();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local_uri = format!("tcp://{}", listener.local_addr()?);
+    info!("Forwarding MQTT connections from '{}' to unix socket '{}'", local_uri, path);
+
+    context::spawn(async move {
+        loop {
+            let mut tcp_stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Error accepting MQTT proxy connection: {:?}", e);
+                    continue;
+                }
+            };
+            let path = path.clone();
+            context::spawn(async move {
+                match UnixStream::connect(&path).await {
+                    Ok(mut unix_stream) => {
+                        if let Err(e) = copy_bidirectional(&mut tcp_stream, &mut unix_stream).await {
+                            debug!("MQTT unix socket proxy connection closed: {:?}", e);
+                        }
+                    }
+                    Err(e) => error!("Error connecting to broker socket '{}': {:?}", path, e),
+                }
+            });
+        }
+    });
+
+    Ok(local_uri)
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::topics;
+
+pub const TOPIC_ZIGBEE2MQTT_PREFIX: &str = "zigbee2mqtt";
+// {"from":"<did or name>","to":"<new name>"}, answered on the response topic
+pub const TOPIC_RENAME_REQUEST: &str = "aqara2mqtt/bridge/request/rename";
+pub const TOPIC_RENAME_RESPONSE: &str = "aqara2mqtt/bridge/response/rename";
+
+// Mapping file, e.g.
+// {"devices":{"lumi.158d0001":"kitchen_sensor"},"resources":{"0.1.85":"temperature"}}
+#[derive(Default, Deserialize, Serialize)]
+pub struct FriendlyNames {
+    #[serde(default)]
+    devices: Mutex<HashMap<String, String>>,
+    #[serde(default)]
+    resources: Mutex<HashMap<String, String>>,
+    // Renames are written back here
+    #[serde(skip)]
+    path: Mutex<Option<PathBuf>>,
+}
+
+impl FriendlyNames {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut names: FriendlyNames = serde_json::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+        names.path = Mutex::new(Some(path.to_path_buf()));
+        Ok(names)
+    }
+
+    // Takes over the names and the file of a freshly loaded mapping, on reload
+    pub fn replace(&self, names: FriendlyNames) {
+        *self.devices.lock().unwrap() = names.devices.into_inner().unwrap();
+        *self.resources.lock().unwrap() = names.resources.into_inner().unwrap();
+        *self.path.lock().unwrap() = names.path.into_inner().unwrap();
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = self.path.lock().unwrap().clone() else { return Ok(()) };
+        let content = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(&path, content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn name(&self, did: &str) -> Option<String> {
+        self.devices.lock().unwrap().get(did).cloned()
+    }
+
+    // zigbee2mqtt/<friendly_name>, falling back to the did for unnamed devices
+    pub fn topic(&self, did: &str) -> String {
+        let name = self.name(did).unwrap_or_else(|| did.to_string());
+        topics::prefixed(&format!("{}/{}", TOPIC_ZIGBEE2MQTT_PREFIX, name))
+    }
+
+    // Did of the device with this friendly name, anything else is taken as a did
+    pub fn resolve(&self, name_or_did: &str) -> String {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .iter()
+            .find(|(_, name)| name.as_str() == name_or_did)
+            .map(|(did, _)| did.clone())
+            .unwrap_or_else(|| name_or_did.to_string())
+    }
+
+    pub fn remove(&self, did: &str) -> Result<(), String> {
+        if self.devices.lock().unwrap().remove(did).is_none() {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    // Names the device given by did or current name, returns its did
+    pub fn rename(&self, from: &str, to: &str) -> Result<String, String> {
+        if to.is_empty() || to.contains(['+', '#']) {
+            return Err(format!("invalid name '{}'", to));
+        }
+        let did = self.resolve(from);
+        let mut devices = self.devices.lock().unwrap();
+        if devices.iter().any(|(other, name)| name == to && *other != did) {
+            return Err(format!("name '{}' is already used", to));
+        }
+        devices.insert(did.clone(), to.to_string());
+        // save() serializes the devices and takes the lock again
+        drop(devices);
+        self.save()?;
+        Ok(did)
+    }
+
+    // One level of named attributes, nested objects become name_field
+    pub fn flatten(&self, state: &Value) -> Value {
+        let mut out = Map::new();
+        let resources = self.resources.lock().unwrap();
+        if let Value::Object(map) = state {
+            for (key, value) in map {
+                let name = resources.get(key).unwrap_or(key);
+                flatten_into(name, value, &mut out);
+            }
+        }
+        Value::Object(out)
+    }
+}
+
+fn flatten_into(name: &str, value: &Value, out: &mut Map<String, Value>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten_into(&format!("{}_{}", name, key), value, out);
+            }
+        }
+        _ => {
+            out.insert(name.to_string(), value.clone());
+        }
+    }
+}
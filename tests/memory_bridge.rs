@@ -0,0 +1,206 @@
+// A bridge between the in-process agent and broker, neither a hub nor a broker needed
+use aqara_agent2mqtt::agent::AgentOptions;
+use aqara_agent2mqtt::agent_socket::{AgentTransport, MemoryAgent, MemoryAgentListener};
+use aqara_agent2mqtt::availability::{STATE_ONLINE, TOPIC_BRIDGE_STATE};
+use aqara_agent2mqtt::command::{ERROR_AGENT_UNAVAILABLE, ERROR_TIMEOUT};
+use aqara_agent2mqtt::mqtt_client::{MemoryBroker, Message, MqttConfig};
+use aqara_agent2mqtt::Bridge;
+use bytes::BytesMut;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+
+const COMMAND_ID: u64 = 4242;
+const BIND_ID: u32 = 77;
+// Commands the agent task holds while the agent is away
+const HELD_COMMANDS: usize = 32;
+
+fn mqtt_config() -> MqttConfig {
+    MqttConfig {
+        user: None,
+        password: None,
+        ca_cert: None,
+        client_cert: None,
+        client_key: None,
+        insecure: false,
+        reconnect_max: Duration::from_secs(1),
+        persistent_session: false,
+        session_expiry: 0,
+        keep_alive: Duration::from_secs(30),
+        connect_timeout: Duration::from_secs(5),
+        max_inflight: 10,
+    }
+}
+
+fn agent_options(name: &str) -> AgentOptions {
+    AgentOptions {
+        socket_path: format!("memory://{}", name),
+        bind_id: BIND_ID,
+        watchdog: None,
+        inventory_interval: None,
+        ..AgentOptions::default()
+    }
+}
+
+// Starts a bridge on the broker `name` and returns once it subscribed to the command topics
+async fn start_bridge(broker: &MemoryBroker, name: &str, options: AgentOptions) -> Bridge {
+    let mut state = broker.subscribe(TOPIC_BRIDGE_STATE);
+    let bridge = Bridge::builder()
+        .mqtt(format!("memory://{}", name), mqtt_config())
+        .agent(options)
+        .ha_driven(false)
+        .telemetry_interval(None)
+        .stats_interval(None)
+        .spawn()
+        .await
+        .unwrap();
+    loop {
+        let msg = timeout(Duration::from_secs(5), state.recv()).await.unwrap().unwrap();
+        if msg.payload() == STATE_ONLINE.as_bytes() {
+            return bridge;
+        }
+    }
+}
+
+// A bridge connected to an agent of its own
+async fn start_with_agent(name: &str, options: AgentOptions) -> (MemoryBroker, MemoryAgent, Bridge) {
+    let broker = MemoryBroker::bind(name).unwrap();
+    let mut agent_listener = MemoryAgentListener::bind(name).unwrap();
+    let bridge = start_bridge(&broker, name, options).await;
+    let agent = timeout(Duration::from_secs(5), agent_listener.accept()).await.unwrap().unwrap();
+    (broker, agent, bridge)
+}
+
+// The next frame the bridge sent, None when nothing came within the timeout
+async fn recv_frame(agent: &mut MemoryAgent, within: Duration) -> Option<Vec<u8>> {
+    let mut buf = BytesMut::zeroed(4096);
+    let (n, _) = timeout(within, agent.recv(&mut buf)).await.ok()?.ok()?;
+    Some(buf[..n].to_vec())
+}
+
+// The next frame with `method`, skipping the bind and register messages
+async fn recv_command(agent: &mut MemoryAgent, method: &str) -> Value {
+    loop {
+        let frame = recv_frame(agent, Duration::from_secs(5)).await.expect("the command never reached the agent");
+        if let Ok(frame) = serde_json::from_slice::<Value>(&frame)
+            && frame["method"] == method
+        {
+            return frame;
+        }
+    }
+}
+
+async fn recv_json(messages: &mut mpsc::UnboundedReceiver<Message>, within: Duration) -> Value {
+    let msg = timeout(within, messages.recv()).await.unwrap().unwrap();
+    serde_json::from_slice(msg.payload()).unwrap()
+}
+
+fn command(id: u64, method: &str) -> Message {
+    Message::new("miio/command", json!({"id": id, "method": method, "params": []}).to_string(), 0)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn command_gets_its_ack() {
+    let (broker, mut agent, bridge) = start_with_agent("command_ack", agent_options("command_ack")).await;
+    let mut acks = broker.subscribe("miio/command_ack");
+
+    let command = json!({"id": COMMAND_ID, "method": "get_properties", "params": [{"did": "lumi.0", "siid": 2, "piid": 1}]});
+    broker.publish(Message::new("miio/command", command.to_string(), 0));
+    let received = recv_command(&mut agent, "get_properties").await;
+    assert_eq!(received["id"], COMMAND_ID);
+    assert_eq!(received["_from"], BIND_ID);
+    assert_eq!(received["params"], command["params"]);
+
+    let reply = json!({"id": COMMAND_ID, "result": [{"did": "lumi.0", "siid": 2, "piid": 1, "value": 1, "code": 0}]});
+    agent.send(reply.to_string().as_bytes()).await.unwrap();
+
+    let ack = recv_json(&mut acks, Duration::from_secs(5)).await;
+    assert_eq!(ack["id"], COMMAND_ID);
+    assert_eq!(ack["result"][0]["value"], 1);
+
+    bridge.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn raw_commands_pass_through() {
+    let (broker, mut agent, bridge) = start_with_agent("raw_command", agent_options("raw_command")).await;
+    let mut acks = broker.subscribe("miio/command_ack/raw");
+
+    broker.publish(Message::new("miio/command/raw", b"\x00raw command".to_vec(), 0));
+    let mut received = None;
+    while let Some(frame) = recv_frame(&mut agent, Duration::from_secs(5)).await {
+        if frame.starts_with(b"\x00") {
+            received = Some(frame);
+            break;
+        }
+    }
+    assert_eq!(received.as_deref(), Some(&b"\x00raw command"[..]));
+
+    // Frames that aren't JSON go out base64 encoded
+    agent.send(b"\x01\x02").await.unwrap();
+    let ack = timeout(Duration::from_secs(5), acks.recv()).await.unwrap().unwrap();
+    assert_eq!(ack.payload(), b"AQI=");
+
+    bridge.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reports_go_to_their_topics() {
+    let (broker, mut agent, bridge) = start_with_agent("reports", agent_options("reports")).await;
+    let mut reports = broker.subscribe("openmiio/report");
+    let mut lanbox = broker.subscribe("aqara2mqtt/lanbox/event");
+
+    agent.send(json!({"method": "properties_changed", "params": [{"did": "lumi.1", "siid": 3, "piid": 1, "value": 21}]}).to_string().as_bytes()).await.unwrap();
+    let report = recv_json(&mut reports, Duration::from_secs(5)).await;
+    assert_eq!(report["params"][0]["did"], "lumi.1");
+
+    agent.send(json!({"key": "lanbox.event", "params": {"event": "rule"}}).to_string().as_bytes()).await.unwrap();
+    let event = recv_json(&mut lanbox, Duration::from_secs(5)).await;
+    assert_eq!(event["params"]["event"], "rule");
+
+    bridge.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unanswered_command_times_out_and_late_reply_is_acked() {
+    let options = AgentOptions { command_timeout: Duration::from_millis(200), ..agent_options("timeout") };
+    let (broker, mut agent, bridge) = start_with_agent("timeout", options).await;
+    let mut acks = broker.subscribe("miio/command_ack");
+
+    // Not one of the retry methods, so it is given up after the first timeout
+    broker.publish(command(COMMAND_ID, "miIO.info"));
+    recv_command(&mut agent, "miIO.info").await;
+
+    // Expired commands are collected once a second
+    let ack = recv_json(&mut acks, Duration::from_secs(5)).await;
+    assert_eq!(ack["id"], COMMAND_ID);
+    assert_eq!(ack["error"]["code"], ERROR_TIMEOUT);
+    assert_eq!(ack["error"]["message"], "command timed out");
+
+    // Addressed to us, so still an ack rather than a report
+    let reply = json!({"id": COMMAND_ID, "_to": BIND_ID, "result": {"fw_ver": "4.0.0"}});
+    agent.send(reply.to_string().as_bytes()).await.unwrap();
+    let ack = recv_json(&mut acks, Duration::from_secs(5)).await;
+    assert_eq!(ack["id"], COMMAND_ID);
+    assert_eq!(ack["result"]["fw_ver"], "4.0.0");
+
+    bridge.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn command_for_missing_agent_gets_error_ack() {
+    // Nothing listens on the agent address, the commands are held until the oldest has to go
+    let broker = MemoryBroker::bind("no_agent").unwrap();
+    let bridge = start_bridge(&broker, "no_agent", agent_options("no_agent")).await;
+    let mut acks = broker.subscribe("miio/command_ack");
+
+    for id in 0..=HELD_COMMANDS as u64 {
+        broker.publish(command(COMMAND_ID + id, "miIO.info"));
+    }
+    let ack = recv_json(&mut acks, Duration::from_secs(10)).await;
+    assert_eq!(ack["id"], COMMAND_ID);
+    assert_eq!(ack["error"]["code"], ERROR_AGENT_UNAVAILABLE);
+    assert_eq!(ack["error"]["message"], "agent unavailable");
+
+    bridge.shutdown().await;
+}